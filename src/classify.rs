@@ -0,0 +1,201 @@
+//! Pluggable classification of responses into successes and failures.
+//!
+//! This mirrors the `MakeClassifier`/`ClassifyResponse` idea from [`tower-http`], reduced to the
+//! single decision this crate needs: whether a response should mark the span as an error.
+//!
+//! [`tower-http`]: https://docs.rs/tower-http
+use std::borrow::Cow;
+
+use http::{HeaderMap, StatusCode};
+
+/// The outcome of classifying a response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ResponseClass {
+    /// The response is a success and the span status is left untouched.
+    Success,
+    /// The response is a failure and the span status is set to [`StatusCode::Error`] with the
+    /// given description.
+    ///
+    /// [`StatusCode::Error`]: opentelemetry::trace::StatusCode::Error
+    Failure(Cow<'static, str>),
+}
+
+/// Marks a response as a failure whatever its status, with the given description.
+///
+/// APIs such as GraphQL or JSON-RPC report errors in the body of a `200 OK` response, which no
+/// [`ResponseClassifier`] can see. Handlers insert this into the extensions of such responses to
+/// set the span status to [`StatusCode::Error`], without the layer having to read the body. A
+/// signal carried in a header can be handled by a classifier instead, which receives the headers.
+///
+/// ```
+/// let mut res = http::Response::new(());
+/// res.extensions_mut().insert(tower_opentelemetry::ResponseFailure("query failed".into()));
+/// ```
+///
+/// [`StatusCode::Error`]: opentelemetry::trace::StatusCode::Error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseFailure(pub Cow<'static, str>);
+
+/// The representative error of a response that reports several errors in its body, such as a
+/// batch or GraphQL response, recorded when [`Layer::with_response_error`] is enabled.
+///
+/// Handlers that find errors while building such a response insert one summary into its
+/// extensions, typically the first error, with how many there were in total when known. The
+/// layer never reads the body, so a response without this extension isn't recorded as failed
+/// however many errors its body carries. The summary marks the span as an error whatever the
+/// response status, unless the handler also inserts a [`SpanStatus`], and like the error of a
+/// failed request it is recorded as `error.type` and an `exception` event, so the type should
+/// have low cardinality and the message must not carry data that shouldn't be exported.
+///
+/// ```
+/// let error = tower_opentelemetry::ResponseError::new("validation", "field `id` is missing").with_count(3);
+/// let mut res = http::Response::new(());
+/// res.extensions_mut().insert(error);
+/// ```
+///
+/// [`Layer::with_response_error`]: crate::Layer::with_response_error
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ResponseError {
+    error_type: Cow<'static, str>,
+    message: Cow<'static, str>,
+    count: Option<usize>,
+}
+
+impl ResponseError {
+    /// Create a summary for an error of `error_type` described by `message`.
+    #[must_use]
+    pub fn new(error_type: impl Into<Cow<'static, str>>, message: impl Into<Cow<'static, str>>) -> Self {
+        Self {
+            error_type: error_type.into(),
+            message: message.into(),
+            count: None,
+        }
+    }
+
+    /// Record that the response reported `count` errors in total, as `error.count`.
+    #[must_use]
+    pub fn with_count(mut self, count: usize) -> Self {
+        self.count = Some(count);
+        self
+    }
+
+    /// The low-cardinality type of the error.
+    #[must_use]
+    pub fn error_type(&self) -> &str {
+        &self.error_type
+    }
+
+    /// The description of the error.
+    #[must_use]
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+
+    /// The number of errors the response reported, if known.
+    #[must_use]
+    pub fn count(&self) -> Option<usize> {
+        self.count
+    }
+}
+
+/// The span status a handler chose for its response, overriding the classifier and any
+/// [`ResponseFailure`].
+///
+/// Handlers that know better than the HTTP status whether a request succeeded insert this into
+/// the extensions of the response, and the layer applies it to the span when the response is
+/// recorded. The request extensions can't carry it, since the layer no longer sees the request
+/// by then.
+///
+/// ```
+/// let mut res = http::Response::new(());
+/// res.extensions_mut().insert(tower_opentelemetry::SpanStatus::Ok);
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SpanStatus {
+    /// Leave the span status unset, even for responses the classifier considers failures.
+    Unset,
+    /// Set the span status to [`StatusCode::Ok`].
+    ///
+    /// [`StatusCode::Ok`]: opentelemetry::trace::StatusCode::Ok
+    Ok,
+    /// Set the span status to [`StatusCode::Error`] with the given description.
+    ///
+    /// [`StatusCode::Error`]: opentelemetry::trace::StatusCode::Error
+    Error(Cow<'static, str>),
+}
+
+/// Decides whether a response is a success or a failure.
+///
+/// Implementations are shared behind an [`Arc`], so a single classifier can be reused across
+/// several middlewares.
+///
+/// [`Arc`]: std::sync::Arc
+pub trait ResponseClassifier: Send + Sync {
+    /// Classify a response from its status and headers.
+    fn classify_response(&self, status: StatusCode, headers: &HeaderMap) -> ResponseClass;
+}
+
+impl<F> ResponseClassifier for F
+where
+    F: Fn(StatusCode, &HeaderMap) -> ResponseClass + Send + Sync,
+{
+    fn classify_response(&self, status: StatusCode, headers: &HeaderMap) -> ResponseClass {
+        self(status, headers)
+    }
+}
+
+/// The default [`ResponseClassifier`], which treats `5xx` responses as failures.
+#[derive(Debug, Copy, Clone, Default)]
+pub struct ServerErrorsAsFailures {}
+
+impl ServerErrorsAsFailures {
+    /// Create a new [`ServerErrorsAsFailures`].
+    #[must_use]
+    pub fn new() -> Self {
+        Self {}
+    }
+}
+
+impl ResponseClassifier for ServerErrorsAsFailures {
+    fn classify_response(&self, status: StatusCode, _headers: &HeaderMap) -> ResponseClass {
+        if status.is_server_error() {
+            ResponseClass::Failure(status.canonical_reason().unwrap_or_default().into())
+        } else {
+            ResponseClass::Success
+        }
+    }
+}
+
+/// A [`ResponseClassifier`] that treats every status within an inclusive range as a failure.
+#[derive(Debug, Copy, Clone)]
+pub struct StatusInRangeAsFailures {
+    start: u16,
+    end: u16,
+}
+
+impl StatusInRangeAsFailures {
+    /// Create a new [`StatusInRangeAsFailures`] for the inclusive `range` of status codes.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the range is empty or lies outside `100..=999`.
+    #[must_use]
+    pub fn new(range: std::ops::RangeInclusive<u16>) -> Self {
+        let (start, end) = range.into_inner();
+        assert!(
+            start <= end && (100..=999).contains(&start) && (100..=999).contains(&end),
+            "invalid status code range"
+        );
+        Self { start, end }
+    }
+}
+
+impl ResponseClassifier for StatusInRangeAsFailures {
+    fn classify_response(&self, status: StatusCode, _headers: &HeaderMap) -> ResponseClass {
+        if (self.start..=self.end).contains(&status.as_u16()) {
+            ResponseClass::Failure(status.canonical_reason().unwrap_or_default().into())
+        } else {
+            ResponseClass::Success
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Propagation of the current context onto outbound requests.
+//!
+//! The [`Layer`] in this module wraps an HTTP client service, starting a [`SpanKind::Client`]
+//! span for every outbound request and injecting the current context into its headers with the
+//! global propagator, or the one of [`Layer::with_propagator`]. When the client is called from a handler behind the main [`crate::Layer`],
+//! the outbound request continues the inbound trace and carries on any inbound baggage.
+//!
+//! Outbound requests built by hand can carry the context with [`inject_context`] instead.
+//!
+//! With [`Layer::with_forwarded_headers`] the client also copies the inbound headers chosen with
+//! [`crate::Layer::with_forwarded_headers`] onto the outbound request.
+use std::{error::Error as StdError, future::Future, pin::Pin, sync::Arc, task::Poll};
+
+use futures_util::future::FutureExt;
+use http::{HeaderMap, HeaderName, HeaderValue, Request, Response};
+use opentelemetry::{
+    global,
+    propagation::TextMapPropagator,
+    trace::{FutureExt as OtelFutureExt, SpanKind, StatusCode, TraceContextExt, Tracer, TracerProvider},
+    Context,
+};
+use opentelemetry_semantic_conventions::trace::{HTTP_FLAVOR, HTTP_METHOD, HTTP_STATUS_CODE, HTTP_URL};
+
+use crate::{
+    guard::SpanGuard, http_flavor, http_method_str, tracer::BoxedTracer, HeaderCarrier, InvalidValuePolicy, Propagator,
+};
+
+/// Inject the current context, as returned by [`Context::current`], into the `headers` of an
+/// outbound request with the global propagator.
+///
+/// ```
+/// let mut headers = http::HeaderMap::new();
+/// tower_opentelemetry::client::inject_context(&mut headers);
+/// ```
+///
+/// Called from a handler behind the main [`crate::Layer`], this continues the inbound trace
+/// with the server span as the parent. Header values that aren't valid in HTTP headers are
+/// dropped.
+pub fn inject_context(headers: &mut HeaderMap) {
+    inject(&Context::current(), headers, InvalidValuePolicy::Drop, None);
+}
+
+/// Inject `cx` into `headers` with `propagator`, or else the global propagator, applying
+/// `policy` to invalid values.
+fn inject(cx: &Context, headers: &mut HeaderMap, policy: InvalidValuePolicy, propagator: Option<&Propagator>) {
+    let mut inject = |propagator: &dyn TextMapPropagator| {
+        let mut carrier = HeaderCarrier::new(&mut *headers).with_policy(policy);
+        propagator.inject_context(cx, &mut carrier);
+    };
+    match propagator {
+        Some(propagator) => inject(propagator),
+        None => global::get_text_map_propagator(inject),
+    }
+}
+
+/// The inbound headers of [`crate::Layer::with_forwarded_headers`], carried in the request's
+/// [`Context`].
+#[derive(Debug, Clone)]
+pub(crate) struct ForwardedHeaders(Vec<(HeaderName, HeaderValue)>);
+
+impl ForwardedHeaders {
+    /// Add every value of the `headers` called any of `names` to `cx`.
+    pub(crate) fn capture(headers: &HeaderMap, names: &[HeaderName], cx: Context) -> Context {
+        let forwarded: Vec<_> = names
+            .iter()
+            .flat_map(|name| headers.get_all(name).iter().map(move |value| (name.clone(), value.clone())))
+            .collect();
+        if forwarded.is_empty() {
+            return cx;
+        }
+        cx.with_value(Self(forwarded))
+    }
+
+    /// Insert the forwarded headers of `cx` into `headers`, keeping any already set.
+    fn inject(cx: &Context, headers: &mut HeaderMap) {
+        let Some(Self(forwarded)) = cx.get::<Self>() else {
+            return;
+        };
+        let already_set: Vec<bool> = forwarded.iter().map(|(name, _)| headers.contains_key(name)).collect();
+        for ((name, value), already_set) in forwarded.iter().zip(already_set) {
+            if !already_set {
+                headers.append(name.clone(), value.clone());
+            }
+        }
+    }
+}
+
+/// [`Layer`] that adds [opentelemetry propagation] to an HTTP client [`Service`].
+///
+/// [`Layer`]: tower_layer::Layer
+/// [opentelemetry propagation]: https://opentelemetry.io/docs/java/manual_instrumentation/#context-propagation
+/// [`Service`]: tower_service::Service
+#[derive(Debug, Clone, Default)]
+pub struct Layer {
+    tracer: Option<BoxedTracer>,
+    propagator: Option<Arc<Propagator>>,
+    invalid_value_policy: InvalidValuePolicy,
+    forwarded_headers: bool,
+}
+
+impl Layer {
+    /// Create a new client [`Layer`] using the global tracer provider.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build spans with a tracer from `provider` instead of the global tracer provider.
+    ///
+    /// SDK tracers only hold a weak reference to their provider, so `provider` must be kept alive
+    /// for as long as spans should be recorded.
+    #[must_use]
+    pub fn with_tracer_provider<P>(mut self, provider: &P) -> Self
+    where
+        P: TracerProvider,
+        P::Tracer: Send + Sync + 'static,
+        <P::Tracer as Tracer>::Span: Send + Sync + 'static,
+    {
+        self.tracer = Some(BoxedTracer::from_provider(provider, None));
+        self
+    }
+
+    /// Inject the context with `propagator` instead of the global text map propagator, like
+    /// [`crate::Layer::with_propagator`] does for inbound requests.
+    #[must_use]
+    pub fn with_propagator<P>(mut self, propagator: P) -> Self
+    where
+        P: TextMapPropagator + Send + Sync + 'static,
+    {
+        self.propagator = Some(Arc::new(propagator));
+        self
+    }
+
+    /// Choose what happens to injected header values that aren't valid in HTTP headers,
+    /// defaulting to [`InvalidValuePolicy::Drop`].
+    #[must_use]
+    pub fn with_invalid_value_policy(mut self, policy: InvalidValuePolicy) -> Self {
+        self.invalid_value_policy = policy;
+        self
+    }
+
+    /// Whether to copy the inbound headers of [`crate::Layer::with_forwarded_headers`] from the
+    /// current context onto outbound requests.
+    ///
+    /// Headers the outbound request already has are kept. Disabled by default, so only clients
+    /// calling trusted services forward them.
+    #[must_use]
+    pub fn with_forwarded_headers(mut self, enabled: bool) -> Self {
+        self.forwarded_headers = enabled;
+        self
+    }
+}
+
+impl<S> tower_layer::Layer<S> for Layer {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service {
+            inner,
+            tracer: self.tracer.clone().unwrap_or_else(|| BoxedTracer::global(None)),
+            propagator: self.propagator.clone(),
+            invalid_value_policy: self.invalid_value_policy,
+            forwarded_headers: self.forwarded_headers,
+        }
+    }
+}
+
+/// Middleware [`Service`] that starts a client span for each outbound request and injects the
+/// current context into its headers.
+///
+/// [`Service`]: tower_service::Service
+#[derive(Debug, Clone)]
+pub struct Service<S> {
+    inner: S,
+    tracer: BoxedTracer,
+    propagator: Option<Arc<Propagator>>,
+    invalid_value_policy: InvalidValuePolicy,
+    forwarded_headers: bool,
+}
+
+type CF<R, E> = dyn Future<Output = Result<R, E>> + Send;
+impl<B, ResBody, S> tower_service::Service<Request<B>> for Service<S>
+where
+    S: tower_service::Service<Request<B>, Response = Response<ResBody>>,
+    S::Future: 'static + Send,
+    S::Error: StdError,
+{
+    type Error = S::Error;
+    type Future = Pin<Box<CF<Self::Response, Self::Error>>>;
+    type Response = S::Response;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, mut req: Request<B>) -> Self::Future {
+        let method = http_method_str(req.method());
+        let builder = self
+            .tracer
+            .span_builder(format!("HTTP {method}"))
+            .with_kind(SpanKind::Client)
+            .with_attributes(vec![
+                HTTP_METHOD.string(method),
+                HTTP_FLAVOR.string(http_flavor(req.version())),
+                HTTP_URL.string(req.uri().to_string()),
+            ]);
+        let cx = self.tracer.build_with_context(builder, &Context::current());
+        inject(&cx, req.headers_mut(), self.invalid_value_policy, self.propagator.as_deref());
+        if self.forwarded_headers {
+            ForwardedHeaders::inject(&cx, req.headers_mut());
+        }
+
+        let mut guard = SpanGuard::new(cx.clone());
+        Box::pin(self.inner.call(req).with_context(cx).map(move |res| {
+            let span = guard.context().span();
+            match &res {
+                Ok(res) => {
+                    span.set_attribute(HTTP_STATUS_CODE.i64(i64::from(res.status().as_u16())));
+                    if res.status().is_client_error() || res.status().is_server_error() {
+                        span.set_status(
+                            StatusCode::Error,
+                            res.status().canonical_reason().unwrap_or_default().to_string(),
+                        );
+                    }
+                }
+                Err(error) => {
+                    span.set_status(StatusCode::Error, error.to_string());
+                    span.record_exception(error);
+                }
+            }
+            guard.end();
+            res
+        }))
+    }
+}
@@ -0,0 +1,388 @@
+//! Layer settings that can be loaded from configuration files.
+//!
+//! [`Config`] holds the options of [`Layer`] that are plain data and implements
+//! [`serde::Deserialize`], so a service can read them from its own configuration and build the
+//! layer with [`Layer::from_config`]:
+//!
+//! ```
+//! # fn main() -> Result<(), serde_json::Error> {
+//! let config: tower_opentelemetry::config::Config = serde_json::from_str(
+//!     r#"{
+//!         "excluded_paths": ["/healthz"],
+//!         "user_agent": "classified",
+//!         "request_id_header": "x-request-id"
+//!     }"#,
+//! )?;
+//! let layer = tower_opentelemetry::Layer::from_config(config);
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! Every field is optional and a missing field keeps the [`Layer`] default. Options that take
+//! closures or values only known at runtime can't be expressed in a file and are only available
+//! on [`Layer`]: the classifier, tracer provider, propagator, sampler, request sampler, tail
+//! sampler, resource detectors, schema URL, span kind, messaging attributes, span builder hook,
+//! span namer, request and async attributes, route overrides, request and response observers,
+//! reload handle, request id generator, and the retry and cache hit header rules. Chain them onto
+//! the layer returned by [`Layer::from_config`].
+use std::{collections::HashMap, time::Duration};
+
+use http::{HeaderName, Method};
+use serde::{
+    de::{self, Deserializer},
+    Deserialize,
+};
+
+use crate::{
+    ClientIpRecording, DurationUnit, InvalidValuePolicy, Layer, QueryRecording, RefererRecording, ResponseInjection,
+    StartTimeFormat, TraceContinuation, UrlRecording, UserAgentRecording,
+};
+
+/// The data-only options of a [`Layer`], see the [module documentation](self).
+///
+/// Each field corresponds to the `Layer::with_*` method of the same name.
+#[derive(Debug, Clone, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+#[non_exhaustive]
+pub struct Config {
+    /// See [`Layer::with_propagation_warning`].
+    pub propagation_warning: Option<bool>,
+    /// See [`Layer::with_deployment_environment_var`].
+    pub deployment_environment_var: Option<String>,
+    /// See [`Layer::with_trace_continuation`].
+    pub trace_continuation: Option<TraceContinuation>,
+    /// See [`Layer::with_upstream_continuation`].
+    pub upstream_continuation: Option<bool>,
+    /// See [`Layer::with_response_injection`].
+    pub response_injection: Option<ResponseInjection>,
+    /// See [`Layer::with_invalid_value_policy`].
+    pub invalid_value_policy: Option<InvalidValuePolicy>,
+    /// See [`Layer::with_traceresponse`].
+    pub traceresponse: Option<bool>,
+    /// See [`Layer::with_trace_id_header`].
+    #[serde(deserialize_with = "header_name")]
+    pub trace_id_header: Option<HeaderName>,
+    /// See [`Layer::with_legacy_trace_id_header`].
+    #[serde(deserialize_with = "header_name")]
+    pub legacy_trace_id_header: Option<HeaderName>,
+    /// See [`Layer::with_request_id`].
+    #[serde(deserialize_with = "header_name")]
+    pub request_id_header: Option<HeaderName>,
+    /// See [`Layer::with_idempotency_key_header`].
+    #[serde(deserialize_with = "header_name")]
+    pub idempotency_key_header: Option<HeaderName>,
+    /// The header of [`Layer::with_link_header`], used with
+    /// [`max_links`](Config::max_links).
+    #[serde(deserialize_with = "header_name")]
+    pub link_header: Option<HeaderName>,
+    /// The maximum number of links for [`link_header`](Config::link_header), defaulting to 8.
+    pub max_links: Option<usize>,
+    /// See [`Layer::with_excluded_paths`].
+    pub excluded_paths: Vec<String>,
+    /// The methods of [`Layer::with_excluded_methods`].
+    #[serde(deserialize_with = "methods")]
+    pub excluded_methods: Vec<Method>,
+    /// The renamed keys of [`Layer::with_attribute_keys`], from the recorded key to its new name.
+    pub attribute_keys: HashMap<String, String>,
+    /// The keys of [`Layer::with_baggage_attributes`].
+    pub baggage_attributes: Vec<String>,
+    /// The header names of [`Layer::with_forwarded_headers`].
+    #[serde(deserialize_with = "header_names")]
+    pub forwarded_headers: Vec<HeaderName>,
+    /// The keys of [`Layer::with_error_only_attributes`].
+    pub error_only_attributes: Vec<String>,
+    /// See [`Layer::with_minimal_attributes`].
+    pub minimal_attributes: Option<bool>,
+    /// See [`Layer::with_bare_spans`].
+    pub bare_spans: Option<bool>,
+    /// The methods of [`Layer::with_minimal_attributes_for`].
+    #[serde(deserialize_with = "methods")]
+    pub minimal_attributes_for: Vec<Method>,
+    /// See [`Layer::with_stream_id`].
+    pub stream_id: Option<bool>,
+    /// See [`Layer::with_http2_negotiation`].
+    pub http2_negotiation: Option<bool>,
+    /// See [`Layer::with_tls_server_name`].
+    pub tls_server_name: Option<bool>,
+    /// See [`Layer::with_http2_priority`].
+    pub http2_priority: Option<bool>,
+    /// See [`Layer::with_http2_pushed`].
+    pub http2_pushed: Option<bool>,
+    /// See [`Layer::with_unix_peer`].
+    pub unix_peer: Option<bool>,
+    /// See [`Layer::with_trusted_forwarded_headers`].
+    pub trusted_forwarded_headers: Option<bool>,
+    /// See [`Layer::with_trusted_hops`].
+    pub trusted_hops: Option<usize>,
+    /// See [`Layer::with_host_authority`].
+    pub host_authority: Option<bool>,
+    /// See [`Layer::with_invalid_host_marker`].
+    pub invalid_host_marker: Option<bool>,
+    /// See [`Layer::with_response_content_type`].
+    pub response_content_type: Option<bool>,
+    /// See [`Layer::with_custom_attribute_prefix`].
+    pub custom_attribute_prefix: Option<String>,
+    /// See [`Layer::with_query_trace_context`].
+    pub query_trace_context: Option<String>,
+    /// See [`Layer::with_user_agent`].
+    pub user_agent: Option<UserAgentRecording>,
+    /// See [`Layer::with_request_start_time`].
+    pub request_start_time: Option<StartTimeFormat>,
+    /// See [`Layer::with_span_events`].
+    pub span_events: Option<bool>,
+    /// See [`Layer::with_request_context`].
+    pub request_context: Option<bool>,
+    /// See [`Layer::with_context_attachment`].
+    pub context_attachment: Option<bool>,
+    /// See [`Layer::with_max_span_name_len`].
+    pub max_span_name_len: Option<usize>,
+    /// See [`Layer::with_status_text`].
+    pub status_text: Option<bool>,
+    /// See [`Layer::with_status_class`].
+    pub status_class: Option<bool>,
+    /// See [`Layer::with_inner_service_type`].
+    pub inner_service_type: Option<bool>,
+    /// See [`Layer::with_inner_service_label`].
+    pub inner_service_label: Option<String>,
+    /// See [`Layer::with_route_matched`].
+    pub route_matched: Option<bool>,
+    /// See [`Layer::with_referer`].
+    pub referer: Option<RefererRecording>,
+    /// See [`Layer::with_received_at_start_time`].
+    pub received_at_start_time: Option<bool>,
+    /// See [`Layer::with_accept_encoding`].
+    pub accept_encoding: Option<bool>,
+    /// See [`Layer::with_accept`].
+    pub accept: Option<bool>,
+    /// See [`Layer::with_content_encoding`].
+    pub content_encoding: Option<bool>,
+    /// See [`Layer::with_content_language`].
+    pub content_language: Option<bool>,
+    /// See [`Layer::with_response_age`].
+    pub response_age: Option<bool>,
+    /// See [`Layer::with_response_error`].
+    pub response_error: Option<bool>,
+    /// See [`Layer::with_chunked_response`].
+    pub chunked_response: Option<bool>,
+    /// See [`Layer::with_url`].
+    pub url: Option<bool>,
+    /// See [`Layer::with_url_recording`].
+    pub url_recording: Option<UrlRecording>,
+    /// See [`Layer::with_client_ip`].
+    pub client_ip: Option<ClientIpRecording>,
+    /// Whether to apply [`Layer::with_privacy_mode`], after the other options.
+    pub privacy_mode: Option<bool>,
+    /// See [`Layer::with_duration_attribute`].
+    pub duration_attribute: Option<DurationUnit>,
+    /// The minimum duration of [`Layer::with_min_export_duration`], in milliseconds.
+    pub min_export_duration_ms: Option<u64>,
+    /// See [`Layer::with_max_active_spans`].
+    pub max_active_spans: Option<usize>,
+    /// See [`Layer::with_ready_wait`].
+    pub ready_wait: Option<bool>,
+    /// See [`Layer::with_clock_skew_attributes`].
+    pub clock_skew_attributes: Option<bool>,
+    /// See [`Layer::with_ok_status`].
+    pub ok_status: Option<bool>,
+    /// See [`Layer::with_structured_exceptions`].
+    pub structured_exceptions: Option<bool>,
+    /// See [`Layer::with_query`].
+    pub query: Option<QueryRecording>,
+    /// See [`Layer::with_query_param_count`].
+    pub query_param_count: Option<bool>,
+    /// See [`Layer::with_tunnel_spans`].
+    pub tunnel_spans: Option<bool>,
+    /// See [`Layer::with_deferred_span_end`].
+    pub deferred_span_end: Option<bool>,
+    /// See [`Layer::with_grpc`].
+    #[cfg(feature = "grpc")]
+    pub grpc: Option<bool>,
+    /// See [`Layer::with_tracing_span`].
+    #[cfg(feature = "tracing")]
+    pub tracing_span: Option<bool>,
+    /// See [`Layer::with_dry_run`].
+    #[cfg(feature = "dry-run")]
+    pub dry_run: Option<bool>,
+    /// See [`Layer::with_metrics`].
+    #[cfg(feature = "metrics")]
+    pub metrics: Option<bool>,
+    /// See [`Layer::with_rejection_metric`].
+    #[cfg(feature = "metrics")]
+    pub rejection_metric: Option<bool>,
+    /// See [`Layer::with_exclusion_metric`].
+    #[cfg(feature = "metrics")]
+    pub exclusion_metric: Option<bool>,
+    /// See [`Layer::with_abandonment_metric`].
+    #[cfg(feature = "metrics")]
+    pub abandonment_metric: Option<bool>,
+    /// See [`Layer::with_metric_method_original`].
+    #[cfg(feature = "metrics")]
+    pub metric_method_original: Option<bool>,
+}
+
+/// The number of links kept from [`Config::link_header`] when [`Config::max_links`] is unset.
+const DEFAULT_MAX_LINKS: usize = 8;
+
+impl Layer {
+    /// Create a [`Layer`] from the options in `config`, leaving the rest at their defaults.
+    #[must_use]
+    pub fn from_config(mut config: Config) -> Self {
+        let mut layer = Self::new();
+        macro_rules! apply {
+            ($($field:ident => $method:ident),* $(,)?) => {
+                $(if let Some(value) = config.$field.take() {
+                    layer = layer.$method(value);
+                })*
+            };
+        }
+        apply! {
+            propagation_warning => with_propagation_warning,
+            trace_continuation => with_trace_continuation,
+            upstream_continuation => with_upstream_continuation,
+            response_injection => with_response_injection,
+            invalid_value_policy => with_invalid_value_policy,
+            traceresponse => with_traceresponse,
+            trace_id_header => with_trace_id_header,
+            legacy_trace_id_header => with_legacy_trace_id_header,
+            request_id_header => with_request_id,
+            idempotency_key_header => with_idempotency_key_header,
+            minimal_attributes => with_minimal_attributes,
+            bare_spans => with_bare_spans,
+            stream_id => with_stream_id,
+            http2_negotiation => with_http2_negotiation,
+            tls_server_name => with_tls_server_name,
+            http2_priority => with_http2_priority,
+            http2_pushed => with_http2_pushed,
+            unix_peer => with_unix_peer,
+            trusted_forwarded_headers => with_trusted_forwarded_headers,
+            trusted_hops => with_trusted_hops,
+            host_authority => with_host_authority,
+            invalid_host_marker => with_invalid_host_marker,
+            response_content_type => with_response_content_type,
+            custom_attribute_prefix => with_custom_attribute_prefix,
+            query_trace_context => with_query_trace_context,
+            user_agent => with_user_agent,
+            request_start_time => with_request_start_time,
+            span_events => with_span_events,
+            request_context => with_request_context,
+            context_attachment => with_context_attachment,
+            max_span_name_len => with_max_span_name_len,
+            status_text => with_status_text,
+            status_class => with_status_class,
+            inner_service_type => with_inner_service_type,
+            inner_service_label => with_inner_service_label,
+            route_matched => with_route_matched,
+            referer => with_referer,
+            received_at_start_time => with_received_at_start_time,
+            accept_encoding => with_accept_encoding,
+            accept => with_accept,
+            content_encoding => with_content_encoding,
+            content_language => with_content_language,
+            response_age => with_response_age,
+            response_error => with_response_error,
+            chunked_response => with_chunked_response,
+            url => with_url,
+            url_recording => with_url_recording,
+            client_ip => with_client_ip,
+            duration_attribute => with_duration_attribute,
+            ready_wait => with_ready_wait,
+            clock_skew_attributes => with_clock_skew_attributes,
+            ok_status => with_ok_status,
+            structured_exceptions => with_structured_exceptions,
+            query => with_query,
+            query_param_count => with_query_param_count,
+            tunnel_spans => with_tunnel_spans,
+            deferred_span_end => with_deferred_span_end,
+            max_active_spans => with_max_active_spans,
+        }
+        #[cfg(feature = "grpc")]
+        apply! { grpc => with_grpc }
+        #[cfg(feature = "tracing")]
+        apply! { tracing_span => with_tracing_span }
+        #[cfg(feature = "dry-run")]
+        apply! { dry_run => with_dry_run }
+        #[cfg(feature = "metrics")]
+        apply! {
+            metrics => with_metrics,
+            rejection_metric => with_rejection_metric,
+            exclusion_metric => with_exclusion_metric,
+            abandonment_metric => with_abandonment_metric,
+            metric_method_original => with_metric_method_original,
+        }
+        apply_remaining(layer, config)
+    }
+}
+
+/// Apply the options of `config` that aren't passed to a builder method as they are.
+fn apply_remaining(mut layer: Layer, config: Config) -> Layer {
+    if let Some(var) = &config.deployment_environment_var {
+        layer = layer.with_deployment_environment_var(var);
+    }
+    if let Some(millis) = config.min_export_duration_ms {
+        layer = layer.with_min_export_duration(Duration::from_millis(millis));
+    }
+    if let Some(name) = config.link_header {
+        layer = layer.with_link_header(name, config.max_links.unwrap_or(DEFAULT_MAX_LINKS));
+    }
+    if !config.excluded_paths.is_empty() {
+        layer = layer.with_excluded_paths(config.excluded_paths);
+    }
+    if !config.excluded_methods.is_empty() {
+        layer = layer.with_excluded_methods(config.excluded_methods);
+    }
+    if !config.attribute_keys.is_empty() {
+        layer = layer.with_attribute_keys(config.attribute_keys);
+    }
+    if !config.baggage_attributes.is_empty() {
+        layer = layer.with_baggage_attributes(config.baggage_attributes);
+    }
+    if !config.forwarded_headers.is_empty() {
+        layer = layer.with_forwarded_headers(config.forwarded_headers);
+    }
+    if !config.error_only_attributes.is_empty() {
+        layer = layer.with_error_only_attributes(config.error_only_attributes);
+    }
+    if !config.minimal_attributes_for.is_empty() {
+        layer = layer.with_minimal_attributes_for(config.minimal_attributes_for);
+    }
+    if config.privacy_mode == Some(true) {
+        layer = layer.with_privacy_mode();
+    }
+    layer
+}
+
+/// Deserialize an optional header name from a string.
+fn header_name<'de, D>(deserializer: D) -> Result<Option<HeaderName>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid("a header name", &name)))
+        .transpose()
+}
+
+/// Deserialize a list of header names from strings.
+fn header_names<'de, D>(deserializer: D) -> Result<Vec<HeaderName>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|name| HeaderName::from_bytes(name.as_bytes()).map_err(|_| invalid("a header name", &name)))
+        .collect()
+}
+
+/// Deserialize a list of HTTP methods from strings.
+fn methods<'de, D>(deserializer: D) -> Result<Vec<Method>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|method| Method::from_bytes(method.as_bytes()).map_err(|_| invalid("an HTTP method", &method)))
+        .collect()
+}
+
+fn invalid<E: de::Error>(expected: &str, value: &str) -> E {
+    E::custom(format!("invalid value {value:?}, expected {expected}"))
+}
@@ -0,0 +1,101 @@
+//! Connection details that servers can expose to the layer through request extensions.
+use std::{net::SocketAddr, path::PathBuf};
+
+/// Details about the connection a request arrived on.
+///
+/// The [`Service`] reads this from the request extensions, so a server integration (or an
+/// earlier layer with access to the connection) must insert it before the request reaches the
+/// [`Service`]. Every field is optional and attributes are only recorded for the fields that are
+/// set and enabled on the [`Layer`].
+///
+/// ```
+/// let mut info = tower_opentelemetry::ConnectionInfo::default();
+/// info.http2_stream_id = Some(3);
+/// ```
+///
+/// [`Service`]: crate::Service
+/// [`Layer`]: crate::Layer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct ConnectionInfo {
+    /// The address of the peer at the other end of the connection.
+    pub peer_addr: Option<SocketAddr>,
+    /// The HTTP/2 stream identifier the request was sent on.
+    pub http2_stream_id: Option<u32>,
+    /// The identity of the peer process when the connection is a Unix domain socket.
+    pub unix_peer: Option<UnixPeer>,
+    /// How the connection came to speak HTTP/2.
+    pub http2_negotiation: Option<Http2Negotiation>,
+    /// The priority the client gave the HTTP/2 stream the request was sent on.
+    pub http2_priority: Option<Http2Priority>,
+    /// Whether the request is a promised request of HTTP/2 server push, answered with a pushed
+    /// response rather than one the client asked for.
+    pub http2_pushed: Option<bool>,
+    /// Whether the connection is encrypted with TLS, which takes precedence over every other
+    /// source of `http.scheme`.
+    ///
+    /// Leave this unset behind a proxy that terminates TLS, since the connection to the proxy
+    /// then says nothing about the scheme the client used.
+    pub tls: Option<bool>,
+    /// The server name the client requested through TLS SNI, which may differ from the `Host`
+    /// header.
+    pub tls_server_name: Option<String>,
+}
+
+/// How a connection came to speak HTTP/2, recorded as `network.protocol.negotiation` when
+/// [`Layer::with_http2_negotiation`] is enabled.
+///
+/// [`Layer::with_http2_negotiation`]: crate::Layer::with_http2_negotiation
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum Http2Negotiation {
+    /// `h2` was selected through TLS ALPN, recorded as `alpn`.
+    Alpn,
+    /// The client sent the HTTP/2 preface without negotiating (h2c prior knowledge), recorded as
+    /// `prior_knowledge`.
+    PriorKnowledge,
+    /// The connection was upgraded from HTTP/1.1 with `Upgrade: h2c`, recorded as `upgrade`.
+    Upgrade,
+}
+
+impl Http2Negotiation {
+    /// The attribute value for this negotiation.
+    pub(crate) fn as_str(self) -> &'static str {
+        match self {
+            Self::Alpn => "alpn",
+            Self::PriorKnowledge => "prior_knowledge",
+            Self::Upgrade => "upgrade",
+        }
+    }
+}
+
+/// The priority of an HTTP/2 stream, from its `HEADERS` or `PRIORITY` frame, recorded when
+/// [`Layer::with_http2_priority`] is enabled.
+///
+/// [`Layer::with_http2_priority`]: crate::Layer::with_http2_priority
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct Http2Priority {
+    /// The weight of the stream, from 1 to 256, recorded as `http2.priority.weight`.
+    pub weight: Option<u16>,
+    /// The stream this stream depends on, recorded as `http2.priority.stream_dependency`.
+    pub stream_dependency: Option<u32>,
+}
+
+/// The identity of the process at the other end of a Unix domain socket connection, typically
+/// obtained from the socket's peer credentials.
+///
+/// Recorded when [`Layer::with_unix_peer`] is enabled and the [`ConnectionInfo`] has no
+/// [`peer_addr`](ConnectionInfo::peer_addr).
+///
+/// [`Layer::with_unix_peer`]: crate::Layer::with_unix_peer
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[non_exhaustive]
+pub struct UnixPeer {
+    /// The path of the socket, recorded as `net.peer.name`.
+    pub path: Option<PathBuf>,
+    /// The user ID of the peer process, recorded as `peer.uid`.
+    pub uid: Option<u32>,
+    /// The process ID of the peer process, recorded as `peer.pid`.
+    pub pid: Option<u32>,
+}
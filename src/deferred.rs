@@ -0,0 +1,63 @@
+//! Spans that end after the response body rather than with the response.
+use opentelemetry::Context;
+
+use crate::guard::SpanGuard;
+#[cfg(feature = "metrics")]
+use crate::metrics::PendingBody;
+
+/// Keeps the span of a request open after its response is produced, until it is dropped.
+///
+/// When [`Layer::with_deferred_span_end`] is enabled, every response carries a [`DeferredSpan`]
+/// in its extensions instead of the span ending as soon as the response is produced. A body
+/// wrapper such as [`response_body::Layer`] takes it out of the extensions and ends the span once
+/// the body has been streamed, after recording what it observed. Without such a wrapper the span
+/// ends when the response's extensions are dropped.
+///
+/// With [`Layer::with_metrics`] also enabled, `http.server.duration` is recorded when the
+/// [`DeferredSpan`] is dropped, so it measures the time to the last byte of the body, while
+/// `http.server.ttfb` measures the time to the response head.
+///
+/// [`Layer::with_deferred_span_end`]: crate::Layer::with_deferred_span_end
+/// [`Layer::with_metrics`]: crate::Layer::with_metrics
+/// [`response_body::Layer`]: crate::response_body::Layer
+#[derive(Debug)]
+pub struct DeferredSpan {
+    guard: SpanGuard,
+    #[cfg(feature = "metrics")]
+    metrics: Option<PendingBody>,
+}
+
+impl DeferredSpan {
+    pub(crate) fn new(guard: SpanGuard) -> Self {
+        Self {
+            guard,
+            #[cfg(feature = "metrics")]
+            metrics: None,
+        }
+    }
+
+    /// Record the request's duration with `metrics` once the span ends.
+    #[cfg(feature = "metrics")]
+    pub(crate) fn with_metrics(mut self, metrics: Option<PendingBody>) -> Self {
+        self.metrics = metrics;
+        self
+    }
+
+    /// The [`Context`] holding the request's span, for recording attributes before it ends.
+    #[must_use]
+    pub fn context(&self) -> &Context {
+        self.guard.context()
+    }
+
+    /// End the request's span now rather than when the [`DeferredSpan`] is dropped.
+    pub fn end(self) {}
+}
+
+#[cfg(feature = "metrics")]
+impl Drop for DeferredSpan {
+    fn drop(&mut self) {
+        if let Some(metrics) = self.metrics.take() {
+            metrics.finish();
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! Resource detectors for the environment a service runs in, for [`Layer::with_detectors`].
+//!
+//! Each detector only reads local files and environment variables, and returns an empty
+//! [`Resource`] when it finds nothing, so they can all be passed unconditionally.
+//!
+//! [`Layer::with_detectors`]: crate::Layer::with_detectors
+use std::{fs, time::Duration};
+
+use opentelemetry::{
+    sdk::{resource::ResourceDetector, Resource},
+    KeyValue,
+};
+use opentelemetry_semantic_conventions::resource::{CLOUD_REGION, CONTAINER_ID, K8S_NAMESPACE_NAME, K8S_POD_NAME};
+
+/// The length of a container id in hexadecimal digits.
+const CONTAINER_ID_LEN: usize = 64;
+
+/// The namespace Kubernetes mounts into every pod with a service account.
+const K8S_NAMESPACE_FILE: &str = "/var/run/secrets/kubernetes.io/serviceaccount/namespace";
+
+/// The environment variables naming the cloud region, in order of preference.
+const CLOUD_REGION_VARS: [&str; 4] = ["CLOUD_REGION", "AWS_REGION", "AWS_DEFAULT_REGION", "GOOGLE_CLOUD_REGION"];
+
+/// Detects `container.id` from the control groups of the current process.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ContainerDetector {
+    _private: (),
+}
+
+impl ContainerDetector {
+    /// Create a new container detector.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ResourceDetector for ContainerDetector {
+    fn detect(&self, _timeout: Duration) -> Resource {
+        let id = fs::read_to_string("/proc/self/cgroup")
+            .ok()
+            .and_then(|cgroup| container_id(&cgroup));
+        Resource::new(id.map(|id| CONTAINER_ID.string(id)))
+    }
+}
+
+/// The container id in the contents of `/proc/self/cgroup`, such as the `<id>` of
+/// `0::/system.slice/docker-<id>.scope` or `12:cpu:/docker/<id>`.
+pub(crate) fn container_id(cgroup: &str) -> Option<String> {
+    cgroup.lines().find_map(|line| {
+        let segment = line.rsplit('/').next()?;
+        let segment = segment.strip_suffix(".scope").unwrap_or(segment);
+        let id = segment.rsplit('-').next()?;
+        (id.len() == CONTAINER_ID_LEN && id.bytes().all(|b| b.is_ascii_hexdigit())).then(|| id.to_string())
+    })
+}
+
+/// Detects `k8s.pod.name` and `k8s.namespace.name` when running in a Kubernetes pod.
+///
+/// The pod name is the `HOSTNAME`, which Kubernetes sets to the pod's name, and the namespace is
+/// read from the service account mount. Nothing is detected outside Kubernetes, recognized by the
+/// `KUBERNETES_SERVICE_HOST` variable every pod receives.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct KubernetesDetector {
+    _private: (),
+}
+
+impl KubernetesDetector {
+    /// Create a new Kubernetes detector.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ResourceDetector for KubernetesDetector {
+    fn detect(&self, _timeout: Duration) -> Resource {
+        if std::env::var_os("KUBERNETES_SERVICE_HOST").is_none() {
+            return Resource::empty();
+        }
+        let mut attributes = Vec::new();
+        if let Ok(pod) = std::env::var("HOSTNAME") {
+            attributes.push(K8S_POD_NAME.string(pod));
+        }
+        if let Ok(namespace) = fs::read_to_string(K8S_NAMESPACE_FILE) {
+            attributes.push(K8S_NAMESPACE_NAME.string(namespace.trim().to_string()));
+        }
+        Resource::new(attributes)
+    }
+}
+
+/// Detects `cloud.region` from the first of `CLOUD_REGION`, `AWS_REGION`, `AWS_DEFAULT_REGION`
+/// and `GOOGLE_CLOUD_REGION` that is set.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CloudRegionDetector {
+    _private: (),
+}
+
+impl CloudRegionDetector {
+    /// Create a new cloud region detector.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ResourceDetector for CloudRegionDetector {
+    fn detect(&self, _timeout: Duration) -> Resource {
+        let region = CLOUD_REGION_VARS
+            .iter()
+            .find_map(|var| std::env::var(var).ok().filter(|region| !region.is_empty()));
+        Resource::new(region.map(|region| KeyValue::new(CLOUD_REGION, region)))
+    }
+}
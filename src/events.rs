@@ -0,0 +1,63 @@
+//! Span events added by handlers through request extensions.
+use std::{
+    borrow::Cow,
+    sync::{Arc, Mutex},
+    time::SystemTime,
+};
+
+use opentelemetry::{trace::TraceContextExt, Context, KeyValue};
+
+type Event = (Cow<'static, str>, SystemTime, Vec<KeyValue>);
+
+/// A handle for adding events to the request's server span without depending on OpenTelemetry's
+/// tracing API.
+///
+/// When [`Layer::with_span_events`] is enabled, the [`Service`] inserts a [`SpanEvents`] into the
+/// request extensions. Handlers can clone it out of the extensions and [`add`] events, which are
+/// recorded on the span with the time they were added once the response is produced. Events added
+/// after that are discarded.
+///
+/// ```
+/// use http::Request;
+/// use opentelemetry::KeyValue;
+/// use tower_opentelemetry::SpanEvents;
+///
+/// fn handler(req: Request<()>) {
+///     if let Some(events) = req.extensions().get::<SpanEvents>() {
+///         events.add("cache.miss", vec![KeyValue::new("cache.key", "users")]);
+///     }
+/// }
+/// # handler(Request::new(()));
+/// ```
+///
+/// [`Layer::with_span_events`]: crate::Layer::with_span_events
+/// [`Service`]: crate::Service
+/// [`add`]: SpanEvents::add
+#[derive(Debug, Clone, Default)]
+pub struct SpanEvents {
+    events: Arc<Mutex<Vec<Event>>>,
+}
+
+impl SpanEvents {
+    /// Queue an event named `name` with `attributes` for the request's span.
+    pub fn add<T>(&self, name: T, attributes: Vec<KeyValue>)
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        if let Ok(mut events) = self.events.lock() {
+            events.push((name.into(), SystemTime::now(), attributes));
+        }
+    }
+
+    /// Record every queued event on the span in `cx`, leaving the queue empty.
+    pub(crate) fn drain_into(&self, cx: &Context) {
+        let events = match self.events.lock() {
+            Ok(mut events) => std::mem::take(&mut *events),
+            Err(_) => return,
+        };
+        let span = cx.span();
+        for (name, timestamp, attributes) in events {
+            span.add_event_with_timestamp(name, timestamp, attributes);
+        }
+    }
+}
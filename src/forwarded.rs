@@ -0,0 +1,123 @@
+//! Derivation of the request scheme and client address, optionally trusting forwarded headers.
+//!
+//! `X-Forwarded-Proto` and `X-Forwarded-For` are only meaningful when set by a trusted proxy; on
+//! a directly exposed service any client can send them. They are therefore ignored unless
+//! [`Layer::with_trusted_forwarded_headers`] is enabled, in which case they take precedence over
+//! what the connection and the `Host` header reveal.
+//!
+//! The scheme is taken from the first of these that is known:
+//!
+//! 1. the [`ConnectionInfo::tls`] flag of the connection the request arrived on,
+//! 2. `X-Forwarded-Proto`, when trusted,
+//! 3. the URI of requests in absolute form, such as `GET http://example.com/path` sent to a
+//!    forward proxy.
+//!
+//! The authority of an absolute-form URI is likewise preferred over the `Host` header.
+//!
+//! [`Layer::with_trusted_forwarded_headers`]: crate::Layer::with_trusted_forwarded_headers
+use std::{
+    convert::TryFrom,
+    net::{IpAddr, SocketAddr},
+};
+
+use http::{header, uri::Authority, HeaderMap, HeaderName, Request};
+
+use crate::ConnectionInfo;
+
+static X_FORWARDED_PROTO: HeaderName = HeaderName::from_static("x-forwarded-proto");
+static X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
+
+/// The scheme of the request, from the connection's TLS flag, `X-Forwarded-Proto` when
+/// `trusted`, or an absolute-form request URI, in that order.
+pub(crate) fn scheme<B>(req: &Request<B>, trusted: bool) -> Option<String> {
+    if let Some(tls) = req.extensions().get::<ConnectionInfo>().and_then(|info| info.tls) {
+        return Some(if tls { "https" } else { "http" }.to_string());
+    }
+    if trusted {
+        if let Some(scheme) = first_value(req.headers(), &X_FORWARDED_PROTO) {
+            return Some(scheme.to_ascii_lowercase());
+        }
+    }
+    req.uri().scheme_str().map(str::to_ascii_lowercase)
+}
+
+/// The authority the request was addressed to, from the URI or else the `Host` header.
+pub(crate) fn authority<B>(req: &Request<B>) -> Option<Authority> {
+    req.uri().authority().cloned().or_else(|| {
+        req.headers()
+            .get(header::HOST)
+            .and_then(|host| Authority::try_from(host.as_bytes()).ok())
+    })
+}
+
+/// Whether the request has a `Host` header that isn't a valid authority, such as one with
+/// spaces or a non-numeric port, and no authority in its URI that would take precedence.
+pub(crate) fn has_invalid_host<B>(req: &Request<B>) -> bool {
+    req.uri().authority().is_none()
+        && req
+            .headers()
+            .get(header::HOST)
+            .is_some_and(|host| Authority::try_from(host.as_bytes()).is_err())
+}
+
+/// The port the request was addressed to, from its [`authority`].
+///
+/// Without an explicit port the default port of `scheme` is used, but only for a known scheme:
+/// guessing `80` when the scheme is unknown would mislabel TLS services behind a proxy.
+pub(crate) fn host_port(authority: Option<&Authority>, scheme: Option<&str>) -> Option<u16> {
+    authority.and_then(Authority::port_u16).or(match scheme {
+        Some("https") => Some(443),
+        Some("http") => Some(80),
+        _ => None,
+    })
+}
+
+/// The address of the client, from `X-Forwarded-For` when `trusted` and otherwise from the
+/// connection's peer address.
+///
+/// With `trusted_hops` of zero the leftmost `X-Forwarded-For` entry is used. Otherwise the entry
+/// `trusted_hops` from the right is, which is the address the outermost trusted proxy saw, or the
+/// leftmost entry if the chain is shorter than that.
+pub(crate) fn client_ip(
+    headers: &HeaderMap,
+    info: Option<&ConnectionInfo>,
+    trusted: bool,
+    trusted_hops: usize,
+) -> Option<IpAddr> {
+    if trusted {
+        if let Some(ip) = forwarded_for(headers, trusted_hops).and_then(parse_hop) {
+            return Some(ip);
+        }
+    }
+    info.and_then(|info| info.peer_addr).map(|addr| addr.ip())
+}
+
+/// The `X-Forwarded-For` entry selected by `trusted_hops`, across every instance of the header.
+fn forwarded_for(headers: &HeaderMap, trusted_hops: usize) -> Option<&str> {
+    if trusted_hops == 0 {
+        return first_value(headers, &X_FORWARDED_FOR);
+    }
+    let hops: Vec<&str> = headers
+        .get_all(&X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(str::trim)
+        .filter(|hop| !hop.is_empty())
+        .collect();
+    hops.get(hops.len().saturating_sub(trusted_hops)).copied()
+}
+
+/// Parse an `X-Forwarded-For` entry, which may carry a port and, for IPv6, brackets.
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    hop.parse()
+        .ok()
+        .or_else(|| hop.parse::<SocketAddr>().ok().map(|addr| addr.ip()))
+        .or_else(|| hop.strip_prefix('[')?.strip_suffix(']')?.parse().ok())
+}
+
+/// The first comma-separated value of the first `name` header.
+fn first_value<'a>(headers: &'a HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    let value = headers.get(name)?.to_str().ok()?;
+    value.split(',').map(str::trim).find(|v| !v.is_empty())
+}
@@ -0,0 +1,150 @@
+//! Propagation through gRPC's binary `grpc-trace-bin` metadata.
+//!
+//! gRPC metadata is carried in HTTP/2 headers, and keys ending in `-bin` hold binary values that
+//! are base64-encoded on the wire. Some gRPC stacks propagate the trace context in the
+//! [OpenCensus] binary format under `grpc-trace-bin` instead of, or alongside, `traceparent`. The
+//! [`GrpcTraceBinPropagator`] reads and writes that format, so it can be installed on its own or
+//! in a composite propagator to continue traces from such clients.
+//!
+//! With [`Layer::with_grpc`] enabled, gRPC and gRPC-Web requests are also recognized by their
+//! content type and their service and method recorded from the request path.
+//!
+//! [OpenCensus]: https://github.com/census-instrumentation/opencensus-specs/blob/master/encodings/BinaryEncoding.md
+//! [`Layer::with_grpc`]: crate::Layer::with_grpc
+use http::{header, Request};
+use lazy_static::lazy_static;
+use opentelemetry::{
+    propagation::{text_map_propagator::FieldIter, Extractor, Injector, TextMapPropagator},
+    trace::{SpanContext, SpanId, TraceContextExt, TraceFlags, TraceId, TraceState},
+    Context, KeyValue,
+};
+use opentelemetry_semantic_conventions::trace::{RPC_METHOD, RPC_SERVICE, RPC_SYSTEM};
+
+/// The metadata key holding the binary trace context.
+const GRPC_TRACE_BIN: &str = "grpc-trace-bin";
+
+lazy_static! {
+    static ref FIELDS: [String; 1] = [GRPC_TRACE_BIN.to_string()];
+}
+
+/// The only version of the binary format.
+const VERSION: u8 = 0;
+const TRACE_ID_FIELD: u8 = 0;
+const SPAN_ID_FIELD: u8 = 1;
+const TRACE_OPTIONS_FIELD: u8 = 2;
+/// The length of an encoded context: the version and three tagged fields.
+const ENCODED_LEN: usize = 1 + (1 + 16) + (1 + 8) + (1 + 1);
+
+/// A [`TextMapPropagator`] for the [OpenCensus] binary trace context in `grpc-trace-bin` metadata.
+///
+/// Values are base64-encoded as gRPC requires for `-bin` keys. Both padded and unpadded values
+/// are accepted, and injected values are unpadded.
+///
+/// ```
+/// use opentelemetry::sdk::propagation::{TextMapCompositePropagator, TraceContextPropagator};
+/// use tower_opentelemetry::GrpcTraceBinPropagator;
+///
+/// opentelemetry::global::set_text_map_propagator(TextMapCompositePropagator::new(vec![
+///     Box::new(TraceContextPropagator::new()),
+///     Box::new(GrpcTraceBinPropagator::new()),
+/// ]));
+/// ```
+///
+/// [OpenCensus]: https://github.com/census-instrumentation/opencensus-specs/blob/master/encodings/BinaryEncoding.md
+#[derive(Debug, Clone, Copy, Default)]
+pub struct GrpcTraceBinPropagator {
+    _private: (),
+}
+
+impl GrpcTraceBinPropagator {
+    /// Create a new `grpc-trace-bin` propagator.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl TextMapPropagator for GrpcTraceBinPropagator {
+    fn inject_context(&self, cx: &Context, injector: &mut dyn Injector) {
+        let span = cx.span();
+        let span_context = span.span_context();
+        if span_context.is_valid() {
+            injector.set(GRPC_TRACE_BIN, base64::encode_config(encode(span_context), base64::STANDARD_NO_PAD));
+        }
+    }
+
+    fn extract_with_context(&self, cx: &Context, extractor: &dyn Extractor) -> Context {
+        extractor
+            .get(GRPC_TRACE_BIN)
+            .and_then(|value| base64::decode_config(value.trim_end_matches('='), base64::STANDARD_NO_PAD).ok())
+            .and_then(|bytes| decode(&bytes))
+            .map_or_else(|| cx.clone(), |span_context| cx.with_remote_span_context(span_context))
+    }
+
+    fn fields(&self) -> FieldIter<'_> {
+        FieldIter::new(FIELDS.as_ref())
+    }
+}
+
+fn encode(span_context: &SpanContext) -> Vec<u8> {
+    let mut bytes = Vec::with_capacity(ENCODED_LEN);
+    bytes.push(VERSION);
+    bytes.push(TRACE_ID_FIELD);
+    bytes.extend_from_slice(&span_context.trace_id().to_bytes());
+    bytes.push(SPAN_ID_FIELD);
+    bytes.extend_from_slice(&span_context.span_id().to_bytes());
+    bytes.push(TRACE_OPTIONS_FIELD);
+    bytes.push(u8::from(span_context.is_sampled()));
+    bytes
+}
+
+fn decode(bytes: &[u8]) -> Option<SpanContext> {
+    if bytes.len() < ENCODED_LEN
+        || bytes[0] != VERSION
+        || bytes[1] != TRACE_ID_FIELD
+        || bytes[18] != SPAN_ID_FIELD
+        || bytes[27] != TRACE_OPTIONS_FIELD
+    {
+        return None;
+    }
+    let mut trace_id = [0; 16];
+    let mut span_id = [0; 8];
+    trace_id.copy_from_slice(&bytes[2..18]);
+    span_id.copy_from_slice(&bytes[19..27]);
+    let flags = if bytes[28] & 1 == 1 { TraceFlags::SAMPLED } else { TraceFlags::default() };
+    let span_context = SpanContext::new(
+        TraceId::from_bytes(trace_id),
+        SpanId::from_bytes(span_id),
+        flags,
+        true,
+        TraceState::default(),
+    );
+    span_context.is_valid().then_some(span_context)
+}
+
+/// The `rpc.*` attributes of `req` when it is a gRPC or gRPC-Web call to `/<service>/<method>`.
+pub(crate) fn rpc_attributes<B>(req: &Request<B>) -> Option<[KeyValue; 3]> {
+    let content_type = req.headers().get(header::CONTENT_TYPE)?.to_str().ok()?;
+    if !is_grpc_content_type(content_type) {
+        return None;
+    }
+    let (service, method) = req.uri().path().strip_prefix('/')?.split_once('/')?;
+    if service.is_empty() || method.is_empty() || method.contains('/') {
+        return None;
+    }
+    Some([
+        RPC_SYSTEM.string("grpc"),
+        RPC_SERVICE.string(service.to_string()),
+        RPC_METHOD.string(method.to_string()),
+    ])
+}
+
+/// Whether `content_type` is `application/grpc`, `application/grpc-web` or
+/// `application/grpc-web-text`, with or without a `+<format>` suffix or parameters.
+fn is_grpc_content_type(content_type: &str) -> bool {
+    let Some(rest) = content_type.trim().strip_prefix("application/grpc") else {
+        return false;
+    };
+    let rest = rest.strip_prefix("-web").map_or(rest, |web| web.strip_prefix("-text").unwrap_or(web));
+    rest.is_empty() || rest.starts_with(['+', ';'])
+}
@@ -0,0 +1,101 @@
+//! Lifecycle management for the spans built by a [`Service`].
+//!
+//! [`Service`]: crate::Service
+use std::{
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+
+use opentelemetry::{trace::TraceContextExt, Context};
+
+/// Owns a span for its whole lifetime and ends it exactly once.
+///
+/// The span is ended by [`SpanGuard::end`], or when the guard is dropped if it was never ended
+/// explicitly, so a future that is cancelled mid-request still exports its span.
+#[derive(Debug)]
+pub(crate) struct SpanGuard {
+    cx: Context,
+    ended: bool,
+    permit: SpanPermit,
+}
+
+impl SpanGuard {
+    pub(crate) fn new(cx: Context) -> Self {
+        Self { cx, ended: false, permit: SpanPermit::unlimited() }
+    }
+
+    /// Hold `permit` until the span ends.
+    pub(crate) fn with_permit(mut self, permit: SpanPermit) -> Self {
+        self.permit = permit;
+        self
+    }
+
+    /// The [`Context`] holding the guarded span.
+    pub(crate) fn context(&self) -> &Context {
+        &self.cx
+    }
+
+    /// End the span now. Any later call, including the one made on drop, does nothing.
+    pub(crate) fn end(&mut self) {
+        self.end_with_timestamp(SystemTime::now());
+    }
+
+    /// End the span at `timestamp`. Any later call, including the one made on drop, does nothing.
+    pub(crate) fn end_with_timestamp(&mut self, timestamp: SystemTime) {
+        if !std::mem::replace(&mut self.ended, true) {
+            self.cx.span().end_with_timestamp(timestamp);
+            self.permit = SpanPermit::unlimited();
+        }
+    }
+}
+
+impl Drop for SpanGuard {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
+
+/// Caps the number of spans of a [`Service`] that are active at once.
+///
+/// [`Service`]: crate::Service
+#[derive(Debug)]
+pub(crate) struct SpanLimit {
+    active: AtomicUsize,
+    max: usize,
+}
+
+impl SpanLimit {
+    pub(crate) fn new(max: usize) -> Self {
+        Self { active: AtomicUsize::new(0), max }
+    }
+
+    /// Count a new active span, or return `None` if `max` spans are already active.
+    pub(crate) fn acquire(self: &Arc<Self>) -> Option<SpanPermit> {
+        self.active
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |active| (active < self.max).then(|| active + 1))
+            .ok()?;
+        Some(SpanPermit(Some(self.clone())))
+    }
+}
+
+/// An active span counted by a [`SpanLimit`], released when dropped.
+#[derive(Debug)]
+pub(crate) struct SpanPermit(Option<Arc<SpanLimit>>);
+
+impl SpanPermit {
+    /// A permit for a span that isn't counted by any limit.
+    pub(crate) fn unlimited() -> Self {
+        Self(None)
+    }
+}
+
+impl Drop for SpanPermit {
+    fn drop(&mut self) {
+        if let Some(limit) = self.0.take() {
+            limit.active.fetch_sub(1, Ordering::AcqRel);
+        }
+    }
+}
@@ -1,69 +1,2033 @@
 #![warn(clippy::pedantic)]
-use std::{borrow::Cow, error::Error as StdError, future::Future, pin::Pin, task::Poll, sync::Arc, backtrace::Backtrace};
+use std::{
+    backtrace::Backtrace,
+    borrow::Cow,
+    collections::{hash_map::RandomState, HashMap},
+    convert::TryFrom,
+    error::Error as StdError,
+    fmt::{self, Write as _},
+    future::Future,
+    hash::{BuildHasher, Hash, Hasher},
+    net::IpAddr,
+    pin::Pin,
+    sync::{Arc, Once},
+    task::Poll,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
 
-use futures_util::future::FutureExt;
+use futures_util::future::{BoxFuture, Either, FutureExt};
 use http::{
     header::{self, HeaderName},
-    HeaderValue, Method, Request, Response, Version,
+    request, HeaderValue, Method, Request, Response, Uri, Version,
 };
 use lazy_static::lazy_static;
 use opentelemetry::{
+    baggage::{Baggage, BaggageExt},
     global,
-    propagation::{Extractor, Injector},
-    trace::{FutureExt as OtelFutureExt, SpanKind, StatusCode, TraceContextExt, Tracer, TracerProvider, TraceId, SpanId},
-    Context,
+    propagation::{Extractor, Injector, TextMapPropagator},
+    sdk::{
+        propagation::TextMapCompositePropagator,
+        resource::ResourceDetector,
+        trace::{SamplingDecision, SamplingResult, ShouldSample},
+        Resource,
+    },
+    trace::{noop::NoopTracerProvider, FutureExt as OtelFutureExt, Link, SpanBuilder, SpanContext, SpanKind, SpanRef, StatusCode, TraceContextExt, TraceFlags, TraceId, Tracer, TracerProvider},
+    Context, Key, KeyValue, Value,
 };
+use opentelemetry_semantic_conventions::resource::DEPLOYMENT_ENVIRONMENT;
 use opentelemetry_semantic_conventions::trace::{
-    HTTP_FLAVOR, HTTP_METHOD, HTTP_STATUS_CODE, HTTP_TARGET, HTTP_URL, HTTP_USER_AGENT,
-    NET_HOST_NAME,
+    EXCEPTION_MESSAGE, EXCEPTION_STACKTRACE, EXCEPTION_TYPE, HTTP_CLIENT_IP, HTTP_FLAVOR, HTTP_HOST, HTTP_METHOD, HTTP_ROUTE,
+    HTTP_SCHEME, HTTP_STATUS_CODE, HTTP_TARGET, HTTP_URL, HTTP_USER_AGENT, MESSAGING_DESTINATION, MESSAGING_SYSTEM, NET_HOST_NAME, NET_HOST_PORT, NET_PEER_IP, NET_PEER_NAME,
 };
 use sysinfo::{System, SystemExt};
 
+mod classify;
+pub mod client;
+#[cfg(feature = "serde")]
+pub mod config;
+mod connection;
+mod deferred;
+#[cfg(feature = "detectors")]
+pub mod detectors;
+mod events;
+mod forwarded;
+#[cfg(feature = "grpc")]
+mod grpc;
+mod guard;
+mod links;
+#[cfg(feature = "metrics")]
+mod metrics;
+#[cfg(feature = "otlp")]
+pub mod otlp;
+mod queue_depth;
+mod received_at;
+mod reload;
+mod routing;
+mod switch;
+mod tail;
+#[cfg(feature = "request-body")]
+pub mod request_body;
+mod request_context;
+#[cfg(feature = "response-body")]
+pub mod response_body;
+mod tracer;
+#[cfg(feature = "tracing")]
+mod tracing_span;
+mod tunnel;
+
+pub use classify::{
+    ResponseClass, ResponseClassifier, ResponseError, ResponseFailure, ServerErrorsAsFailures, SpanStatus, StatusInRangeAsFailures,
+};
+pub use connection::{ConnectionInfo, Http2Negotiation, Http2Priority, UnixPeer};
+pub use deferred::DeferredSpan;
+pub use events::SpanEvents;
+#[cfg(feature = "grpc")]
+pub use grpc::GrpcTraceBinPropagator;
+#[cfg(feature = "metrics")]
+pub use metrics::Handled;
+pub use queue_depth::QueueDepth;
+pub use received_at::ReceivedAt;
+pub use request_context::RequestContext;
+pub use routing::{MatchedRoute, RouteOverride, RoutePrefix, RouterLabel};
+pub use reload::ReloadHandle;
+pub use switch::TracingSwitch;
+use guard::{SpanGuard, SpanLimit, SpanPermit};
+use tracer::BoxedTracer;
+pub use tracer::TracerHandle;
+pub use tunnel::Tunnel;
+
 lazy_static! {
     static ref SYSTEM: System = System::new_all();
+
+    /// The per-process key for [`ClientIpRecording::Hashed`].
+    static ref CLIENT_IP_HASHER: RandomState = RandomState::new();
+}
+
+/// The environment variable read by [`Layer::with_deployment_environment_from_env`].
+pub const DEPLOYMENT_ENVIRONMENT_VAR: &str = "OTEL_DEPLOYMENT_ENVIRONMENT";
+
+/// A generous limit for [`Layer::with_max_active_spans`], far above the concurrency of most
+/// services.
+pub const DEFAULT_MAX_ACTIVE_SPANS: usize = 10_000;
+
+/// The [W3C Trace Context Level 2] response header.
+///
+/// [W3C Trace Context Level 2]: https://www.w3.org/TR/trace-context-2/#traceresponse-header
+static TRACERESPONSE: HeaderName = HeaderName::from_static("traceresponse");
+
+/// Guards the one-time warning emitted when no text map propagator is installed.
+static PROPAGATION_CHECK: Once = Once::new();
+
+/// The normalized method recorded for any method not defined by [RFC 9110] or [RFC 5789].
+///
+/// [RFC 9110]: https://www.rfc-editor.org/rfc/rfc9110.html#name-methods
+/// [RFC 5789]: https://www.rfc-editor.org/rfc/rfc5789.html
+const OTHER_METHOD: &str = "_OTHER";
+
+/// The original request method, recorded when [`HTTP_METHOD`] was normalized to `_OTHER` or to
+/// its canonical case.
+const HTTP_REQUEST_METHOD_ORIGINAL: Key = Key::from_static_str("http.request.method_original");
+
+/// The number of parameters in the request's query string.
+const HTTP_QUERY_PARAM_COUNT: Key = Key::from_static_str("http.query.param_count");
+
+/// The HTTP/2 stream identifier, from [`ConnectionInfo::http2_stream_id`].
+const HTTP_STREAM_ID: Key = Key::from_static_str("http.stream_id");
+/// How HTTP/2 was negotiated, from [`ConnectionInfo::http2_negotiation`].
+const NETWORK_PROTOCOL_NEGOTIATION: Key = Key::from_static_str("network.protocol.negotiation");
+
+/// The weight of the HTTP/2 stream, from [`Http2Priority::weight`].
+const HTTP2_PRIORITY_WEIGHT: Key = Key::from_static_str("http2.priority.weight");
+/// The stream the HTTP/2 stream depends on, from [`Http2Priority::stream_dependency`].
+const HTTP2_PRIORITY_STREAM_DEPENDENCY: Key = Key::from_static_str("http2.priority.stream_dependency");
+
+/// Whether the response was pushed, from [`ConnectionInfo::http2_pushed`].
+const HTTP2_PUSHED: Key = Key::from_static_str("http2.pushed");
+
+/// Whether the server span continued a valid inbound trace context.
+const TRACE_CONTINUED_FROM_UPSTREAM: Key = Key::from_static_str("trace.continued_from_upstream");
+
+/// The low-cardinality class of the error returned by the inner service.
+const ERROR_TYPE: Key = Key::from_static_str("error.type");
+/// The [`ERROR_TYPE`] of timeouts.
+const ERROR_TYPE_TIMEOUT: &str = "timeout";
+/// The [`ERROR_TYPE`] of cancelled requests.
+const ERROR_TYPE_CANCELLED: &str = "cancelled";
+
+/// Marks requests whose `Host` header isn't a valid authority.
+const HTTP_HOST_INVALID: Key = Key::from_static_str("http.host.invalid");
+
+/// The TLS SNI server name, from [`ConnectionInfo::tls_server_name`].
+const TLS_SERVER_NAME: Key = Key::from_static_str("tls.server_name");
+
+/// The user ID of a Unix domain socket peer, from [`UnixPeer::uid`].
+const PEER_UID: Key = Key::from_static_str("peer.uid");
+
+/// The process ID of a Unix domain socket peer, from [`UnixPeer::pid`].
+const PEER_PID: Key = Key::from_static_str("peer.pid");
+
+/// The response `Content-Type`.
+const HTTP_RESPONSE_CONTENT_TYPE: Key = Key::from_static_str("http.response.content_type");
+
+/// The event added once the attributes from [`Layer::with_async_attributes`] have resolved.
+const ASYNC_ATTRIBUTES_EVENT: &str = "tower_opentelemetry.async_attributes";
+
+/// Whether the response was served from a cache, per [`Layer::with_cache_hit_header`].
+const CACHE_HIT: Key = Key::from_static_str("cache.hit");
+
+/// The coarse user agent classification, see [`UserAgentRecording::Classified`].
+const HTTP_USER_AGENT_CLASS: Key = Key::from_static_str("http.user_agent.class");
+
+/// The wall-clock time the request was received, see [`Layer::with_request_start_time`].
+const HTTP_REQUEST_START_TIME: Key = Key::from_static_str("http.request.start_time");
+
+/// The canonical reason phrase of the response status.
+const HTTP_STATUS_TEXT: Key = Key::from_static_str("http.status_text");
+/// The class of the response status, such as `4xx`.
+const HTTP_STATUS_CLASS: Key = Key::from_static_str("http.status_class");
+/// Whether a [`MatchedRoute`] was known for the request.
+const HTTP_ROUTE_MATCHED: Key = Key::from_static_str("http.route.matched");
+
+/// The request's `Referer`, see [`Layer::with_referer`].
+const HTTP_REQUEST_HEADER_REFERER: Key = Key::from_static_str("http.request.header.referer");
+
+/// The request's `Accept-Encoding`, see [`Layer::with_accept_encoding`].
+const HTTP_REQUEST_HEADER_ACCEPT_ENCODING: Key = Key::from_static_str("http.request.header.accept_encoding");
+/// The request's `Accept`, see [`Layer::with_accept`].
+const HTTP_REQUEST_HEADER_ACCEPT: Key = Key::from_static_str("http.request.header.accept");
+
+/// Whether the request carries an idempotency key, see [`Layer::with_idempotency_key_header`].
+const HTTP_REQUEST_HAS_IDEMPOTENCY_KEY: Key = Key::from_static_str("http.request.has_idempotency_key");
+
+/// The response's `Content-Encoding`, see [`Layer::with_content_encoding`].
+const HTTP_RESPONSE_HEADER_CONTENT_ENCODING: Key = Key::from_static_str("http.response.header.content_encoding");
+
+/// The response's `Content-Language`, see [`Layer::with_content_language`].
+const HTTP_RESPONSE_HEADER_CONTENT_LANGUAGE: Key = Key::from_static_str("http.response.header.content_language");
+
+/// The response's `Age` in seconds, see [`Layer::with_response_age`].
+const HTTP_RESPONSE_HEADER_AGE: Key = Key::from_static_str("http.response.header.age");
+
+/// The number of errors a response reported, from [`ResponseError::with_count`].
+const ERROR_COUNT: Key = Key::from_static_str("error.count");
+
+/// Whether the response is sent with chunked transfer encoding, see
+/// [`Layer::with_chunked_response`].
+const HTTP_RESPONSE_CHUNKED: Key = Key::from_static_str("http.response.chunked");
+
+/// The router that handled the request, from a [`RouterLabel`] extension.
+const HTTP_ROUTER: Key = Key::from_static_str("http.router");
+
+/// The request's correlation id, see [`Layer::with_request_id`].
+const HTTP_REQUEST_ID: Key = Key::from_static_str("http.request.id");
+
+/// The response latency in milliseconds, see [`Layer::with_duration_attribute`].
+const HTTP_SERVER_DURATION_MS: Key = Key::from_static_str("http.server.duration_ms");
+
+/// The response latency in nanoseconds, see [`Layer::with_duration_attribute`].
+const HTTP_SERVER_DURATION_NS: Key = Key::from_static_str("http.server.duration_ns");
+
+/// Whether the request is a retry, see [`Layer::with_retry_header`].
+const HTTP_REQUEST_IS_RETRY: Key = Key::from_static_str("http.request.is_retry");
+
+/// The service wrapped by the layer, see [`Layer::with_inner_service_type`].
+const TOWER_SERVICE: Key = Key::from_static_str("tower.service");
+
+/// The depth of an outer buffer's queue when the request entered it, see [`QueueDepth`].
+const HTTP_SERVER_QUEUE_DEPTH: Key = Key::from_static_str("http.server.queue_depth");
+/// The time the caller waited for the service to become ready, see [`Layer::with_ready_wait`].
+const HTTP_SERVER_READY_WAIT_MS: Key = Key::from_static_str("http.server.ready_wait_ms");
+
+/// The request's duration on the monotonic clock, see [`Layer::with_clock_skew_attributes`].
+const TIMING_MONOTONIC_DURATION_MS: Key = Key::from_static_str("timing.monotonic.duration_ms");
+/// The wall-clock time the request started, in nanoseconds since the Unix epoch.
+const TIMING_WALL_START: Key = Key::from_static_str("timing.wall.start_unix_nano");
+/// The wall-clock time the request ended, in nanoseconds since the Unix epoch.
+const TIMING_WALL_END: Key = Key::from_static_str("timing.wall.end_unix_nano");
+
+/// Marks `CONNECT` requests, which establish a tunnel rather than a request/response exchange.
+const HTTP_TUNNEL: Key = Key::from_static_str("http.tunnel");
+
+/// The canonical name of `method`, matching the standard methods case-insensitively so `get`
+/// and `GET` are both `GET`, or `_OTHER` for any other method.
+#[inline]
+fn http_method_str(method: &Method) -> Cow<'static, str> {
+    const KNOWN: [&str; 9] = ["OPTIONS", "GET", "POST", "PUT", "DELETE", "HEAD", "TRACE", "CONNECT", "PATCH"];
+    match *method {
+        Method::OPTIONS => "OPTIONS".into(),
+        Method::GET => "GET".into(),
+        Method::POST => "POST".into(),
+        Method::PUT => "PUT".into(),
+        Method::DELETE => "DELETE".into(),
+        Method::HEAD => "HEAD".into(),
+        Method::TRACE => "TRACE".into(),
+        Method::CONNECT => "CONNECT".into(),
+        Method::PATCH => "PATCH".into(),
+        _ => KNOWN
+            .iter()
+            .find(|known| known.eq_ignore_ascii_case(method.as_str()))
+            .map_or(OTHER_METHOD, |known| *known)
+            .into(),
+    }
+}
+
+/// The value of the `name` header, if present and valid UTF-8.
+#[inline]
+fn header_str<'a>(headers: &'a http::HeaderMap, name: &HeaderName) -> Option<&'a str> {
+    headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+/// Classify a user agent as `bot`, `browser` or `unknown`.
+fn user_agent_class(user_agent: &str) -> &'static str {
+    const BOT_MARKERS: [&str; 6] = ["bot", "crawl", "spider", "slurp", "curl", "wget"];
+    let lower = user_agent.to_ascii_lowercase();
+    if BOT_MARKERS.iter().any(|marker| lower.contains(marker)) {
+        "bot"
+    } else if lower.starts_with("mozilla/") || lower.starts_with("opera/") {
+        "browser"
+    } else {
+        "unknown"
+    }
+}
+
+/// Marks a span name cut short by [`Layer::with_max_span_name_len`].
+const SPAN_NAME_ELLIPSIS: &str = "...";
+
+/// Shorten `name` to at most `max` bytes, ending in [`SPAN_NAME_ELLIPSIS`], without splitting a
+/// character.
+///
+/// When `max` leaves no room for anything before the ellipsis, the name is cut without one.
+fn truncate_span_name(mut name: Cow<'static, str>, max: usize) -> Cow<'static, str> {
+    if name.len() <= max {
+        return name;
+    }
+    let ellipsis = if max > SPAN_NAME_ELLIPSIS.len() { SPAN_NAME_ELLIPSIS } else { "" };
+    let mut end = max - ellipsis.len();
+    while !name.is_char_boundary(end) {
+        end -= 1;
+    }
+    let name_mut = name.to_mut();
+    name_mut.truncate(end);
+    name_mut.push_str(ellipsis);
+    name
+}
+
+/// Run `f` on the parts of `req`, for hooks that aren't generic over the body type.
+#[inline]
+fn with_parts<B, R>(req: Request<B>, f: impl FnOnce(&request::Parts) -> R) -> (Request<B>, R) {
+    let (parts, body) = req.into_parts();
+    let result = f(&parts);
+    (Request::from_parts(parts, body), result)
+}
+
+/// The `error.type` of errors this crate recognizes without a mapping: timed out I/O and
+/// aborted futures.
+fn builtin_error_type(error: &(dyn StdError + 'static)) -> Option<&'static str> {
+    if let Some(error) = error.downcast_ref::<std::io::Error>() {
+        return match error.kind() {
+            std::io::ErrorKind::TimedOut => Some(ERROR_TYPE_TIMEOUT),
+            _ => None,
+        };
+    }
+    error.is::<futures_util::future::Aborted>().then_some(ERROR_TYPE_CANCELLED)
+}
+
+/// The value of the first query parameter of `uri` called `name`.
+fn query_param<'a>(uri: &'a Uri, name: &str) -> Option<&'a str> {
+    uri.query()?.split('&').find_map(|param| {
+        let (key, value) = param.split_once('=')?;
+        (key == name && !value.is_empty()).then_some(value)
+    })
+}
+
+/// Whether a response with `status` and `headers` to a request of `version` is sent with chunked
+/// transfer encoding, which is never the case for responses without a body: informational,
+/// `204 No Content` and `304 Not Modified` responses, and responses to `HEAD` requests.
+fn is_chunked(status: http::StatusCode, headers: &http::HeaderMap, version: Version, is_head: bool) -> bool {
+    let has_body = !(is_head
+        || status.is_informational()
+        || status == http::StatusCode::NO_CONTENT
+        || status == http::StatusCode::NOT_MODIFIED);
+    if !has_body {
+        return false;
+    }
+    let Some(transfer_encoding) = headers.get_all(header::TRANSFER_ENCODING).iter().next_back() else {
+        return version == Version::HTTP_11 && !headers.contains_key(header::CONTENT_LENGTH);
+    };
+    let last_coding = transfer_encoding.to_str().ok().and_then(|value| value.rsplit(',').next());
+    last_coding.is_some_and(|coding| coding.trim().eq_ignore_ascii_case("chunked"))
+}
+
+/// The attributes held back by [`Layer::with_error_only_attributes`], kept in the span's
+/// [`Context`] until the request ends.
+struct ErrorOnlyAttributes(Vec<KeyValue>);
+
+/// Record the [`ErrorOnlyAttributes`] of `cx` on its span, which ended as an error.
+fn record_error_only(cx: &Context) {
+    if let Some(ErrorOnlyAttributes(attributes)) = cx.get::<ErrorOnlyAttributes>() {
+        let span = cx.span();
+        for attribute in attributes {
+            span.set_attribute(attribute.clone());
+        }
+    }
+}
+
+/// A copy of `cx` with its baggage and forwarded headers but without its span, so spans built
+/// in it are roots.
+fn without_span(cx: &Context) -> Context {
+    let baggage: Baggage = cx
+        .baggage()
+        .iter()
+        .map(|(key, (value, metadata))| (key.clone(), (value.clone(), metadata.clone())))
+        .collect();
+    let root = Context::new().with_value(baggage);
+    match cx.get::<client::ForwardedHeaders>() {
+        Some(forwarded) => root.with_value(forwarded.clone()),
+        None => root,
+    }
+}
+
+#[inline]
+fn http_flavor(version: Version) -> Cow<'static, str> {
+    match version {
+        Version::HTTP_09 => "0.9".into(),
+        Version::HTTP_10 => "1.0".into(),
+        Version::HTTP_11 => "1.1".into(),
+        Version::HTTP_2 => "2.0".into(),
+        Version::HTTP_3 => "3.0".into(),
+        other => format!("{other:?}").into(),
+    }
+}
+
+/// Where the request's query string is recorded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum QueryRecording {
+    /// The query is included in [`HTTP_TARGET`] but not in the span name.
+    #[default]
+    Target,
+    /// The query is included in both [`HTTP_TARGET`] and the span name.
+    TargetAndName,
+    /// The query is included in neither [`HTTP_TARGET`] nor the span name.
+    Omit,
+}
+
+/// How much of the request URL is recorded in [`HTTP_URL`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum UrlRecording {
+    /// The request URI as received, including the query.
+    #[default]
+    Full,
+    /// `scheme://host/path` with the query stripped, from the same scheme and host as
+    /// `http.scheme` and `net.host.port`, or just the path when either is unknown.
+    NoQuery,
+    /// Only the path.
+    PathOnly,
+}
+
+/// How the client and peer addresses are recorded in [`HTTP_CLIENT_IP`] and [`NET_PEER_IP`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum ClientIpRecording {
+    /// Record the addresses as they are.
+    #[default]
+    Raw,
+    /// Record a hex hash of each address, keyed per process. Hashes of the same address match
+    /// within a process, but can't be reversed by hashing candidate addresses.
+    Hashed,
+    /// Don't record the addresses.
+    Omit,
+}
+
+impl ClientIpRecording {
+    fn value(self, ip: IpAddr) -> Option<String> {
+        match self {
+            Self::Raw => Some(ip.to_string()),
+            Self::Hashed => {
+                let mut hasher = CLIENT_IP_HASHER.build_hasher();
+                ip.hash(&mut hasher);
+                Some(format!("{:016x}", hasher.finish()))
+            }
+            Self::Omit => None,
+        }
+    }
+}
+
+type SpanBuilderHook = dyn Fn(&request::Parts, &mut SpanBuilder) + Send + Sync;
+type Propagator = dyn TextMapPropagator + Send + Sync;
+type RequestAttributes = dyn Fn(&request::Parts) -> Vec<KeyValue> + Send + Sync;
+type SpanNamer = dyn Fn(&request::Parts) -> Cow<'static, str> + Send + Sync;
+type RequestIdGenerator = dyn Fn() -> String + Send + Sync;
+type HeaderRule = dyn Fn(&str) -> bool + Send + Sync;
+type ErrorTypeMapping = dyn Fn(&(dyn StdError + 'static)) -> Option<&'static str> + Send + Sync;
+type OnRequest = dyn Fn(&request::Parts) + Send + Sync;
+type OnResponse = dyn Fn(Option<&http::response::Parts>, Duration) + Send + Sync;
+type AsyncAttributes = dyn Fn(&request::Parts) -> BoxFuture<'static, Vec<KeyValue>> + Send + Sync;
+type RequestSampler = dyn Fn(&request::Parts) -> SamplingDecision + Send + Sync;
+
+/// How the request's `User-Agent` is recorded.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum UserAgentRecording {
+    /// Record the raw header as [`HTTP_USER_AGENT`].
+    #[default]
+    Full,
+    /// Record only a coarse `bot`, `browser` or `unknown` classification as
+    /// `http.user_agent.class`, which keeps cardinality low and avoids storing the raw string.
+    Classified,
+    /// Don't record the user agent.
+    Omit,
+}
+
+/// How the request's `Referer` is recorded.
+///
+/// Referers often carry query strings from the referring page, which may include tokens, so
+/// [`RefererRecording::WithoutQuery`] is usually the safer choice.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum RefererRecording {
+    /// Don't record the referer.
+    #[default]
+    Omit,
+    /// Record the referer with its query string and fragment removed.
+    WithoutQuery,
+    /// Record the raw header.
+    Full,
 }
 
-#[inline]
-fn http_method_str(method: &Method) -> Cow<'static, str> {
-    match method {
-        &Method::OPTIONS => "OPTIONS".into(),
-        &Method::GET => "GET".into(),
-        &Method::POST => "POST".into(),
-        &Method::PUT => "PUT".into(),
-        &Method::DELETE => "DELETE".into(),
-        &Method::HEAD => "HEAD".into(),
-        &Method::TRACE => "TRACE".into(),
-        &Method::CONNECT => "CONNECT".into(),
-        &Method::PATCH => "PATCH".into(),
-        other => other.to_string().into(),
+/// How [`Layer::with_request_start_time`] formats `http.request.start_time`.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum StartTimeFormat {
+    /// An RFC 3339 timestamp in UTC with nanosecond precision, e.g.
+    /// `2022-03-01T12:00:00.000000000Z`.
+    #[default]
+    Rfc3339,
+    /// Nanoseconds since the Unix epoch as an integer.
+    EpochNanos,
+}
+
+/// What happens to propagation header values that aren't valid in HTTP headers, such as values
+/// with control characters or non-ASCII text.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum InvalidValuePolicy {
+    /// Skip the header, reporting it to the global error handler.
+    #[default]
+    Drop,
+    /// Percent-encode every byte other than printable ASCII and tab, and `%` itself, so the
+    /// receiver can decode the original value. Valid values are encoded too, which only changes
+    /// those with a `%` in them.
+    PercentEncode,
+    /// Send the value base64-encoded with padding.
+    Base64,
+}
+
+/// The size of a body, from its exact `hint` when known and otherwise from the `Content-Length`
+/// in `headers`.
+#[cfg(any(feature = "request-body", feature = "response-body"))]
+fn body_size(hint: &http_body::SizeHint, headers: &http::HeaderMap) -> Option<i64> {
+    hint.exact()
+        .or_else(|| header_str(headers, &http::header::CONTENT_LENGTH)?.trim().parse().ok())
+        .map(|size| i64::try_from(size).unwrap_or(i64::MAX))
+}
+
+/// Whether `byte` can be sent in a header value that every receiver can read as text.
+fn is_header_safe(byte: u8) -> bool {
+    byte == b'\t' || (b' '..=b'~').contains(&byte)
+}
+
+impl InvalidValuePolicy {
+    /// Encode `value` according to this policy, or `None` to drop it.
+    fn encode(self, value: &str) -> Option<HeaderValue> {
+        let encoded = match self {
+            Self::Drop => return None,
+            Self::PercentEncode => {
+                let mut encoded = String::with_capacity(value.len());
+                for byte in value.bytes() {
+                    if byte == b'%' || !is_header_safe(byte) {
+                        let _ = write!(encoded, "%{byte:02X}");
+                    } else {
+                        encoded.push(char::from(byte));
+                    }
+                }
+                encoded
+            }
+            Self::Base64 => base64::encode(value),
+        };
+        HeaderValue::try_from(encoded).ok()
+    }
+}
+
+/// The unit of the duration attribute enabled by [`Layer::with_duration_attribute`].
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum DurationUnit {
+    /// Record `http.server.duration_ms` as fractional milliseconds.
+    Millis,
+    /// Record `http.server.duration_ns` as integer nanoseconds.
+    Nanos,
+}
+
+/// How the inbound trace context extracted from the request relates to the server span.
+///
+/// Samplers see the parent and links chosen here, so the variants differ in sampling too.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum TraceContinuation {
+    /// Continue the inbound trace with the server span as a child of the remote span. Parent-based
+    /// samplers follow the upstream's sampling decision.
+    #[default]
+    Child,
+    /// Start a new trace and link the server span to the remote span, for services at a trust
+    /// boundary that shouldn't join their callers' traces. The sampling decision is made afresh as
+    /// for a root span, so a parent-based sampler uses its root sampler and ignores the upstream's
+    /// decision, which is only visible on the link.
+    NewRootLinked,
+    /// Start a new trace unrelated to the inbound context, which is ignored apart from its
+    /// baggage. The sampling decision is made afresh as for a root span.
+    AlwaysNewRoot,
+}
+
+/// When the span context is injected into the response headers.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Deserialize), serde(rename_all = "snake_case"))]
+pub enum ResponseInjection {
+    /// Never inject the span context into the response.
+    Disabled,
+    /// Inject the span context only when the span is sampled, so unsampled requests don't return
+    /// ids that lead nowhere.
+    #[default]
+    Sampled,
+    /// Always inject the span context, whether or not the span is sampled.
+    Always,
+}
+
+/// [`Layer`] that adds high level [opentelemetry propagation] to a [`Service`].
+///
+/// [`Layer`]: tower_layer::Layer
+/// [opentelemetry propagation]: https://opentelemetry.io/docs/java/manual_instrumentation/#context-propagation
+/// [`Service`]: tower_service::Service
+#[derive(Debug, Clone, Default)]
+pub struct Layer {
+    config: Config,
+}
+
+impl Layer {
+    /// Create a new [`Layer`] using the default [`ServerErrorsAsFailures`] classifier.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Use the given [`ResponseClassifier`] to decide which responses mark the span as an error.
+    ///
+    /// Responses carrying a [`ResponseFailure`] are failures regardless of the classifier.
+    #[must_use]
+    pub fn with_classifier<C>(mut self, classifier: C) -> Self
+    where
+        C: ResponseClassifier + 'static,
+    {
+        self.config.classifier = Arc::new(classifier);
+        self
+    }
+
+    /// Run `detectors` once, now, and record the attributes they detect on every span, such as
+    /// the container id or the Kubernetes pod.
+    ///
+    /// Each detector gets `timeout` to finish. The results are cached, so detection costs nothing
+    /// per request. Detectors for common environments are in the `detectors` module, behind the
+    /// `detectors` feature.
+    #[must_use]
+    pub fn with_detectors(mut self, timeout: Duration, detectors: Vec<Box<dyn ResourceDetector>>) -> Self {
+        for (key, value) in &Resource::from_detectors(timeout, detectors) {
+            self.config.set_static_attribute(KeyValue::new(key.clone(), value.clone()));
+        }
+        self
+    }
+
+    /// Extract and inject trace context with `propagator` instead of the global text map
+    /// propagator.
+    ///
+    /// This lets layers in the same process propagate differently, and tests and libraries avoid
+    /// touching process-wide state. By default the propagator installed with
+    /// [`global::set_text_map_propagator`] is used.
+    #[must_use]
+    pub fn with_propagator<P>(mut self, propagator: P) -> Self
+    where
+        P: TextMapPropagator + Send + Sync + 'static,
+    {
+        self.config.propagator = Some(Arc::new(propagator));
+        self
+    }
+
+    /// Whether to report, once per process, that no global text map propagator is installed.
+    ///
+    /// Without a propagator inbound trace context is never extracted, so every request starts a
+    /// new trace. The warning is reported through [`global::handle_error`] and is enabled by
+    /// default.
+    #[must_use]
+    pub fn with_propagation_warning(mut self, enabled: bool) -> Self {
+        self.config.propagation_warning = enabled;
+        self
+    }
+
+    /// Extract the inbound trace context from the query parameter `name` when the headers carry
+    /// none, for callers such as webhooks and redirects that can't set headers.
+    ///
+    /// The parameter holds a `traceparent` value, such as
+    /// `?traceparent=00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01`, and is passed to the
+    /// propagator as the `traceparent` field, exactly as sent. Unlike headers, query parameters
+    /// survive in links that are shared, bookmarked or logged, so anyone holding such a URL can
+    /// attach requests to the trace or force its sampling decision; only enable this for
+    /// endpoints whose callers are trusted to choose the trace they join. Disabled by default.
+    #[must_use]
+    pub fn with_query_trace_context<T>(mut self, name: T) -> Self
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        self.config.query_trace_context = Some(name.into());
+        self
+    }
+
+    /// Carry the inbound request headers called any of `names`, such as a tenant or correlation
+    /// id, in the request's context so the [`client::Layer`] can forward them downstream.
+    ///
+    /// ```
+    /// use http::HeaderName;
+    ///
+    /// let layer = tower_opentelemetry::Layer::new()
+    ///     .with_forwarded_headers([HeaderName::from_static("x-tenant-id")]);
+    /// let client = tower_opentelemetry::client::Layer::new().with_forwarded_headers(true);
+    /// ```
+    ///
+    /// Only client layers with [`client::Layer::with_forwarded_headers`] enabled copy the headers,
+    /// so they don't leak to third parties called through other clients. Every value of a repeated
+    /// header is forwarded, and so are the headers of requests no span is built for, such as
+    /// excluded or dropped requests. By default no headers are forwarded.
+    #[must_use]
+    pub fn with_forwarded_headers<I>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = HeaderName>,
+    {
+        self.config.forwarded_headers = names.into_iter().collect();
+        self
+    }
+
+    /// Record `deployment.environment` on every span from the [`DEPLOYMENT_ENVIRONMENT_VAR`]
+    /// environment variable, if it is set.
+    #[must_use]
+    pub fn with_deployment_environment_from_env(self) -> Self {
+        self.with_deployment_environment_var(DEPLOYMENT_ENVIRONMENT_VAR)
+    }
+
+    /// Record `deployment.environment` on every span from the environment variable `var`, if it is
+    /// set.
+    ///
+    /// The variable is read once, when this method is called, rather than on every request.
+    #[must_use]
+    pub fn with_deployment_environment_var(mut self, var: &str) -> Self {
+        if let Ok(environment) = std::env::var(var) {
+            self.config.set_static_attribute(DEPLOYMENT_ENVIRONMENT.string(environment));
+        }
+        self
+    }
+
+    /// Choose how the inbound trace context relates to the server span, defaulting to
+    /// [`TraceContinuation::Child`].
+    ///
+    /// Inbound baggage is kept in every case. The legacy trace id header of
+    /// [`Layer::with_legacy_trace_id_header`] only applies to [`TraceContinuation::Child`].
+    #[must_use]
+    pub fn with_trace_continuation(mut self, continuation: TraceContinuation) -> Self {
+        self.config.trace_continuation = continuation;
+        self
+    }
+
+    /// Whether to record `trace.continued_from_upstream`, which is `true` when the span continues
+    /// a valid inbound trace context and `false` when it starts a new root.
+    ///
+    /// Spans recording `false` behind a caller that should propagate show where propagation
+    /// chains break. Spans started as new roots by [`TraceContinuation::NewRootLinked`] or
+    /// [`TraceContinuation::AlwaysNewRoot`] record `false`. Disabled by default.
+    #[must_use]
+    pub fn with_upstream_continuation(mut self, enabled: bool) -> Self {
+        self.config.upstream_continuation = enabled;
+        self
+    }
+
+    /// Whether to record the type name of the service this layer wraps as `tower.service`, such
+    /// as `my_app::api::Router`, to tell nested services apart.
+    ///
+    /// The name is captured once when the layer is applied. Type names may reveal internal
+    /// structure, so this is disabled by default.
+    #[must_use]
+    pub fn with_inner_service_type(mut self, enabled: bool) -> Self {
+        self.config.inner_service_type = enabled;
+        self
+    }
+
+    /// Record `label` as `tower.service` on every span, in place of the type name of
+    /// [`Layer::with_inner_service_type`].
+    #[must_use]
+    pub fn with_inner_service_label<T>(mut self, label: T) -> Self
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        self.config.set_static_attribute(TOWER_SERVICE.string(label));
+        self
+    }
+
+    /// Build spans of `kind` instead of [`SpanKind::Server`].
+    ///
+    /// [`SpanKind::Consumer`] suits entry points that bridge a message queue to HTTP handlers,
+    /// typically together with [`Layer::with_messaging`]. The HTTP attributes are recorded
+    /// regardless of the kind.
+    #[must_use]
+    pub fn with_span_kind(mut self, kind: SpanKind) -> Self {
+        self.config.span_kind = kind;
+        self
+    }
+
+    /// Record `messaging.system` and `messaging.destination` on every span, for entry points
+    /// that deliver messages rather than serve HTTP clients.
+    #[must_use]
+    pub fn with_messaging<T, U>(mut self, system: T, destination: U) -> Self
+    where
+        T: Into<Cow<'static, str>>,
+        U: Into<Cow<'static, str>>,
+    {
+        self.config.set_static_attribute(MESSAGING_SYSTEM.string(system));
+        self.config.set_static_attribute(MESSAGING_DESTINATION.string(destination));
+        self
+    }
+
+    /// Build spans with a tracer from `provider` instead of the global tracer provider.
+    ///
+    /// SDK tracers only hold a weak reference to their provider, so `provider` must be kept alive
+    /// for as long as spans should be recorded. The tracer is created immediately, so call
+    /// [`Layer::with_schema_url`] first if a schema URL is wanted.
+    #[must_use]
+    pub fn with_tracer_provider<P>(mut self, provider: &P) -> Self
+    where
+        P: TracerProvider,
+        P::Tracer: Send + Sync + 'static,
+        <P::Tracer as Tracer>::Span: Send + Sync + 'static,
+    {
+        self.config.tracer = Some(BoxedTracer::from_provider(provider, self.config.schema_url));
+        self
+    }
+
+    /// Report `schema_url` as the schema of the tracer's instrumentation scope, so backends can
+    /// validate attribute names against the matching semantic conventions.
+    ///
+    /// Applies to the global tracer and to providers set by later calls to
+    /// [`Layer::with_tracer_provider`].
+    #[must_use]
+    pub fn with_schema_url(mut self, schema_url: &'static str) -> Self {
+        self.config.schema_url = Some(schema_url);
+        self
+    }
+
+    /// Choose when the span context is injected into the response headers, defaulting to
+    /// [`ResponseInjection::Sampled`].
+    ///
+    /// This covers every response returned by the inner service, including `4xx` and `5xx`
+    /// responses, so clients can report the trace id of a failed request along with the trace
+    /// echo headers. Only errors returned instead of a response carry no headers.
+    #[must_use]
+    pub fn with_response_injection(mut self, injection: ResponseInjection) -> Self {
+        self.config.response_injection = injection;
+        self
+    }
+
+    /// Choose what happens to injected response header values that aren't valid in HTTP
+    /// headers, defaulting to [`InvalidValuePolicy::Drop`].
+    ///
+    /// Header names that aren't valid are always skipped.
+    #[must_use]
+    pub fn with_invalid_value_policy(mut self, policy: InvalidValuePolicy) -> Self {
+        self.config.invalid_value_policy = policy;
+        self
+    }
+
+    /// Whether to return the server's span context in the W3C [`traceresponse`] header.
+    ///
+    /// The header is only added when the span context is injected into the response, see
+    /// [`Layer::with_response_injection`]. Disabled by default.
+    ///
+    /// [`traceresponse`]: https://www.w3.org/TR/trace-context-2/#traceresponse-header
+    #[must_use]
+    pub fn with_traceresponse(mut self, enabled: bool) -> Self {
+        self.config.traceresponse = enabled;
+        self
+    }
+
+    /// Echo the trace id, as 32 lowercase hex digits, in the response header `name`.
+    ///
+    /// The header is only added when the span context is injected into the response, see
+    /// [`Layer::with_response_injection`].
+    #[must_use]
+    pub fn with_trace_id_header(mut self, name: HeaderName) -> Self {
+        self.config.trace_id_header = Some(name);
+        self
+    }
+
+    /// Continue traces from the legacy header `name` when no propagator finds a parent.
+    ///
+    /// The header value must be a trace id written as 32 hex digits, optionally hyphenated like a
+    /// UUID; any other value is ignored. Because the header carries no parent span id, the span
+    /// is started as a new root within that trace. Every request sending the same value joins the
+    /// same trace, so the upstream must generate a fresh id per operation or unrelated requests
+    /// will be merged. Disabled by default.
+    #[must_use]
+    pub fn with_legacy_trace_id_header(mut self, name: HeaderName) -> Self {
+        self.config.legacy_trace_id_header = Some(name);
+        self
+    }
+
+    /// Customize the [`SpanBuilder`] of every request just before the span is built.
+    ///
+    /// The hook runs after the default name, kind and attributes are set, so it can adjust
+    /// anything the other options don't cover, such as the start time, links or sampling result.
+    /// It sees the request's [`request::Parts`] since the layer isn't tied to a body type.
+    #[must_use]
+    pub fn with_span_builder_hook<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&request::Parts, &mut SpanBuilder) + Send + Sync + 'static,
+    {
+        self.config.span_builder_hook = Some(Arc::new(hook));
+        self
+    }
+
+    /// Whether to record the HTTP/2 stream identifier as `http.stream_id`.
+    ///
+    /// The identifier is read from a [`ConnectionInfo`] request extension and omitted for other
+    /// protocol versions or when the server doesn't provide it. Disabled by default.
+    #[must_use]
+    pub fn with_stream_id(mut self, enabled: bool) -> Self {
+        self.config.stream_id = enabled;
+        self
+    }
+
+    /// Whether to record how HTTP/2 was negotiated, by ALPN, prior knowledge or upgrade, as
+    /// `network.protocol.negotiation`.
+    ///
+    /// The negotiation is read from [`ConnectionInfo::http2_negotiation`] and omitted for other
+    /// protocol versions or when the server doesn't provide it. Disabled by default.
+    #[must_use]
+    pub fn with_http2_negotiation(mut self, enabled: bool) -> Self {
+        self.config.http2_negotiation = enabled;
+        self
+    }
+
+    /// Whether to record the priority of the HTTP/2 stream as `http2.priority.weight` and
+    /// `http2.priority.stream_dependency`, to debug head-of-line blocking between streams.
+    ///
+    /// The priority is read from [`ConnectionInfo::http2_priority`] and omitted for other
+    /// protocol versions or when the server doesn't provide it. Disabled by default.
+    #[must_use]
+    pub fn with_http2_priority(mut self, enabled: bool) -> Self {
+        self.config.http2_priority = enabled;
+        self
+    }
+
+    /// Whether to record if the response is an HTTP/2 server push as `http2.pushed`.
+    ///
+    /// The indicator is read from [`ConnectionInfo::http2_pushed`] and omitted for other protocol
+    /// versions or when the server doesn't provide it. Disabled by default.
+    #[must_use]
+    pub fn with_http2_pushed(mut self, enabled: bool) -> Self {
+        self.config.http2_pushed = enabled;
+        self
+    }
+
+    /// Whether to record the server name the client requested through TLS SNI as
+    /// `tls.server_name`, to debug certificate and virtual host routing.
+    ///
+    /// The name is read from [`ConnectionInfo::tls_server_name`] and omitted for connections
+    /// without TLS or when the server doesn't provide it. Disabled by default.
+    #[must_use]
+    pub fn with_tls_server_name(mut self, enabled: bool) -> Self {
+        self.config.tls_server_name = enabled;
+        self
+    }
+
+    /// Whether to record the identity of Unix domain socket peers as `net.peer.name`, `peer.uid`
+    /// and `peer.pid`.
+    ///
+    /// The identity is read from [`ConnectionInfo::unix_peer`] and omitted for connections with a
+    /// [`ConnectionInfo::peer_addr`]. Disabled by default.
+    #[must_use]
+    pub fn with_unix_peer(mut self, enabled: bool) -> Self {
+        self.config.unix_peer = enabled;
+        self
+    }
+
+    /// Whether to trust `X-Forwarded-Proto` and `X-Forwarded-For` for `http.scheme` and
+    /// `http.client_ip`.
+    ///
+    /// Only enable this behind a proxy that sets or strips these headers, since otherwise any
+    /// client can spoof them. When untrusted, which is the default, the scheme comes from the
+    /// request URI and the client address from [`ConnectionInfo::peer_addr`]. The connection's
+    /// [`ConnectionInfo::tls`] flag is preferred over `X-Forwarded-Proto` either way.
+    #[must_use]
+    pub fn with_trusted_forwarded_headers(mut self, trusted: bool) -> Self {
+        self.config.trust_forwarded = trusted;
+        self
+    }
+
+    /// Whether to record the response `Content-Type` as `http.response.content_type`.
+    ///
+    /// Omitted when the response has no `Content-Type`. Disabled by default.
+    #[must_use]
+    pub fn with_response_content_type(mut self, enabled: bool) -> Self {
+        self.config.response_content_type = enabled;
+        self
+    }
+
+    /// Compute every request attribute with `attributes` instead of the built-in attributes.
+    ///
+    /// None of the layer's own request attributes are computed when this is set, including
+    /// those added to every span and those of options such as [`Layer::with_user_agent`], so
+    /// following the semantic conventions is up to `attributes`. The start time, request id,
+    /// span builder hook and response attributes such as `http.status_code` are unaffected.
+    #[must_use]
+    pub fn with_request_attributes<F>(mut self, attributes: F) -> Self
+    where
+        F: Fn(&request::Parts) -> Vec<KeyValue> + Send + Sync + 'static,
+    {
+        self.config.request_attributes = Some(Arc::new(attributes));
+        self
+    }
+
+    /// Add the attributes `extract` computes from each request, after those of any extractors
+    /// added before it.
+    ///
+    /// ```
+    /// use opentelemetry::KeyValue;
+    ///
+    /// let layer = tower_opentelemetry::Layer::new()
+    ///     .with_attribute_extractor(|parts| match parts.headers.get("x-tenant") {
+    ///         Some(tenant) => vec![KeyValue::new("tenant", tenant.to_str().unwrap_or_default().to_string())],
+    ///         None => Vec::new(),
+    ///     })
+    ///     .with_attribute_extractor(|parts| vec![KeyValue::new("authenticated", parts.headers.contains_key("authorization"))]);
+    /// ```
+    ///
+    /// Unlike the single [`Layer::with_span_builder_hook`], extractors accumulate, so separate
+    /// parts of an application can each add their own. Their attributes follow the layer's and
+    /// come before those of the span builder hook, which can still see and change them.
+    #[must_use]
+    pub fn with_attribute_extractor<F>(mut self, extract: F) -> Self
+    where
+        F: Fn(&request::Parts) -> Vec<KeyValue> + Send + Sync + 'static,
+    {
+        self.config.attribute_extractors.push(Arc::new(extract));
+        self
+    }
+
+    /// Call `hook` with every traced request when the [`Service`] is called, to drive another
+    /// observability system from the same middleware alongside the OpenTelemetry span.
+    ///
+    /// ```
+    /// let layer = tower_opentelemetry::Layer::new()
+    ///     .with_on_request(|parts| eprintln!("{} {}", parts.method, parts.uri))
+    ///     .with_on_response(|parts, elapsed| match parts {
+    ///         Some(parts) => eprintln!("{} in {elapsed:?}", parts.status),
+    ///         None => eprintln!("failed in {elapsed:?}"),
+    ///     });
+    /// ```
+    ///
+    /// The hooks fire whether or not the span is sampled, but not for requests skipped by
+    /// [`Layer::with_excluded_paths`], [`Layer::with_excluded_methods`] or a disabled
+    /// [`TracingSwitch`].
+    #[must_use]
+    pub fn with_on_request<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(&request::Parts) + Send + Sync + 'static,
+    {
+        self.config.on_request = Some(Arc::new(hook));
+        self
+    }
+
+    /// Call `hook` with every traced response and the time since the [`Service`] was called,
+    /// the counterpart of [`Layer::with_on_request`].
+    ///
+    /// `hook` receives `None` when the inner service failed. The time is measured to the
+    /// response head, even with [`Layer::with_deferred_span_end`].
+    #[must_use]
+    pub fn with_on_response<F>(mut self, hook: F) -> Self
+    where
+        F: Fn(Option<&http::response::Parts>, Duration) + Send + Sync + 'static,
+    {
+        self.config.on_response = Some(Arc::new(hook));
+        self
+    }
+
+    /// Add attributes to every span that need to be awaited, such as a tenant resolved from a
+    /// token store.
+    ///
+    /// `hook` runs synchronously on the request's [`request::Parts`] in `call` and returns a
+    /// future. The inner service is not called until that future resolves, so its latency is
+    /// added to every request; a `tower_opentelemetry.async_attributes` span event marks when it
+    /// completed. Prefer [`Layer::with_span_builder_hook`] for attributes that can
+    /// be computed synchronously.
+    #[must_use]
+    pub fn with_async_attributes<F, Fut>(mut self, hook: F) -> Self
+    where
+        F: Fn(&request::Parts) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Vec<KeyValue>> + Send + 'static,
+    {
+        self.config.async_attributes = Some(Arc::new(move |parts| hook(parts).boxed()));
+        self
+    }
+
+    /// Prefix the keys of the attributes added by [`Layer::with_attribute_extractor`],
+    /// [`Layer::with_async_attributes`] and [`Layer::with_span_builder_hook`] with `prefix`, such
+    /// as `app.`, so they can't collide with the standard attributes.
+    ///
+    /// Only attributes the span builder hook appends are prefixed; attributes set by the layer
+    /// keep their keys even if the hook changes them. By default keys are used as given.
+    #[must_use]
+    pub fn with_custom_attribute_prefix<T>(mut self, prefix: T) -> Self
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        self.config.custom_attribute_prefix = Some(prefix.into());
+        self
+    }
+
+    /// Record `cache.hit` from the response header `name`, using `is_hit` to decide whether its
+    /// value denotes a cache hit.
+    ///
+    /// ```
+    /// use http::HeaderName;
+    ///
+    /// let layer = tower_opentelemetry::Layer::new()
+    ///     .with_cache_hit_header(HeaderName::from_static("x-cache"), |value| value.starts_with("HIT"));
+    /// ```
+    ///
+    /// The attribute is omitted when the response doesn't carry the header.
+    #[must_use]
+    pub fn with_cache_hit_header<F>(mut self, name: HeaderName, is_hit: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.config.cache_hit = Some((name, Arc::new(is_hit)));
+        self
+    }
+
+    /// Choose how the request's `User-Agent` is recorded, defaulting to
+    /// [`UserAgentRecording::Full`].
+    #[must_use]
+    pub fn with_user_agent(mut self, user_agent: UserAgentRecording) -> Self {
+        self.config.user_agent = user_agent;
+        self
+    }
+
+    /// Record the wall-clock time the request was received as `http.request.start_time`, in the
+    /// given `format`.
+    ///
+    /// The time is captured as soon as the request reaches the [`Service`], independently of the
+    /// span's own start time, for reconciling traces with logs keyed on wall time.
+    #[must_use]
+    pub fn with_request_start_time(mut self, format: StartTimeFormat) -> Self {
+        self.config.request_start_time = Some(format);
+        self
+    }
+
+    /// Insert a [`SpanEvents`] handle into each request's extensions, through which handlers can
+    /// add events to the server span. Disabled by default.
+    #[must_use]
+    pub fn with_span_events(mut self, enabled: bool) -> Self {
+        self.config.span_events = enabled;
+        self
+    }
+
+    /// Whether to insert a [`RequestContext`] into the request extensions, so handlers can carry
+    /// the request's context into spawned tasks. Disabled by default.
+    #[must_use]
+    pub fn with_request_context(mut self, enabled: bool) -> Self {
+        self.config.request_context = enabled;
+        self
+    }
+
+    /// Name spans with `namer` instead of the request path.
+    ///
+    /// Returning a `&'static str` through [`Cow::Borrowed`] avoids allocating a name per request.
+    /// The name is still subject to [`Layer::with_max_span_name_len`].
+    #[must_use]
+    pub fn with_span_name<F>(mut self, namer: F) -> Self
+    where
+        F: Fn(&request::Parts) -> Cow<'static, str> + Send + Sync + 'static,
+    {
+        self.config.span_namer = Some(Arc::new(namer));
+        self
+    }
+
+    /// Truncate span names longer than `max` bytes, replacing the tail with `...` so the name
+    /// including the marker fits in `max`. A `max` of 3 or less leaves no room for the marker, so
+    /// names are then cut without it. Truncation never splits a multibyte character.
+    #[must_use]
+    pub fn with_max_span_name_len(mut self, max: usize) -> Self {
+        self.config.max_span_name_len = Some(max);
+        self
+    }
+
+    /// Whether to record the canonical reason phrase of the response status as `http.status_text`.
+    ///
+    /// Omitted for status codes without a canonical reason. Disabled by default, since it is
+    /// redundant with `http.status_code` for most tooling.
+    #[must_use]
+    pub fn with_status_text(mut self, enabled: bool) -> Self {
+        self.config.status_text = enabled;
+        self
+    }
+
+    /// Whether to record whether a [`MatchedRoute`] was found in the request or response
+    /// extensions as `http.route.matched`.
+    ///
+    /// Requests that fall through to a catch-all handler without a route template are recorded
+    /// as `false`, which makes unrouted traffic easy to find. Disabled by default.
+    #[must_use]
+    pub fn with_route_matched(mut self, enabled: bool) -> Self {
+        self.config.route_matched = enabled;
+        self
+    }
+
+    /// Whether to record the class of the response status, `1xx` to `5xx`, as
+    /// `http.status_class`, which is cheaper to filter on than ranges of `http.status_code`.
+    ///
+    /// Omitted for nonstandard status codes of `600` and above. Disabled by default.
+    #[must_use]
+    pub fn with_status_class(mut self, enabled: bool) -> Self {
+        self.config.status_class = enabled;
+        self
+    }
+
+    /// Choose whether and how the request's `Referer` is recorded as
+    /// `http.request.header.referer`, defaulting to [`RefererRecording::Omit`].
+    #[must_use]
+    pub fn with_referer(mut self, referer: RefererRecording) -> Self {
+        self.config.referer = referer;
+        self
+    }
+
+    /// Whether to start spans at the time in a [`ReceivedAt`] request extension, when present, so
+    /// their duration includes time spent queued in outer layers.
+    ///
+    /// The same time is used for [`Layer::with_request_start_time`]. Without the extension spans
+    /// start when the [`Service`] is called. Disabled by default.
+    #[must_use]
+    pub fn with_received_at_start_time(mut self, enabled: bool) -> Self {
+        self.config.received_at_start_time = enabled;
+        self
+    }
+
+    /// Whether to record the `http.server.duration` and `http.server.requests` metrics through
+    /// the global meter provider.
+    ///
+    /// Both are broken down by `http.method`, `http.status_code`, `network.protocol.version` and
+    /// `http.route`, which are all low-cardinality: the route is the [`MatchedRoute`] template, or
+    /// `_OTHER` when none is known, never the raw path. With [`Layer::with_deferred_span_end`],
+    /// `http.server.ttfb` is also recorded when the response head is produced, and the duration
+    /// covers streaming the body as well. A request whose future is dropped before it produced a
+    /// response is recorded without a status code and with `error.type` set to `cancelled`.
+    /// Disabled by default.
+    ///
+    /// The metrics API of `opentelemetry` 0.17 has no support for exemplars, so the duration
+    /// histogram can't link to the trace of an example request. Putting the trace id in the
+    /// attributes instead would make every request its own series, so it is deliberately left out.
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn with_metrics(mut self, enabled: bool) -> Self {
+        self.config.metrics = enabled;
+        self
+    }
+
+    /// Whether to record a reduced set of attributes on every span, trading detail for throughput
+    /// on hot paths.
+    ///
+    /// Spans then carry only `http.method`, `http.route` when a [`MatchedRoute`] is known,
+    /// `http.status_code`, and any attributes added to every span or enabled for the response.
+    /// The URL, target, flavor, scheme, host name and port, addresses, user agent and every other
+    /// request header are never read. Disabled by default.
+    #[must_use]
+    pub fn with_minimal_attributes(mut self, enabled: bool) -> Self {
+        self.config.minimal_attributes = enabled;
+        self
+    }
+
+    /// Record a minimal span for requests with any of `methods`, such as `HEAD` health probes.
+    ///
+    /// Of the request attributes, those spans only carry `http.method` and any attributes added
+    /// to every span; the URL, user agent and the other request details are skipped. The response
+    /// status is still recorded. By default every method gets the full set of attributes.
+    #[must_use]
+    pub fn with_minimal_attributes_for<I>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = Method>,
+    {
+        self.config.minimal_methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Whether to build spans without any request attributes, the extreme end of
+    /// [`Layer::with_minimal_attributes`] for the hottest paths.
+    ///
+    /// Not even `http.method` or `net.host.name` is recorded, so with a static span name and no
+    /// other per-request attributes enabled, such as a request id header or
+    /// [`Layer::with_baggage_attributes`], no attribute list is allocated at all. Request headers
+    /// that are only recorded when explicitly enabled still are, namely [`Layer::with_accept`],
+    /// [`Layer::with_accept_encoding`], [`Layer::with_idempotency_key_header`] and
+    /// [`Layer::with_retry_header`]. Attributes added to every span and those recorded from the
+    /// response are kept. Disabled by default.
+    #[must_use]
+    pub fn with_bare_spans(mut self, enabled: bool) -> Self {
+        self.config.bare_spans = enabled;
+        self
+    }
+
+    /// Whether to count error responses without a [`Handled`] marker in
+    /// `http.server.rejected_requests`, so rejections by layers between the [`Service`] and the
+    /// handler show up in metrics.
+    ///
+    /// Requires [`Layer::with_metrics`]. Disabled by default.
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn with_rejection_metric(mut self, enabled: bool) -> Self {
+        self.config.rejection_metric = enabled;
+        self
+    }
+
+    /// Whether to record the request's `Accept-Encoding` as `http.request.header.accept_encoding`.
+    ///
+    /// Omitted when the request has no `Accept-Encoding`. Disabled by default.
+    #[must_use]
+    pub fn with_accept_encoding(mut self, enabled: bool) -> Self {
+        self.config.accept_encoding = enabled;
+        self
+    }
+
+    /// Whether to record the request's `Accept` as `http.request.header.accept`, for diagnosing
+    /// content negotiation and `406` responses.
+    ///
+    /// Omitted when the request has no `Accept`. Disabled by default.
+    #[must_use]
+    pub fn with_accept(mut self, enabled: bool) -> Self {
+        self.config.accept = enabled;
+        self
+    }
+
+    /// Record whether the request carries the header `name`, such as `Idempotency-Key`, as the
+    /// boolean `http.request.has_idempotency_key`, for debugging duplicate submissions to
+    /// transactional APIs.
+    ///
+    /// Only the presence of the header is recorded, never the key itself. By default the
+    /// attribute isn't recorded.
+    #[must_use]
+    pub fn with_idempotency_key_header(mut self, name: HeaderName) -> Self {
+        self.config.idempotency_key_header = Some(name);
+        self
+    }
+
+    /// Whether to record the response's `Content-Encoding` as
+    /// `http.response.header.content_encoding`.
+    ///
+    /// Omitted when the response has no `Content-Encoding`. Disabled by default.
+    #[must_use]
+    pub fn with_content_encoding(mut self, enabled: bool) -> Self {
+        self.config.content_encoding = enabled;
+        self
+    }
+
+    /// Whether to record the response's `Content-Language` as `http.response.header.content_language`,
+    /// telling which language a localized service served.
+    ///
+    /// Omitted when the response has no `Content-Language`. Disabled by default.
+    #[must_use]
+    pub fn with_content_language(mut self, enabled: bool) -> Self {
+        self.config.content_language = enabled;
+        self
+    }
+
+    /// Whether to record the response's `Age` as the integer `http.response.header.age`, telling
+    /// how long a cached response had been stored.
+    ///
+    /// Omitted when the response has no `Age` or it isn't a non-negative number of seconds.
+    /// Disabled by default.
+    #[must_use]
+    pub fn with_response_age(mut self, enabled: bool) -> Self {
+        self.config.response_age = enabled;
+        self
+    }
+
+    /// Whether to record if the response is sent with chunked transfer encoding as
+    /// `http.response.chunked`, to debug proxies and clients that mishandle it.
+    ///
+    /// A response is chunked when the last coding of its `Transfer-Encoding` is `chunked` or, for
+    /// HTTP/1.1, when it has neither `Transfer-Encoding` nor `Content-Length`. This reflects the
+    /// headers produced by the handler: a server that adds `Content-Length` for bodies of known
+    /// size sends those unchunked even though they are recorded as chunked. Responses that never
+    /// have a body, such as `204 No Content` or the response to a `HEAD` request, are recorded as
+    /// unchunked. Omitted for HTTP/2 and later, which have no chunked encoding. Disabled by
+    /// default.
+    #[must_use]
+    pub fn with_chunked_response(mut self, enabled: bool) -> Self {
+        self.config.chunked_response = enabled;
+        self
+    }
+
+    /// Whether to record the full request URL as [`HTTP_URL`]. Enabled by default.
+    #[must_use]
+    pub fn with_url(mut self, enabled: bool) -> Self {
+        self.config.record_url = enabled;
+        self
+    }
+
+    /// Choose how much of the request URL [`HTTP_URL`] records, defaulting to
+    /// [`UrlRecording::Full`].
+    ///
+    /// [`UrlRecording::NoQuery`] keeps a useful absolute URL while leaving out query strings,
+    /// which often carry personal data. Has no effect when [`Layer::with_url`] is disabled.
+    #[must_use]
+    pub fn with_url_recording(mut self, url: UrlRecording) -> Self {
+        self.config.url_recording = url;
+        self
+    }
+
+    /// Choose how the client and peer addresses are recorded, defaulting to
+    /// [`ClientIpRecording::Raw`].
+    #[must_use]
+    pub fn with_client_ip(mut self, client_ip: ClientIpRecording) -> Self {
+        self.config.client_ip = client_ip;
+        self
+    }
+
+    /// Apply the privacy-preserving setting of every option that can record personal data.
+    ///
+    /// This is shorthand for:
+    /// - [`Layer::with_url`]`(false)`, dropping [`HTTP_URL`]
+    /// - [`Layer::with_query`]`(`[`QueryRecording::Omit`]`)`, keeping only the path in
+    ///   [`HTTP_TARGET`] and the span name
+    /// - [`Layer::with_user_agent`]`(`[`UserAgentRecording::Omit`]`)`, dropping [`HTTP_USER_AGENT`]
+    /// - [`Layer::with_referer`]`(`[`RefererRecording::Omit`]`)`, dropping the referer
+    /// - [`Layer::with_client_ip`]`(`[`ClientIpRecording::Hashed`]`)`, hashing [`HTTP_CLIENT_IP`]
+    ///   and [`NET_PEER_IP`]
+    ///
+    /// Later calls to those methods override the corresponding part of the preset.
+    #[must_use]
+    pub fn with_privacy_mode(self) -> Self {
+        self.with_url(false)
+            .with_query(QueryRecording::Omit)
+            .with_user_agent(UserAgentRecording::Omit)
+            .with_referer(RefererRecording::Omit)
+            .with_client_ip(ClientIpRecording::Hashed)
+    }
+
+    /// Correlate requests by the id in the request header `name`, recording it as
+    /// `http.request.id` and echoing it in the same response header.
+    ///
+    /// Requests without the header get a generated id, a random UUID unless
+    /// [`Layer::with_request_id_generator`] is set, which is also inserted into the request
+    /// headers so the handler sees it. Unlike the trace id, the request id is recorded regardless
+    /// of sampling. Disabled by default.
+    #[must_use]
+    pub fn with_request_id(mut self, name: HeaderName) -> Self {
+        self.config.request_id_header = Some(name);
+        self
+    }
+
+    /// Generate missing request ids with `generator` instead of as random UUIDs.
+    ///
+    /// Only used when [`Layer::with_request_id`] is enabled. Ids that aren't valid header values
+    /// are discarded.
+    #[must_use]
+    pub fn with_request_id_generator<F>(mut self, generator: F) -> Self
+    where
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.config.request_id_generator = Some(Arc::new(generator));
+        self
+    }
+
+    /// Record the time taken to produce the response as a span attribute in `unit`.
+    ///
+    /// The span's start and end already encode its duration, but an explicit attribute can be
+    /// easier to query. Disabled by default.
+    #[must_use]
+    pub fn with_duration_attribute(mut self, unit: DurationUnit) -> Self {
+        self.config.duration_attribute = Some(unit);
+        self
+    }
+
+    /// Whether to make the request's context current while the inner service's `call` runs.
+    ///
+    /// The inner service's future always runs within the context, so handlers awaiting in it can
+    /// use [`Context::current`] either way. Turning this off avoids attaching the context on the
+    /// calling thread, for runtimes where that is costly or conflicts with their own
+    /// task-locals, at the cost of inner layers such as `request_body::Layer` no longer seeing the
+    /// span when they are called. Enabled by default.
+    #[must_use]
+    pub fn with_context_attachment(mut self, enabled: bool) -> Self {
+        self.config.attach_context = enabled;
+        self
+    }
+
+    /// Whether to record both clocks' view of each request for detecting clock skew downstream:
+    /// the monotonic duration as `timing.monotonic.duration_ms`, and the wall-clock start and end
+    /// as `timing.wall.start_unix_nano` and `timing.wall.end_unix_nano`.
+    ///
+    /// Both are observed around the inner service's future, so the wall-clock difference only
+    /// departs from the monotonic duration when the system clock is adjusted mid-request.
+    /// Disabled by default because of the extra attributes.
+    #[must_use]
+    pub fn with_clock_skew_attributes(mut self, enabled: bool) -> Self {
+        self.config.clock_skew_attributes = enabled;
+        self
+    }
+
+    /// Pick the client address from `X-Forwarded-For` for a deployment behind `hops` trusted
+    /// proxies, counting from the right of the chain.
+    ///
+    /// Each trusted proxy appends the address it received the request from, so the entry `hops`
+    /// from the right is the client as seen by the outermost trusted proxy, and entries further
+    /// left, which the client can forge, are ignored. The chain is only read with
+    /// [`Layer::with_trusted_forwarded_headers`] enabled, and `hops` of zero, the default, uses
+    /// its leftmost entry.
+    #[must_use]
+    pub fn with_trusted_hops(mut self, hops: usize) -> Self {
+        self.config.trusted_hops = hops;
+        self
+    }
+
+    /// Whether to explicitly set the span status to `Ok` for responses the classifier considers
+    /// successful.
+    ///
+    /// Some backends treat an unset status differently from `Ok`. By default the status of
+    /// successful requests is left unset.
+    #[must_use]
+    pub fn with_ok_status(mut self, enabled: bool) -> Self {
+        self.config.ok_status = enabled;
+        self
+    }
+
+    /// Whether to record inner service errors as an `exception` event following the stable
+    /// exception conventions, with the error's type name as `exception.type` alongside
+    /// `exception.message` and `exception.stacktrace`.
+    ///
+    /// By default the event carries only the message and stack trace.
+    #[must_use]
+    pub fn with_structured_exceptions(mut self, enabled: bool) -> Self {
+        self.config.structured_exceptions = enabled;
+        self
+    }
+
+    /// Record `http.request.is_retry` from the request header `name`, using `is_retry` to decide
+    /// whether its value marks the request as a retry.
+    ///
+    /// ```
+    /// use http::HeaderName;
+    ///
+    /// let layer = tower_opentelemetry::Layer::new()
+    ///     .with_retry_header(HeaderName::from_static("x-retry"), |value| value.eq_ignore_ascii_case("true"));
+    /// ```
+    ///
+    /// The attribute is omitted when the request doesn't carry the header.
+    #[must_use]
+    pub fn with_retry_header<F>(mut self, name: HeaderName, is_retry: F) -> Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.config.retry = Some((name, Arc::new(is_retry)));
+        self
+    }
+
+    /// Link each span to the upstream contexts listed in the request header `name`, keeping at
+    /// most `max_links` distinct links.
+    ///
+    /// Each header value holds one or more comma-separated contexts in the W3C `traceparent`
+    /// format, and the header may be repeated. Malformed entries, duplicates and the span's own
+    /// parent are skipped.
+    #[must_use]
+    pub fn with_link_header(mut self, name: HeaderName, max_links: usize) -> Self {
+        self.config.link_header = Some((name, max_links));
+        self
+    }
+
+    /// Skip tracing for requests whose path is exactly one of `paths`, such as health checks.
+    ///
+    /// Excluded requests are passed straight to the inner service: no span is started, no
+    /// context is extracted or injected and no attributes are recorded. By default every request
+    /// is traced.
+    #[must_use]
+    pub fn with_excluded_paths<I, P>(mut self, paths: I) -> Self
+    where
+        I: IntoIterator<Item = P>,
+        P: Into<Cow<'static, str>>,
+    {
+        self.config.excluded_paths = paths.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Skip tracing for requests with any of `methods`, such as every `OPTIONS` request.
+    ///
+    /// Excluded requests are treated like those of [`Layer::with_excluded_paths`], and a request
+    /// is skipped when either its method or its path is excluded. By default every method is
+    /// traced.
+    #[must_use]
+    pub fn with_excluded_methods<I>(mut self, methods: I) -> Self
+    where
+        I: IntoIterator<Item = Method>,
+    {
+        self.config.excluded_methods = methods.into_iter().collect();
+        self
+    }
+
+    /// Whether to count requests skipped by [`Layer::with_excluded_paths`] or
+    /// [`Layer::with_excluded_methods`] in `tower_otel.excluded_requests`, broken down by `http.method`.
+    ///
+    /// Requires [`Layer::with_metrics`]. Disabled by default.
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn with_exclusion_metric(mut self, enabled: bool) -> Self {
+        self.config.exclusion_metric = enabled;
+        self
+    }
+
+    /// Whether to record the time the caller waited for the service to become ready as
+    /// `http.server.ready_wait_ms`, separating queueing in a concurrency limit or a busy inner
+    /// service from the handling time.
+    ///
+    /// The wait runs from the first `poll_ready` that returned `Pending` to the call, and is zero
+    /// when the service was ready straight away. Disabled by default.
+    #[must_use]
+    pub fn with_ready_wait(mut self, enabled: bool) -> Self {
+        self.config.ready_wait = enabled;
+        self
+    }
+
+    /// Whether to count callers that stop waiting for the service to become ready, dropping it
+    /// before passing their request to `call`, in `tower_otel.abandoned_requests`.
+    ///
+    /// These requests are lost to backpressure before any span exists for them. A caller is
+    /// counted when it drops the [`Service`] after `poll_ready` returned `Pending` without calling
+    /// it, as when a [`ServiceExt::oneshot`] future is dropped while the service isn't ready.
+    /// Clones of a waiting service start out not waiting. Requires [`Layer::with_metrics`].
+    /// Disabled by default.
+    ///
+    /// [`ServiceExt::oneshot`]: https://docs.rs/tower/latest/tower/trait.ServiceExt.html#method.oneshot
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn with_abandonment_metric(mut self, enabled: bool) -> Self {
+        self.config.abandonment_metric = enabled;
+        self
+    }
+
+    /// Whether to add the raw request method as `http.request.method_original` to the metric
+    /// attributes, as on spans, when `http.method` was normalized to `_OTHER` or to its canonical
+    /// case.
+    ///
+    /// Clients can send any token as the method, so this can create a series per distinct method
+    /// and should only be enabled when every client is trusted. Requires [`Layer::with_metrics`].
+    /// Disabled by default, leaving only the normalized method.
+    #[cfg(feature = "metrics")]
+    #[must_use]
+    pub fn with_metric_method_original(mut self, enabled: bool) -> Self {
+        self.config.metric_method_original = enabled;
+        self
+    }
+
+    /// Whether to record `rpc.system`, `rpc.service` and `rpc.method` for gRPC calls.
+    ///
+    /// Requests are recognized by an `application/grpc` content type, including gRPC-Web's
+    /// `application/grpc-web` and `application/grpc-web-text` used by browser clients, and the
+    /// service and method are taken from the `/<service>/<method>` path. Disabled by default.
+    #[cfg(feature = "grpc")]
+    #[must_use]
+    pub fn with_grpc(mut self, enabled: bool) -> Self {
+        self.config.grpc = enabled;
+        self
+    }
+
+    /// Whether to record `http.host.invalid` as `true` when the `Host` header can't be parsed as
+    /// an authority.
+    ///
+    /// Malformed hosts are never recorded: `net.host.port` and `http.host` are omitted for them
+    /// either way, and this marks the span so hostile or buggy clients can still be found.
+    /// Requests with an authority in their URI ignore the `Host` header. Disabled by default.
+    #[must_use]
+    pub fn with_invalid_host_marker(mut self, enabled: bool) -> Self {
+        self.config.invalid_host_marker = enabled;
+        self
+    }
+
+    /// Whether to record the host and port the request was addressed to as a single `http.host`
+    /// authority, such as `example.com:443`, alongside `net.host.port`.
+    ///
+    /// The host comes from the URI or the `Host` header and the port is the same as
+    /// `net.host.port`, so the value is just the host when the port is unknown. Omitted when the
+    /// request names no host. Disabled by default.
+    #[must_use]
+    pub fn with_host_authority(mut self, enabled: bool) -> Self {
+        self.config.host_authority = enabled;
+        self
+    }
+
+    /// Check `switch` on every request and pass requests straight through to the inner service,
+    /// without a span, while it is off.
+    ///
+    /// See [`TracingSwitch`]. By default tracing can't be switched off at runtime.
+    #[must_use]
+    pub fn with_switch(mut self, switch: TracingSwitch) -> Self {
+        self.config.switch = Some(switch);
+        self
+    }
+
+    /// Follow `handle`, switching every [`Service`] built by this layer to the configuration of
+    /// each [`ReloadHandle::reload`].
+    ///
+    /// See [`ReloadHandle`] for what a reload replaces. By default the configuration is fixed
+    /// when the layer is built.
+    #[must_use]
+    pub fn with_reload_handle(mut self, handle: ReloadHandle) -> Self {
+        self.config.reload = Some(handle);
+        self
+    }
+
+    /// Whether to also enter a [`tracing`] span around each request, with `http.method`,
+    /// `http.route`, `http.status_code` and `trace_id` fields, so logs written through `tracing`
+    /// can be correlated with the trace.
+    ///
+    /// The fields reuse the values computed for the OpenTelemetry span. `http.route` is only set
+    /// when a [`MatchedRoute`] is known and `http.status_code` once the response is produced.
+    /// Disabled by default.
+    ///
+    /// [`tracing`]: https://docs.rs/tracing
+    #[cfg(feature = "tracing")]
+    #[must_use]
+    pub fn with_tracing_span(mut self, enabled: bool) -> Self {
+        self.config.tracing_span = enabled;
+        self
+    }
+
+    /// Whether to log every server span through [`tracing`] instead of exporting it, to check
+    /// the span names and attributes a configuration produces before wiring up an exporter.
+    ///
+    /// Each span is logged when it ends as an `INFO` event with the target
+    /// `tower_opentelemetry::dry_run`, carrying its name, kind, status, duration, attributes and
+    /// event names. Spans are buffered as with [`Layer::with_tail_sampling`], which is ignored
+    /// along with [`Layer::with_min_export_duration`], and child spans created by handlers are
+    /// still exported. Disabled by default.
+    ///
+    /// [`tracing`]: https://docs.rs/tracing
+    #[cfg(feature = "dry-run")]
+    #[must_use]
+    pub fn with_dry_run(mut self, enabled: bool) -> Self {
+        self.config.tail_policy.dry_run = enabled;
+        self
+    }
+
+    /// Decide whether to export each server span once it ends, from the response status and the
+    /// request's duration, rather than when it starts.
+    ///
+    /// `keep` receives the response status, or `None` when there is no response because the inner
+    /// service failed or the request was cancelled by dropping its future, and returns whether to
+    /// export the span. This is a lightweight local tail sampler, for example to
+    /// always keep `5xx` responses and slow requests while sampling the rest, and it replaces the
+    /// tracer's sampler for the server span. Kept spans carry the full set of attributes and
+    /// events.
+    ///
+    /// Every span is buffered in memory until it ends, so each request costs about as much as a
+    /// sampled one. Only the server span is tail-sampled: its context is always marked as
+    /// sampled, so child spans are recorded and exported regardless of the decision. The ids of
+    /// the buffered span come from the provider's `IdGenerator` when an SDK tracer is passed to
+    /// [`Layer::with_tracer_provider`], and from the SDK's default generator otherwise, including
+    /// with the global provider. Disabled by default.
+    #[must_use]
+    pub fn with_tail_sampling<F>(mut self, keep: F) -> Self
+    where
+        F: Fn(Option<http::StatusCode>, Duration) -> bool + Send + Sync + 'static,
+    {
+        self.config.tail_policy.sampler = Some(Arc::new(keep));
+        self
+    }
+
+    /// Only export server spans that take at least `min`, or end with an error status, dropping
+    /// the fast successful ones to focus on latency outliers.
+    ///
+    /// The decision is made when the span ends, so spans are buffered in the same way as with
+    /// [`Layer::with_tail_sampling`], which still decides the spans that are slow enough when both
+    /// are set, and the tracer's sampler is likewise bypassed for the server span. Child spans
+    /// created by handlers are exported even when their server span is dropped. Disabled by
+    /// default.
+    #[must_use]
+    pub fn with_min_export_duration(mut self, min: Duration) -> Self {
+        self.config.tail_policy.min_duration = Some(min);
+        self
+    }
+
+    /// Rename the keys of the attributes the layer records, such as from `http.status_code` to
+    /// `http.response.status_code` for a backend that expects newer semantic conventions.
+    ///
+    /// ```
+    /// let layer = tower_opentelemetry::Layer::new()
+    ///     .with_attribute_keys([("http.status_code", "http.response.status_code")]);
+    /// ```
+    ///
+    /// Keys that aren't mapped are recorded unchanged. Attributes added by extractors, the span
+    /// builder hook or [`Layer::with_async_attributes`], and those the body layers record, keep
+    /// their keys.
+    #[must_use]
+    pub fn with_attribute_keys<I, K, V>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = (K, V)>,
+        K: Into<Key>,
+        V: Into<Key>,
+    {
+        self.config.attribute_keys = keys.into_iter().map(|(from, to)| (from.into(), to.into())).collect();
+        self
+    }
+
+    /// Record the inbound baggage entries with any of `keys` on the span as `baggage.<key>`
+    /// attributes, so backends that can't query baggage can still filter on them.
+    ///
+    /// ```
+    /// let layer = tower_opentelemetry::Layer::new().with_baggage_attributes(["user.tier"]);
+    /// ```
+    ///
+    /// Entries are read from the baggage extracted from the request, whatever the
+    /// [`TraceContinuation`], and omitted when absent. Only the listed keys are copied, since
+    /// callers choose the baggage they send and could otherwise add any number of attributes. By
+    /// default no baggage is recorded.
+    #[must_use]
+    pub fn with_baggage_attributes<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<Key>,
+    {
+        self.config.baggage_attributes = keys
+            .into_iter()
+            .map(|key| {
+                let key = key.into();
+                let attribute = Key::new(format!("baggage.{}", key.as_str()));
+                (key, attribute)
+            })
+            .collect();
+        self
+    }
+
+    /// Hold back the request attributes with any of `keys` until the request ends, and only record
+    /// them on spans that end as errors, to keep successful spans small.
+    ///
+    /// ```
+    /// let layer = tower_opentelemetry::Layer::new()
+    ///     .with_error_only_attributes(["http.url", "http.user_agent"]);
+    /// ```
+    ///
+    /// Any attribute known when the span starts can be held back, such as the URL, user agent or
+    /// recorded request headers, matched by the key it is recorded with. Held back attributes are
+    /// invisible to the sampler, the span builder hook and the span namer, so attributes those
+    /// rely on, such as `http.method` or `http.route`, shouldn't be. A span ends as an error when
+    /// the inner service fails or its response gets an error status. By default every attribute
+    /// is recorded when the span starts.
+    #[must_use]
+    pub fn with_error_only_attributes<I, K>(mut self, keys: I) -> Self
+    where
+        I: IntoIterator<Item = K>,
+        K: Into<Key>,
+    {
+        self.config.error_only_attributes = keys.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Decide whether to sample each server span with `sampler` before it is built, instead of
+    /// deferring to the sampler of the tracer provider.
+    ///
+    /// ```
+    /// use opentelemetry::sdk::trace::Sampler;
+    ///
+    /// let layer = tower_opentelemetry::Layer::new()
+    ///     .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(0.1))));
+    /// ```
+    ///
+    /// `sampler` runs in `call` with the parent context and the span's name, kind, attributes and
+    /// links, and its decision is handed to the tracer, so the provider's sampler isn't consulted
+    /// for the server span. Child spans still follow the provider's sampler. Only SDK tracers
+    /// honour the decision, and [`Layer::with_tail_sampling`] takes precedence over it.
+    ///
+    /// The trace id of a root span comes from the provider's [`IdGenerator`] so that `sampler`
+    /// decides on the id the span gets. That generator can only be reached from an SDK tracer
+    /// passed to [`Layer::with_tracer_provider`]: with the global provider, `sampler` decides on
+    /// an id of its own and the span's id is left to the provider, so a ratio-based decision
+    /// isn't tied to the span's trace id.
+    ///
+    /// [`IdGenerator`]: opentelemetry::trace::IdGenerator
+    #[must_use]
+    pub fn with_sampler<S>(mut self, sampler: S) -> Self
+    where
+        S: ShouldSample + 'static,
+    {
+        self.config.sampler = Some(Arc::new(sampler));
+        self
+    }
+
+    /// Decide whether to trace each request with `decide`, from anything in its
+    /// [`request::Parts`] such as the method, headers or a tenant in the extensions.
+    ///
+    /// ```
+    /// use opentelemetry::sdk::trace::SamplingDecision;
+    ///
+    /// let layer = tower_opentelemetry::Layer::new().with_request_sampler(|parts| {
+    ///     if parts.headers.contains_key("x-synthetic") {
+    ///         SamplingDecision::Drop
+    ///     } else {
+    ///         SamplingDecision::RecordAndSample
+    ///     }
+    /// });
+    /// ```
+    ///
+    /// `decide` runs in `call` before the span is built. On [`SamplingDecision::Drop`] no span is
+    /// built and the request goes straight to the inner service, which still runs in the
+    /// extracted parent context so outbound calls continue the inbound trace. Otherwise the span
+    /// is built with the returned decision, which takes precedence over [`Layer::with_sampler`]
+    /// and the provider's sampler. Only SDK tracers honour the decision to record or sample.
+    #[must_use]
+    pub fn with_request_sampler<F>(mut self, decide: F) -> Self
+    where
+        F: Fn(&request::Parts) -> SamplingDecision + Send + Sync + 'static,
+    {
+        self.config.request_sampler = Some(Arc::new(decide));
+        self
+    }
+
+    /// Apply `route_override` to the spans of requests whose [`MatchedRoute`] is `route` when they
+    /// end, such as to flag known flaky or deprecated endpoints centrally.
+    ///
+    /// The route is read from the response extensions, or else the request extensions, and is
+    /// compared to the template, which keeps the overrides bounded to known routes. Calling this
+    /// again for the same route replaces its override.
+    #[must_use]
+    pub fn with_route_override<R>(mut self, route: R, route_override: RouteOverride) -> Self
+    where
+        R: Into<Cow<'static, str>>,
+    {
+        let route = route.into();
+        self.config.route_overrides.retain(|(existing, _)| *existing != route);
+        self.config.route_overrides.push((route, route_override));
+        self
+    }
+
+    /// Map errors returned by the inner service to a low-cardinality `error.type` with `map`,
+    /// tried before the mappings registered earlier and the built-in ones.
+    ///
+    /// ```
+    /// use std::error::Error;
+    ///
+    /// #[derive(Debug)]
+    /// struct Elapsed;
+    ///
+    /// impl std::fmt::Display for Elapsed {
+    ///     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    ///         f.write_str("request timed out")
+    ///     }
+    /// }
+    ///
+    /// impl Error for Elapsed {}
+    ///
+    /// let layer = tower_opentelemetry::Layer::new()
+    ///     .with_error_type(|error: &(dyn Error + 'static)| error.is::<Elapsed>().then_some("timeout"));
+    /// ```
+    ///
+    /// Every error records `error.type` next to its `exception` event by default, with or without
+    /// a mapping registered here: it comes from the first error in its [`source`] chain that a
+    /// mapping recognizes. Timed out [`std::io::Error`]s are built in as `timeout` and aborted
+    /// futures as `cancelled`; other errors fall back to their type name. Timeout errors such as
+    /// `tower::timeout::error::Elapsed` come from crates this one doesn't depend on, so they
+    /// need a mapping like the one above.
+    ///
+    /// Mappings downcast the error, so the [`Service`] requires the inner service's error to be
+    /// `'static`. Errors borrowing non-`'static` data, which earlier releases accepted, no longer
+    /// compile; they are rare in practice, since Tower errors are usually owned or boxed.
+    ///
+    /// [`source`]: std::error::Error::source
+    #[must_use]
+    pub fn with_error_type<F>(mut self, map: F) -> Self
+    where
+        F: Fn(&(dyn StdError + 'static)) -> Option<&'static str> + Send + Sync + 'static,
+    {
+        self.config.error_types.insert(0, Arc::new(map));
+        self
+    }
+
+    /// Trace at most `max` requests at once, passing any further requests straight through to
+    /// the inner service without a span until some of the active spans end.
+    ///
+    /// This is a safety valve against spans that never end, such as those of response bodies
+    /// that are never dropped, which would otherwise keep their memory for good. A span counts as
+    /// active until it ends, including while it is deferred to the end of a streamed body or kept
+    /// open for a tunnel. With [`Layer::with_metrics`], requests passed through are counted in
+    /// `tower_otel.span_limited_requests`. [`DEFAULT_MAX_ACTIVE_SPANS`] is a generous limit for a
+    /// single service. There is no limit by default.
+    #[must_use]
+    pub fn with_max_active_spans(mut self, max: usize) -> Self {
+        self.config.span_limit = Some(Arc::new(SpanLimit::new(max)));
+        self
+    }
+
+    /// Whether to record the [`ResponseError`] that handlers insert into the extensions of
+    /// responses reporting several errors in their body, such as batch or GraphQL responses.
+    ///
+    /// The span is marked as an error even on a `200 OK` response, with the summary's type as
+    /// `error.type`, an `exception` event carrying its type and message, and its count as
+    /// `error.count` when known. A [`SpanStatus`] of `Ok` or `Unset` in the response overrides
+    /// this, and none of them are recorded then. Disabled by default.
+    #[must_use]
+    pub fn with_response_error(mut self, enabled: bool) -> Self {
+        self.config.response_error = enabled;
+        self
+    }
+
+    /// Choose where the request's query string is recorded, defaulting to
+    /// [`QueryRecording::Target`].
+    #[must_use]
+    pub fn with_query(mut self, query: QueryRecording) -> Self {
+        self.config.query = query;
+        self
     }
-}
 
-#[inline]
-fn http_flavor(version: Version) -> Cow<'static, str> {
-    match version {
-        Version::HTTP_09 => "0.9".into(),
-        Version::HTTP_10 => "1.0".into(),
-        Version::HTTP_11 => "1.1".into(),
-        Version::HTTP_2 => "2.0".into(),
-        Version::HTTP_3 => "3.0".into(),
-        other => format!("{:?}", other).into(),
+    /// Whether to record the number of query parameters as `http.query.param_count`.
+    ///
+    /// The attribute is omitted for requests without a query string. Disabled by default.
+    #[must_use]
+    pub fn with_query_param_count(mut self, enabled: bool) -> Self {
+        self.config.query_param_count = enabled;
+        self
     }
-}
 
-/// [`Layer`] that adds high level [opentelemetry propagation] to a [`Service`].
-///
-/// [`Layer`]: tower_layer::Layer
-/// [opentelemetry propagation]: https://opentelemetry.io/docs/java/manual_instrumentation/#context-propagation
-/// [`Service`]: tower_service::Service
-#[derive(Debug, Copy, Clone, Default)]
-pub struct Layer {}
+    /// Whether to keep the span of a successful `CONNECT` request open for the tunnel's lifetime.
+    ///
+    /// When enabled, the response carries a [`Tunnel`] extension that ends the span when dropped,
+    /// instead of the span ending as soon as the response is produced. Disabled by default.
+    #[must_use]
+    pub fn with_tunnel_spans(mut self, enabled: bool) -> Self {
+        self.config.tunnel_spans = enabled;
+        self
+    }
 
-impl Layer {
-    /// Create a new [`TraceLayer`] using the given [`MakeClassifier`].
+    /// Whether to keep each span open after the response is produced, until its body has been
+    /// streamed.
+    ///
+    /// When enabled, responses carry a [`DeferredSpan`] extension that ends the span when
+    /// dropped, for a body wrapper such as `response_body::Layer` to record attributes observed
+    /// at the end of the body. Spans of tunnels kept open by [`Layer::with_tunnel_spans`] are
+    /// unaffected. Disabled by default.
     #[must_use]
-    pub fn new() -> Self {
-        Self {}
+    pub fn with_deferred_span_end(mut self, enabled: bool) -> Self {
+        self.config.deferred_span_end = enabled;
+        self
     }
 }
 
@@ -71,26 +2035,1228 @@ impl<S> tower_layer::Layer<S> for Layer where S: Clone {
     type Service = Service<S>;
 
     fn layer(&self, inner: S) -> Self::Service {
-        Service::new(inner)
+        let reload = self.config.reload.clone().map(|handle| (handle, 0));
+        Service::with_config(inner, self.config.clone().for_service::<S>(), reload)
+    }
+}
+
+/// Settings shared between a [`Layer`] and every [`Service`] it produces.
+#[derive(Clone)]
+#[allow(clippy::struct_excessive_bools)]
+struct Config {
+    classifier: Arc<dyn ResponseClassifier>,
+    query: QueryRecording,
+    propagation_warning: bool,
+    query_param_count: bool,
+    tunnel_spans: bool,
+    static_attributes: Vec<KeyValue>,
+    tracer: Option<BoxedTracer>,
+    response_injection: ResponseInjection,
+    traceresponse: bool,
+    trace_id_header: Option<HeaderName>,
+    legacy_trace_id_header: Option<HeaderName>,
+    span_builder_hook: Option<Arc<SpanBuilderHook>>,
+    stream_id: bool,
+    trust_forwarded: bool,
+    response_content_type: bool,
+    async_attributes: Option<Arc<AsyncAttributes>>,
+    cache_hit: Option<(HeaderName, Arc<HeaderRule>)>,
+    user_agent: UserAgentRecording,
+    request_start_time: Option<StartTimeFormat>,
+    span_events: bool,
+    max_span_name_len: Option<usize>,
+    unix_peer: bool,
+    span_namer: Option<Arc<SpanNamer>>,
+    status_text: bool,
+    referer: RefererRecording,
+    received_at_start_time: bool,
+    schema_url: Option<&'static str>,
+    #[cfg(feature = "metrics")]
+    metrics: bool,
+    minimal_methods: Vec<Method>,
+    #[cfg(feature = "metrics")]
+    rejection_metric: bool,
+    accept_encoding: bool,
+    accept: bool,
+    content_encoding: bool,
+    record_url: bool,
+    url_recording: UrlRecording,
+    client_ip: ClientIpRecording,
+    request_id_header: Option<HeaderName>,
+    request_id_generator: Option<Arc<RequestIdGenerator>>,
+    duration_attribute: Option<DurationUnit>,
+    trusted_hops: usize,
+    ok_status: bool,
+    structured_exceptions: bool,
+    retry: Option<(HeaderName, Arc<HeaderRule>)>,
+    span_kind: SpanKind,
+    link_header: Option<(HeaderName, usize)>,
+    excluded_paths: Vec<Cow<'static, str>>,
+    excluded_methods: Vec<Method>,
+    #[cfg(feature = "metrics")]
+    exclusion_metric: bool,
+    #[cfg(feature = "metrics")]
+    abandonment_metric: bool,
+    #[cfg(feature = "metrics")]
+    metric_method_original: bool,
+    minimal_attributes: bool,
+    #[cfg(feature = "grpc")]
+    grpc: bool,
+    custom_attribute_prefix: Option<Cow<'static, str>>,
+    http2_negotiation: bool,
+    tls_server_name: bool,
+    http2_priority: bool,
+    http2_pushed: bool,
+    status_class: bool,
+    invalid_value_policy: InvalidValuePolicy,
+    host_authority: bool,
+    invalid_host_marker: bool,
+    switch: Option<TracingSwitch>,
+    deferred_span_end: bool,
+    request_context: bool,
+    trace_continuation: TraceContinuation,
+    upstream_continuation: bool,
+    #[cfg(feature = "tracing")]
+    tracing_span: bool,
+    propagator: Option<Arc<Propagator>>,
+    route_matched: bool,
+    tail_policy: tail::Policy,
+    inner_service_type: bool,
+    request_attributes: Option<Arc<RequestAttributes>>,
+    clock_skew_attributes: bool,
+    attach_context: bool,
+    attribute_keys: HashMap<Key, Key>,
+    sampler: Option<Arc<dyn ShouldSample>>,
+    route_overrides: Vec<(Cow<'static, str>, RouteOverride)>,
+    attribute_extractors: Vec<Arc<RequestAttributes>>,
+    on_request: Option<Arc<OnRequest>>,
+    on_response: Option<Arc<OnResponse>>,
+    error_types: Vec<Arc<ErrorTypeMapping>>,
+    span_limit: Option<Arc<SpanLimit>>,
+    reload: Option<ReloadHandle>,
+    chunked_response: bool,
+    query_trace_context: Option<Cow<'static, str>>,
+    error_only_attributes: Vec<Key>,
+    ready_wait: bool,
+    baggage_attributes: Vec<(Key, Key)>,
+    response_age: bool,
+    forwarded_headers: Vec<HeaderName>,
+    response_error: bool,
+    bare_spans: bool,
+    content_language: bool,
+    request_sampler: Option<Arc<RequestSampler>>,
+    idempotency_key_header: Option<HeaderName>,
+}
+
+impl Default for Config {
+    #[allow(clippy::too_many_lines)]
+    fn default() -> Self {
+        Self {
+            classifier: Arc::new(ServerErrorsAsFailures::new()),
+            query: QueryRecording::default(),
+            propagation_warning: true,
+            query_param_count: false,
+            tunnel_spans: false,
+            static_attributes: Vec::new(),
+            tracer: None,
+            response_injection: ResponseInjection::default(),
+            traceresponse: false,
+            trace_id_header: None,
+            legacy_trace_id_header: None,
+            span_builder_hook: None,
+            stream_id: false,
+            trust_forwarded: false,
+            response_content_type: false,
+            async_attributes: None,
+            cache_hit: None,
+            user_agent: UserAgentRecording::default(),
+            request_start_time: None,
+            span_events: false,
+            max_span_name_len: None,
+            unix_peer: false,
+            span_namer: None,
+            status_text: false,
+            referer: RefererRecording::default(),
+            received_at_start_time: false,
+            schema_url: None,
+            #[cfg(feature = "metrics")]
+            metrics: false,
+            minimal_methods: Vec::new(),
+            #[cfg(feature = "metrics")]
+            rejection_metric: false,
+            accept_encoding: false,
+            accept: false,
+            content_encoding: false,
+            record_url: true,
+            url_recording: UrlRecording::default(),
+            client_ip: ClientIpRecording::default(),
+            request_id_header: None,
+            request_id_generator: None,
+            duration_attribute: None,
+            trusted_hops: 0,
+            ok_status: false,
+            structured_exceptions: false,
+            retry: None,
+            span_kind: SpanKind::Server,
+            link_header: None,
+            excluded_paths: Vec::new(),
+            excluded_methods: Vec::new(),
+            #[cfg(feature = "metrics")]
+            exclusion_metric: false,
+            #[cfg(feature = "metrics")]
+            abandonment_metric: false,
+            #[cfg(feature = "metrics")]
+            metric_method_original: false,
+            minimal_attributes: false,
+            #[cfg(feature = "grpc")]
+            grpc: false,
+            custom_attribute_prefix: None,
+            http2_negotiation: false,
+            tls_server_name: false,
+            http2_priority: false,
+            http2_pushed: false,
+            status_class: false,
+            invalid_value_policy: InvalidValuePolicy::default(),
+            host_authority: false,
+            invalid_host_marker: false,
+            switch: None,
+            deferred_span_end: false,
+            request_context: false,
+            trace_continuation: TraceContinuation::default(),
+            upstream_continuation: false,
+            #[cfg(feature = "tracing")]
+            tracing_span: false,
+            propagator: None,
+            route_matched: false,
+            tail_policy: tail::Policy::default(),
+            inner_service_type: false,
+            request_attributes: None,
+            clock_skew_attributes: false,
+            attach_context: true,
+            attribute_keys: HashMap::new(),
+            sampler: None,
+            route_overrides: Vec::new(),
+            attribute_extractors: Vec::new(),
+            on_request: None,
+            on_response: None,
+            error_types: Vec::new(),
+            span_limit: None,
+            reload: None,
+            chunked_response: false,
+            query_trace_context: None,
+            error_only_attributes: Vec::new(),
+            ready_wait: false,
+            baggage_attributes: Vec::new(),
+            response_age: false,
+            forwarded_headers: Vec::new(),
+            response_error: false,
+            bare_spans: false,
+            content_language: false,
+            request_sampler: None,
+            idempotency_key_header: None,
+        }
+    }
+}
+
+impl Config {
+    /// The configuration of a [`Service`] wrapping an `S`.
+    fn for_service<S>(mut self) -> Arc<Self> {
+        if self.inner_service_type && !self.static_attributes.iter().any(|kv| kv.key == TOWER_SERVICE) {
+            self.set_static_attribute(TOWER_SERVICE.string(std::any::type_name::<S>()));
+        }
+        Arc::new(self)
+    }
+
+    /// Whether `req` is excluded from tracing.
+    fn is_excluded<B>(&self, req: &Request<B>) -> bool {
+        if self.excluded_methods.contains(req.method()) {
+            return true;
+        }
+        let path = req.uri().path();
+        self.excluded_paths.iter().any(|excluded| excluded == path)
+    }
+
+    /// Apply the custom attribute prefix, if any, to `attribute`.
+    fn custom_attribute(&self, attribute: KeyValue) -> KeyValue {
+        match &self.custom_attribute_prefix {
+            Some(prefix) => KeyValue::new(format!("{prefix}{}", attribute.key.as_str()), attribute.value),
+            None => attribute,
+        }
+    }
+
+    /// Store `decision`, from [`Layer::with_request_sampler`], or else the decision of the
+    /// layer's sampler, if any, in `builder`.
+    ///
+    /// The layer's sampler needs the trace id of a root span before `tracer` builds it, so it is
+    /// taken from the id generator of the tracer's provider and kept in `builder`.
+    fn sample(
+        &self,
+        tracer: &BoxedTracer,
+        builder: &mut SpanBuilder,
+        parent: &Context,
+        decision: Option<SamplingDecision>,
+    ) {
+        if let Some(decision) = decision {
+            builder.sampling_result = Some(SamplingResult {
+                decision,
+                attributes: Vec::new(),
+                trace_state: parent.span().span_context().trace_state().clone(),
+            });
+            return;
+        }
+        let Some(sampler) = &self.sampler else {
+            return;
+        };
+        let trace_id = if parent.has_active_span() {
+            parent.span().span_context().trace_id()
+        } else if let Some(trace_id) = builder.trace_id {
+            trace_id
+        } else {
+            // Without the provider's generator, leave the span's id to the tracer rather than
+            // replace a custom generator with the default one.
+            match tracer.provider_trace_id() {
+                Some(trace_id) => *builder.trace_id.insert(trace_id),
+                None => tracer.new_trace_id(),
+            }
+        };
+        builder.sampling_result = Some(sampler.should_sample(
+            Some(parent),
+            trace_id,
+            &builder.name,
+            builder.span_kind.as_ref().unwrap_or(&SpanKind::Server),
+            builder.attributes.as_deref().unwrap_or_default(),
+            builder.links.as_deref().unwrap_or_default(),
+            &tracer::instrumentation_library(),
+        ));
+    }
+
+    /// The [`MatchedRoute`] of `req`, kept to apply [`Layer::with_route_override`] when its span
+    /// ends, or `None` when no overrides are configured.
+    fn request_route<B>(&self, req: &Request<B>) -> Option<MatchedRoute> {
+        MatchedRoute::from_extensions(req.extensions()).filter(|_| !self.route_overrides.is_empty())
+    }
+
+    /// Apply the [`Layer::with_route_override`] for `route`, if any, to the span in `cx`.
+    fn override_route(&self, cx: &Context, route: Option<MatchedRoute>) {
+        let Some(MatchedRoute(route)) = route else {
+            return;
+        };
+        if let Some((_, route_override)) = self.route_overrides.iter().find(|(matched, _)| *matched == route) {
+            route_override.apply(&cx.span());
+        }
+    }
+
+    /// Rename the key of `attribute` if [`Layer::with_attribute_keys`] maps it.
+    fn remap_key(&self, attribute: KeyValue) -> KeyValue {
+        match self.attribute_keys.get(&attribute.key) {
+            Some(key) => KeyValue::new(key.clone(), attribute.value),
+            None => attribute,
+        }
+    }
+
+    /// Add `attribute` to every span, replacing any earlier value for the same key.
+    fn set_static_attribute(&mut self, attribute: KeyValue) {
+        self.static_attributes.retain(|kv| kv.key != attribute.key);
+        self.static_attributes.push(attribute);
+    }
+
+    /// Call `f` with the layer's propagator, or else the global one.
+    fn with_text_map_propagator<T>(&self, mut f: impl FnMut(&dyn TextMapPropagator) -> T) -> T {
+        match &self.propagator {
+            Some(propagator) => f(propagator.as_ref()),
+            None => global::get_text_map_propagator(f),
+        }
+    }
+
+    /// Extract the remote parent context from the request headers.
+    ///
+    /// Extraction starts from an empty context rather than the current one, so when no valid
+    /// remote parent is found the span starts a fresh root instead of parenting onto whatever
+    /// context happens to be current. Extracted baggage is kept either way, and so are the
+    /// headers of [`Layer::with_forwarded_headers`], whether or not a span is built.
+    fn extract_parent<B>(&self, req: &mut Request<B>) -> Context {
+        let extracted = self.extract_remote(req);
+        client::ForwardedHeaders::capture(req.headers(), &self.forwarded_headers, extracted)
+    }
+
+    /// Extract the remote context from the request headers, or else from the query string.
+    fn extract_remote<B>(&self, req: &mut Request<B>) -> Context {
+        let query = self
+            .query_trace_context
+            .as_deref()
+            .and_then(|name| query_param(req.uri(), name))
+            .map(str::to_string);
+        let headers = req.headers_mut();
+        self.with_text_map_propagator(|propagator| {
+            if self.propagation_warning && self.propagator.is_none() {
+                PROPAGATION_CHECK.call_once(|| {
+                    if propagator.fields().next().is_none() {
+                        global::handle_error(global::Error::Other(
+                            "no text map propagator is installed, so tower-opentelemetry cannot \
+                             continue inbound traces; see opentelemetry::global::set_text_map_propagator"
+                                .to_string(),
+                        ));
+                    }
+                });
+            }
+            let extracted = propagator.extract_with_context(&Context::new(), &HeaderCarrier::new(headers));
+            match &query {
+                Some(traceparent) if !extracted.span().span_context().is_valid() => {
+                    let carrier = HashMap::from([("traceparent".to_string(), traceparent.clone())]);
+                    propagator.extract_with_context(&extracted, &carrier)
+                }
+                _ => extracted,
+            }
+        })
+    }
+
+    /// Parse the trace id from the legacy trace id header, if one is configured and present.
+    fn legacy_trace_id(&self, headers: &http::HeaderMap) -> Option<TraceId> {
+        let value = headers.get(self.legacy_trace_id_header.as_ref()?)?.to_str().ok()?;
+        let hex: String = value.trim().chars().filter(|c| *c != '-').collect();
+        if hex.len() != 32 || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return None;
+        }
+        TraceId::from_hex(&hex).ok().filter(|trace_id| *trace_id != TraceId::INVALID)
+    }
+
+    /// The span name for `req`, from the [`Layer::with_span_name`] closure if set and otherwise
+    /// from the request path.
+    fn span_name<B>(&self, req: Request<B>) -> (Request<B>, Cow<'static, str>) {
+        let (req, name) = if let Some(namer) = &self.span_namer {
+            with_parts(req, |parts| namer(parts))
+        } else {
+            let uri = req.uri();
+            let name = match self.query {
+                QueryRecording::TargetAndName => uri
+                    .path_and_query()
+                    .map_or_else(|| uri.path(), |pq| pq.as_str())
+                    .to_string(),
+                QueryRecording::Target | QueryRecording::Omit => uri.path().to_string(),
+            };
+            (req, name.into())
+        };
+        match self.max_span_name_len {
+            Some(max) => (req, truncate_span_name(name, max)),
+            None => (req, name),
+        }
+    }
+
+    /// The `http.request.start_time` attribute for a request received at `received_at`, if enabled.
+    fn start_time_attribute(&self, received_at: SystemTime) -> Option<KeyValue> {
+        let value = match self.request_start_time? {
+            StartTimeFormat::Rfc3339 => Value::from(humantime::format_rfc3339_nanos(received_at).to_string()),
+            StartTimeFormat::EpochNanos => {
+                let nanos = received_at.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+                Value::I64(i64::try_from(nanos).unwrap_or(i64::MAX))
+            }
+        };
+        Some(KeyValue::new(HTTP_REQUEST_START_TIME, value))
+    }
+
+    /// The request URL to record as [`HTTP_URL`], as chosen by [`Layer::with_url_recording`].
+    fn url<B>(&self, req: &Request<B>) -> String {
+        let uri = req.uri();
+        match self.url_recording {
+            UrlRecording::Full => uri.to_string(),
+            UrlRecording::NoQuery => {
+                match (forwarded::scheme(req, self.trust_forwarded), forwarded::authority(req)) {
+                    (Some(scheme), Some(authority)) => format!("{scheme}://{authority}{}", uri.path()),
+                    _ => uri.path().to_string(),
+                }
+            }
+            UrlRecording::PathOnly => uri.path().to_string(),
+        }
+    }
+
+    fn request_attributes<B>(&self, req: &Request<B>) -> Vec<KeyValue> {
+        if self.bare_spans {
+            let mut attributes = self.static_attributes.clone();
+            self.opt_in_header_attributes(req.headers(), &mut attributes);
+            return attributes;
+        }
+        let uri = req.uri();
+        let mut attributes = Vec::with_capacity(11 + self.static_attributes.len());
+        attributes.extend(self.static_attributes.iter().cloned());
+        if req.method() == Method::CONNECT {
+            attributes.push(HTTP_TUNNEL.bool(true));
+        }
+        let method = http_method_str(req.method());
+        if method != req.method().as_str() {
+            attributes.push(HTTP_REQUEST_METHOD_ORIGINAL.string(req.method().to_string()));
+        }
+        attributes.push(HTTP_METHOD.string(method));
+        if self.route_matched {
+            attributes.push(HTTP_ROUTE_MATCHED.bool(req.extensions().get::<MatchedRoute>().is_some()));
+        }
+        if self.minimal_attributes {
+            if let Some(MatchedRoute(route)) = MatchedRoute::from_extensions(req.extensions()) {
+                attributes.push(HTTP_ROUTE.string(route));
+            }
+            return attributes;
+        }
+        if self.minimal_methods.contains(req.method()) {
+            return attributes;
+        }
+        attributes.push(HTTP_FLAVOR.string(http_flavor(req.version())));
+        if self.record_url {
+            attributes.push(HTTP_URL.string(self.url(req)));
+        }
+
+        if let Some(host_name) = SYSTEM.host_name() {
+            attributes.push(NET_HOST_NAME.string(host_name));
+        }
+
+        match self.query {
+            QueryRecording::Target | QueryRecording::TargetAndName => {
+                if let Some(path) = uri.path_and_query() {
+                    attributes.push(HTTP_TARGET.string(path.as_str().to_string()));
+                }
+            }
+            QueryRecording::Omit => attributes.push(HTTP_TARGET.string(uri.path().to_string())),
+        }
+        if self.query_param_count {
+            if let Some(query) = uri.query() {
+                let count = query.split('&').filter(|param| !param.is_empty()).count();
+                attributes.push(HTTP_QUERY_PARAM_COUNT.i64(i64::try_from(count).unwrap_or(i64::MAX)));
+            }
+        }
+        let scheme = forwarded::scheme(req, self.trust_forwarded);
+        let authority = forwarded::authority(req);
+        if self.invalid_host_marker && authority.is_none() && forwarded::has_invalid_host(req) {
+            attributes.push(HTTP_HOST_INVALID.bool(true));
+        }
+        let port = forwarded::host_port(authority.as_ref(), scheme.as_deref());
+        if let Some(port) = port {
+            attributes.push(NET_HOST_PORT.i64(i64::from(port)));
+        }
+        if let Some(authority) = authority.filter(|_| self.host_authority) {
+            let host = match port {
+                Some(port) => format!("{}:{port}", authority.host()),
+                None => authority.host().to_string(),
+            };
+            attributes.push(HTTP_HOST.string(host));
+        }
+        if let Some(scheme) = scheme {
+            attributes.push(HTTP_SCHEME.string(scheme));
+        }
+        let info = req.extensions().get::<ConnectionInfo>();
+        let client_ip = forwarded::client_ip(req.headers(), info, self.trust_forwarded, self.trusted_hops);
+        if let Some(ip) = client_ip.and_then(|ip| self.client_ip.value(ip)) {
+            attributes.push(HTTP_CLIENT_IP.string(ip));
+        }
+        if let Some(info) = info {
+            if let Some(ip) = info.peer_addr.and_then(|addr| self.client_ip.value(addr.ip())) {
+                attributes.push(NET_PEER_IP.string(ip));
+            }
+            self.connection_attributes(req.version(), info, &mut attributes);
+        }
+        self.header_attributes(req.headers(), &mut attributes);
+        if let Some(MatchedRoute(route)) = MatchedRoute::from_extensions(req.extensions()) {
+            attributes.push(HTTP_ROUTE.string(route));
+        }
+        if let Some(RouterLabel(router)) = req.extensions().get::<RouterLabel>() {
+            attributes.push(HTTP_ROUTER.string(router.clone()));
+        }
+        if let Some(QueueDepth(depth)) = req.extensions().get::<QueueDepth>() {
+            attributes.push(HTTP_SERVER_QUEUE_DEPTH.i64(i64::try_from(*depth).unwrap_or(i64::MAX)));
+        }
+        #[cfg(feature = "grpc")]
+        if self.grpc {
+            attributes.extend(grpc::rpc_attributes(req).into_iter().flatten());
+        }
+        attributes
+    }
+
+    /// Record the enabled attributes derived from the request headers.
+    fn header_attributes(&self, headers: &http::HeaderMap, attributes: &mut Vec<KeyValue>) {
+        if let Some(user_agent) = header_str(headers, &header::USER_AGENT) {
+            match self.user_agent {
+                UserAgentRecording::Full => attributes.push(HTTP_USER_AGENT.string(user_agent.to_string())),
+                UserAgentRecording::Classified => {
+                    attributes.push(HTTP_USER_AGENT_CLASS.string(user_agent_class(user_agent)));
+                }
+                UserAgentRecording::Omit => {}
+            }
+        }
+        self.opt_in_header_attributes(headers, attributes);
+        if let Some(referer) = header_str(headers, &header::REFERER) {
+            match self.referer {
+                RefererRecording::Omit => {}
+                RefererRecording::WithoutQuery => {
+                    let end = referer.find(['?', '#']).unwrap_or(referer.len());
+                    attributes.push(HTTP_REQUEST_HEADER_REFERER.string(referer[..end].to_string()));
+                }
+                RefererRecording::Full => attributes.push(HTTP_REQUEST_HEADER_REFERER.string(referer.to_string())),
+            }
+        }
+    }
+
+    /// Record the request header attributes that are off unless explicitly enabled, which
+    /// [`Layer::with_bare_spans`] keeps.
+    fn opt_in_header_attributes(&self, headers: &http::HeaderMap, attributes: &mut Vec<KeyValue>) {
+        if self.accept_encoding {
+            if let Some(accept_encoding) = header_str(headers, &header::ACCEPT_ENCODING) {
+                attributes.push(HTTP_REQUEST_HEADER_ACCEPT_ENCODING.string(accept_encoding.to_string()));
+            }
+        }
+        if self.accept {
+            if let Some(accept) = header_str(headers, &header::ACCEPT) {
+                attributes.push(HTTP_REQUEST_HEADER_ACCEPT.string(accept.to_string()));
+            }
+        }
+        if let Some(name) = &self.idempotency_key_header {
+            attributes.push(HTTP_REQUEST_HAS_IDEMPOTENCY_KEY.bool(headers.contains_key(name)));
+        }
+        if let Some((name, is_retry)) = &self.retry {
+            if let Some(value) = header_str(headers, name) {
+                attributes.push(HTTP_REQUEST_IS_RETRY.bool(is_retry(value)));
+            }
+        }
+    }
+
+    /// Record the enabled attributes derived from the request's [`ConnectionInfo`].
+    fn connection_attributes(&self, version: Version, info: &ConnectionInfo, attributes: &mut Vec<KeyValue>) {
+        if self.stream_id && version == Version::HTTP_2 {
+            if let Some(stream_id) = info.http2_stream_id {
+                attributes.push(HTTP_STREAM_ID.i64(i64::from(stream_id)));
+            }
+        }
+        if self.http2_negotiation && version == Version::HTTP_2 {
+            if let Some(negotiation) = info.http2_negotiation {
+                attributes.push(NETWORK_PROTOCOL_NEGOTIATION.string(negotiation.as_str()));
+            }
+        }
+        if self.http2_priority && version == Version::HTTP_2 {
+            if let Some(priority) = info.http2_priority {
+                if let Some(weight) = priority.weight {
+                    attributes.push(HTTP2_PRIORITY_WEIGHT.i64(i64::from(weight)));
+                }
+                if let Some(dependency) = priority.stream_dependency {
+                    attributes.push(HTTP2_PRIORITY_STREAM_DEPENDENCY.i64(i64::from(dependency)));
+                }
+            }
+        }
+        if self.http2_pushed && version == Version::HTTP_2 {
+            if let Some(pushed) = info.http2_pushed {
+                attributes.push(HTTP2_PUSHED.bool(pushed));
+            }
+        }
+        if self.tls_server_name {
+            if let Some(name) = &info.tls_server_name {
+                attributes.push(TLS_SERVER_NAME.string(name.clone()));
+            }
+        }
+        if self.unix_peer && info.peer_addr.is_none() {
+            if let Some(peer) = &info.unix_peer {
+                if let Some(path) = &peer.path {
+                    attributes.push(NET_PEER_NAME.string(path.display().to_string()));
+                }
+                if let Some(uid) = peer.uid {
+                    attributes.push(PEER_UID.i64(i64::from(uid)));
+                }
+                if let Some(pid) = peer.pid {
+                    attributes.push(PEER_PID.i64(i64::from(pid)));
+                }
+            }
+        }
+    }
+
+    /// The request id from the configured header, generating and inserting one if it is missing.
+    fn request_id(&self, headers: &mut http::HeaderMap) -> Option<HeaderValue> {
+        let name = self.request_id_header.as_ref()?;
+        if let Some(id) = headers.get(name) {
+            return Some(id.clone());
+        }
+        let id = match &self.request_id_generator {
+            Some(generate) => generate(),
+            None => uuid::Uuid::new_v4().to_string(),
+        };
+        let id = HeaderValue::try_from(id).ok()?;
+        headers.insert(name.clone(), id.clone());
+        Some(id)
+    }
+
+    /// Echo the request id in the response, unless the inner service already set the header.
+    fn echo_request_id<ResBody>(&self, id: HeaderValue, res: &mut Response<ResBody>) {
+        if let Some(name) = &self.request_id_header {
+            res.headers_mut().entry(name).or_insert(id);
+        }
+    }
+
+    /// Record the time since `started` on the span in `cx` if [`Layer::with_duration_attribute`]
+    /// is enabled, along with the wall-clock timing since `started_at` if
+    /// [`Layer::with_clock_skew_attributes`] is.
+    fn record_duration(&self, cx: &Context, started: Instant, started_at: SystemTime) {
+        let elapsed = started.elapsed();
+        if self.clock_skew_attributes {
+            let span = cx.span();
+            let unix_nanos = |time: SystemTime| {
+                let nanos = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos();
+                i64::try_from(nanos).unwrap_or(i64::MAX)
+            };
+            span.set_attribute(self.remap_key(TIMING_MONOTONIC_DURATION_MS.f64(elapsed.as_secs_f64() * 1000.0)));
+            span.set_attribute(self.remap_key(TIMING_WALL_START.i64(unix_nanos(started_at))));
+            span.set_attribute(self.remap_key(TIMING_WALL_END.i64(unix_nanos(SystemTime::now()))));
+        }
+        let attribute = match self.duration_attribute {
+            Some(DurationUnit::Millis) => HTTP_SERVER_DURATION_MS.f64(elapsed.as_secs_f64() * 1000.0),
+            Some(DurationUnit::Nanos) => {
+                HTTP_SERVER_DURATION_NS.i64(i64::try_from(elapsed.as_nanos()).unwrap_or(i64::MAX))
+            }
+            None => return,
+        };
+        cx.span().set_attribute(self.remap_key(attribute));
+    }
+
+    /// Record `ready_wait` on the span in `cx`, if enabled.
+    fn record_ready_wait(&self, cx: &Context, ready_wait: Duration) {
+        if self.ready_wait {
+            let millis = ready_wait.as_secs_f64() * 1000.0;
+            cx.span().set_attribute(self.remap_key(HTTP_SERVER_READY_WAIT_MS.f64(millis)));
+        }
+    }
+
+    /// Record an error from the inner service on the span in `cx`.
+    fn record_error<E: StdError + 'static>(&self, cx: &Context, error: &E) {
+        let span = cx.span();
+        span.set_status(StatusCode::Error, format!("{error:?}"));
+        span.set_attribute(self.remap_key(ERROR_TYPE.string(self.error_type(error))));
+        record_error_only(cx);
+        let stacktrace = Backtrace::force_capture().to_string();
+        if self.structured_exceptions {
+            span.add_event(
+                "exception",
+                vec![
+                    EXCEPTION_TYPE.string(std::any::type_name::<E>()),
+                    EXCEPTION_MESSAGE.string(error.to_string()),
+                    EXCEPTION_STACKTRACE.string(stacktrace),
+                ],
+            );
+        } else {
+            span.record_exception_with_stacktrace(error, stacktrace);
+        }
+    }
+
+    /// The low-cardinality `error.type` of `error`, from the first error in its source chain
+    /// that a registered or built-in mapping recognizes, or else the type name of `E`.
+    fn error_type<E: StdError + 'static>(&self, error: &E) -> &'static str {
+        let mut source: Option<&(dyn StdError + 'static)> = Some(error);
+        while let Some(error) = source {
+            let mapped = self.error_types.iter().find_map(|map| map(error)).or_else(|| builtin_error_type(error));
+            if let Some(error_type) = mapped {
+                return error_type;
+            }
+            source = error.source();
+        }
+        std::any::type_name::<E>()
+    }
+
+    /// Pass `res` and the time since `started` to the response observer, if any.
+    fn observe_response<ResBody>(&self, res: Response<ResBody>, started: Instant) -> Response<ResBody> {
+        let Some(on_response) = &self.on_response else {
+            return res;
+        };
+        let (parts, body) = res.into_parts();
+        on_response(Some(&parts), started.elapsed());
+        Response::from_parts(parts, body)
+    }
+
+    /// Set the status of `span` from the [`ResponseFailure`] of `res`, its recorded
+    /// [`ResponseError`] or else its classification, returning whether it is an error.
+    fn record_class<ResBody>(&self, span: &SpanRef<'_>, res: &Response<ResBody>) -> bool {
+        let class = match (res.extensions().get::<ResponseFailure>(), self.response_error(res)) {
+            (Some(ResponseFailure(reason)), _) => ResponseClass::Failure(reason.clone()),
+            (None, Some(error)) => ResponseClass::Failure(error.message().to_string().into()),
+            (None, None) => self.classifier.classify_response(res.status(), res.headers()),
+        };
+        match class {
+            ResponseClass::Failure(reason) => {
+                span.set_status(StatusCode::Error, reason.into_owned());
+                return true;
+            }
+            ResponseClass::Success if self.ok_status => span.set_status(StatusCode::Ok, String::new()),
+            ResponseClass::Success => {}
+        }
+        false
+    }
+
+    /// The [`ResponseError`] of `res`, if [`Layer::with_response_error`] is enabled.
+    fn response_error<'a, ResBody>(&self, res: &'a Response<ResBody>) -> Option<&'a ResponseError> {
+        res.extensions().get::<ResponseError>().filter(|_| self.response_error)
+    }
+
+    /// Record the [`ResponseError`] of `res` on `span`, if enabled and the handler didn't mark the
+    /// response as not failed with a [`SpanStatus`] of `Ok` or `Unset`.
+    fn record_response_error<ResBody>(&self, span: &SpanRef<'_>, res: &Response<ResBody>) {
+        if matches!(res.extensions().get::<SpanStatus>(), Some(SpanStatus::Ok | SpanStatus::Unset)) {
+            return;
+        }
+        let Some(error) = self.response_error(res) else {
+            return;
+        };
+        span.set_attribute(self.remap_key(ERROR_TYPE.string(error.error_type().to_string())));
+        if let Some(count) = error.count() {
+            span.set_attribute(self.remap_key(ERROR_COUNT.i64(i64::try_from(count).unwrap_or(i64::MAX))));
+        }
+        span.add_event(
+            "exception",
+            vec![
+                EXCEPTION_TYPE.string(error.error_type().to_string()),
+                EXCEPTION_MESSAGE.string(error.message().to_string()),
+            ],
+        );
+    }
+
+    /// Move the attributes of [`Layer::with_error_only_attributes`] out of `builder`.
+    fn hold_back_error_only(&self, builder: &mut SpanBuilder) -> Vec<KeyValue> {
+        let Some(attributes) = builder.attributes.as_mut().filter(|_| !self.error_only_attributes.is_empty()) else {
+            return Vec::new();
+        };
+        let (held_back, kept) =
+            attributes.drain(..).partition(|attribute| self.error_only_attributes.contains(&attribute.key));
+        *attributes = kept;
+        held_back
+    }
+
+    /// Inject the span context into the response to a request of `version`, which is a `HEAD`
+    /// request if `is_head`, and record its outcome on the span.
+    fn record_response<ResBody>(&self, cx: &Context, res: &mut Response<ResBody>, version: Version, is_head: bool) {
+        let span = cx.span();
+        let inject = match self.response_injection {
+            ResponseInjection::Disabled => false,
+            ResponseInjection::Sampled => span.span_context().is_sampled(),
+            ResponseInjection::Always => true,
+        };
+        if inject {
+            self.with_text_map_propagator(|propagator| {
+                let mut carrier = HeaderCarrier::new(res.headers_mut()).with_policy(self.invalid_value_policy);
+                propagator.inject_context(cx, &mut carrier);
+            });
+            self.inject_trace_headers(span.span_context(), res.headers_mut());
+        }
+        span.set_attribute(self.remap_key(HTTP_STATUS_CODE.i64(i64::from(res.status().as_u16()))));
+        tail::ResponseStatus::set(cx, res.status());
+        let is_error = match res.extensions().get::<SpanStatus>() {
+            Some(SpanStatus::Unset) => false,
+            Some(SpanStatus::Ok) => {
+                span.set_status(StatusCode::Ok, String::new());
+                false
+            }
+            Some(SpanStatus::Error(reason)) => {
+                span.set_status(StatusCode::Error, reason.clone().into_owned());
+                true
+            }
+            None => self.record_class(&span, res),
+        };
+        self.record_response_error(&span, res);
+        if is_error {
+            record_error_only(cx);
+        }
+        if let Some(MatchedRoute(route)) = MatchedRoute::from_extensions(res.extensions()) {
+            span.set_attribute(self.remap_key(HTTP_ROUTE.string(route)));
+            if self.route_matched {
+                span.set_attribute(self.remap_key(HTTP_ROUTE_MATCHED.bool(true)));
+            }
+        }
+        if let Some(RouterLabel(router)) = res.extensions().get::<RouterLabel>() {
+            span.set_attribute(self.remap_key(HTTP_ROUTER.string(router.clone())));
+        }
+        if self.status_text {
+            if let Some(reason) = res.status().canonical_reason() {
+                span.set_attribute(self.remap_key(HTTP_STATUS_TEXT.string(reason)));
+            }
+        }
+        if self.status_class {
+            let class = match res.status().as_u16() / 100 {
+                1 => Some("1xx"),
+                2 => Some("2xx"),
+                3 => Some("3xx"),
+                4 => Some("4xx"),
+                5 => Some("5xx"),
+                _ => None,
+            };
+            if let Some(class) = class {
+                span.set_attribute(self.remap_key(HTTP_STATUS_CLASS.string(class)));
+            }
+        }
+        if self.content_encoding {
+            if let Some(content_encoding) = header_str(res.headers(), &header::CONTENT_ENCODING) {
+                span.set_attribute(self.remap_key(HTTP_RESPONSE_HEADER_CONTENT_ENCODING.string(content_encoding.to_string())));
+            }
+        }
+        if self.content_language {
+            if let Some(content_language) = header_str(res.headers(), &header::CONTENT_LANGUAGE) {
+                span.set_attribute(self.remap_key(HTTP_RESPONSE_HEADER_CONTENT_LANGUAGE.string(content_language.to_string())));
+            }
+        }
+        if self.response_age {
+            if let Some(age) = header_str(res.headers(), &header::AGE).and_then(|age| age.trim().parse::<u64>().ok()) {
+                let age = i64::try_from(age).unwrap_or(i64::MAX);
+                span.set_attribute(self.remap_key(HTTP_RESPONSE_HEADER_AGE.i64(age)));
+            }
+        }
+        if self.chunked_response && version < Version::HTTP_2 {
+            span.set_attribute(self.remap_key(HTTP_RESPONSE_CHUNKED.bool(is_chunked(res.status(), res.headers(), version, is_head))));
+        }
+        if self.response_content_type {
+            if let Some(content_type) = header_str(res.headers(), &header::CONTENT_TYPE) {
+                span.set_attribute(self.remap_key(HTTP_RESPONSE_CONTENT_TYPE.string(content_type.to_string())));
+            }
+        }
+        if let Some((name, is_hit)) = &self.cache_hit {
+            if let Some(value) = header_str(res.headers(), name) {
+                span.set_attribute(self.remap_key(CACHE_HIT.bool(is_hit(value))));
+            }
+        }
+    }
+
+    /// Add the configured trace echo headers for `span_context` to the response `headers`.
+    fn inject_trace_headers(&self, span_context: &SpanContext, headers: &mut http::HeaderMap) {
+        if !span_context.is_valid() {
+            return;
+        }
+        if self.traceresponse {
+            let value = format!(
+                "00-{:032x}-{:016x}-{:02x}",
+                span_context.trace_id(),
+                span_context.span_id(),
+                span_context.trace_flags() & TraceFlags::SAMPLED
+            );
+            headers.insert(
+                TRACERESPONSE.clone(),
+                HeaderValue::from_str(&value).expect("hex digits are a valid header value"),
+            );
+        }
+        if let Some(name) = &self.trace_id_header {
+            let value = format!("{:032x}", span_context.trace_id());
+            headers.insert(
+                name.clone(),
+                HeaderValue::from_str(&value).expect("hex digits are a valid header value"),
+            );
+        }
+    }
+
+    /// Record the resolved [`Layer::with_async_attributes`] on the current span.
+    fn record_async_attributes(&self, attributes: Vec<KeyValue>) {
+        let cx = Context::current();
+        let span = cx.span();
+        span.add_event(ASYNC_ATTRIBUTES_EVENT, Vec::new());
+        for attribute in attributes {
+            span.set_attribute(self.custom_attribute(attribute));
+        }
+    }
+}
+
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config").finish_non_exhaustive()
     }
 }
 
 /// Middleware [`Service`] that propagates the opentelemetry trace header, configures a span for
 /// the request, and records any exceptions.
 ///
+/// The span lasts until the future returned by `call` resolves, so it covers all the work the
+/// inner service's future awaits. A future dropped before resolving still ends its span.
+///
+/// The inner service and request body must be `Send + 'static`, because with
+/// [`Layer::with_async_attributes`] the inner service is only called from the returned future,
+/// and the inner service's error must be `'static`, so that it can be downcast to classify its
+/// `error.type`, see [`Layer::with_error_type`]. These are breaking changes from earlier
+/// releases, which accepted non-`Send` services and bodies and borrowed errors.
+///
 /// [`Service`]: tower_service::Service
 #[derive(Clone)]
 pub struct Service<S: Clone> {
     inner: S,
-    tracer: Arc<global::BoxedTracer>,
+    tracer: TracerHandle,
+    config: Arc<Config>,
+    #[cfg(feature = "metrics")]
+    metrics: Option<metrics::Metrics>,
+    #[cfg(feature = "metrics")]
+    readiness: Option<metrics::PendingReadiness>,
+    /// The handle of [`Layer::with_reload_handle`] and the last generation applied.
+    reload: Option<(ReloadHandle, u64)>,
+    /// When `poll_ready` first returned `Pending` since the last call.
+    pending_since: Option<Instant>,
 }
 
 impl<S> Service<S> where S: Clone {
-    fn new(inner: S) -> Self {
+    /// Create a [`Service`] wrapping `inner` with the configuration of `layer`, for middleware
+    /// stacks composed by hand.
+    ///
+    /// ```
+    /// # use std::convert::Infallible;
+    /// let handler = tower::service_fn(|_req: http::Request<()>| async {
+    ///     Ok::<_, Infallible>(http::Response::new(()))
+    /// });
+    /// let service = tower_opentelemetry::Service::new(handler, &tower_opentelemetry::Layer::new());
+    /// ```
+    ///
+    /// This is the same as `layer.layer(inner)`, which remains the usual way to build the service
+    /// as part of a [`tower::ServiceBuilder`].
+    ///
+    /// [`tower::ServiceBuilder`]: https://docs.rs/tower/latest/tower/struct.ServiceBuilder.html
+    #[must_use]
+    pub fn new(inner: S, layer: &Layer) -> Self {
+        tower_layer::Layer::layer(layer, inner)
+    }
+
+    fn with_config(inner: S, config: Arc<Config>, reload: Option<(ReloadHandle, u64)>) -> Self {
         Self {
             inner,
-            tracer: Arc::new(global::tracer_provider().versioned_tracer("tower-opentelemetry", Some(env!("CARGO_PKG_VERSION")), None)),
+            reload,
+            pending_since: None,
+            tracer: Self::tracer_for(&config),
+            #[cfg(feature = "metrics")]
+            metrics: config.metrics.then(metrics::Metrics::global),
+            #[cfg(feature = "metrics")]
+            readiness: None,
+            config,
+        }
+    }
+
+    /// The tracer of `config`, or the global tracer if none is configured.
+    fn tracer_for(config: &Config) -> TracerHandle {
+        TracerHandle(config.tracer.clone().unwrap_or_else(|| BoxedTracer::global(config.schema_url)))
+    }
+
+    /// Stop waiting for readiness as the request is passed to `call`, returning how long the
+    /// caller waited, and pick up any reloaded configuration for the request.
+    fn finish_readiness(&mut self) -> Duration {
+        #[cfg(feature = "metrics")]
+        if let Some(readiness) = self.readiness.take() {
+            readiness.complete();
+        }
+        let ready_wait = self.pending_since.take().map(|since| since.elapsed()).unwrap_or_default();
+        self.reload_config();
+        ready_wait
+    }
+
+    /// Switch to the configuration of the latest [`ReloadHandle::reload`], if it hasn't been
+    /// applied yet.
+    ///
+    /// Only the configuration and what is derived from it are replaced: the inner service was
+    /// polled ready for this call, and a clone of it might not be.
+    fn reload_config(&mut self) {
+        let Some((handle, applied)) = &self.reload else {
+            return;
+        };
+        let generation = handle.generation();
+        if generation == *applied {
+            return;
+        }
+        let Some(config) = handle.config() else {
+            return;
+        };
+        self.reload = Some((handle.clone(), generation));
+        let config = config.for_service::<S>();
+        self.tracer = Self::tracer_for(&config);
+        #[cfg(feature = "metrics")]
+        {
+            self.metrics = config.metrics.then(metrics::Metrics::global);
+        }
+        self.config = config;
+    }
+
+    /// Whether `req` is passed straight through to the inner service because tracing is switched
+    /// off or the request is excluded.
+    fn is_untraced<B>(&self, req: &Request<B>) -> bool {
+        if self.config.switch.as_ref().is_some_and(|switch| !switch.is_enabled()) {
+            return true;
+        }
+        if !self.config.is_excluded(req) {
+            return false;
+        }
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics.as_ref().filter(|_| self.config.exclusion_metric) {
+            metrics.excluded(req);
+        }
+        true
+    }
+
+    /// Call the inner service with `req` without building a span, in the parent context
+    /// extracted from it, so outbound requests still continue the trace and forward headers.
+    fn call_untraced<B>(&mut self, mut req: Request<B>) -> Pin<Box<CF<S::Response, S::Error>>>
+    where
+        S: tower_service::Service<Request<B>>,
+        S::Future: Send + 'static,
+    {
+        let cx = self.config.extract_parent(&mut req);
+        let _attachment = cx.clone().attach();
+        Box::pin(self.inner.call(req).with_context(cx))
+    }
+
+    /// Count the span of `req` as active, or return `None` if [`Layer::with_max_active_spans`]
+    /// spans are already active.
+    fn span_permit<B>(&self, req: &Request<B>) -> Option<SpanPermit> {
+        let Some(limit) = &self.config.span_limit else {
+            return Some(SpanPermit::unlimited());
+        };
+        let permit = limit.acquire();
+        #[cfg(feature = "metrics")]
+        if let Some(metrics) = self.metrics.as_ref().filter(|_| permit.is_none()) {
+            metrics.span_limited(req);
+        }
+        #[cfg(not(feature = "metrics"))]
+        let _ = req;
+        permit
+    }
+
+    /// Build the span for `req`, returning the request, the context holding the span and the
+    /// request id, if enabled.
+    fn start_span<B>(
+        &self,
+        mut req: Request<B>,
+        decision: Option<SamplingDecision>,
+    ) -> (Request<B>, Context, Option<HeaderValue>) {
+        let called_at = SystemTime::now();
+        let received_at = match req.extensions().get::<ReceivedAt>() {
+            Some(ReceivedAt(received_at)) if self.config.received_at_start_time => Some(*received_at),
+            _ => None,
+        };
+        let extracted = self.config.extract_parent(&mut req);
+        let remote = extracted.span().span_context().clone();
+        let parent_context = match self.config.trace_continuation {
+            TraceContinuation::Child => extracted,
+            TraceContinuation::NewRootLinked | TraceContinuation::AlwaysNewRoot => without_span(&extracted),
+        };
+        let request_id = self.config.request_id(req.headers_mut());
+        let (with_req, name) = self.config.span_name(req);
+        req = with_req;
+        let tracer = req
+            .extensions()
+            .get::<TracerHandle>()
+            .unwrap_or(&self.tracer)
+            .clone();
+        let mut builder = tracer.span_builder(name).with_kind(self.config.span_kind.clone());
+        if self.config.trace_continuation == TraceContinuation::Child && !remote.is_valid() {
+            builder.trace_id = self.config.legacy_trace_id(req.headers());
+        }
+        let mut links = Vec::new();
+        if self.config.trace_continuation == TraceContinuation::NewRootLinked && remote.is_valid() {
+            links.push(Link::new(remote.clone(), Vec::new()));
+        }
+        if let Some((name, max_links)) = &self.config.link_header {
+            links.extend(links::from_headers(req.headers(), name, *max_links, &remote));
+        }
+        if !links.is_empty() {
+            builder.links = Some(links);
+        }
+        let mut attributes = match &self.config.request_attributes {
+            Some(compute) => {
+                let (with_req, attributes) = with_parts(req, |parts| compute(parts));
+                req = with_req;
+                attributes
+            }
+            None => self.config.request_attributes(&req),
+        };
+        attributes.extend(self.config.start_time_attribute(received_at.unwrap_or(called_at)));
+        builder.start_time = received_at;
+        if let Some(id) = request_id.as_ref().and_then(|id| id.to_str().ok()) {
+            attributes.push(HTTP_REQUEST_ID.string(id.to_string()));
+        }
+        if self.config.upstream_continuation {
+            let continued = remote.is_valid() && self.config.trace_continuation == TraceContinuation::Child;
+            attributes.push(TRACE_CONTINUED_FROM_UPSTREAM.bool(continued));
+        }
+        let baggage = parent_context.baggage();
+        for (key, attribute) in &self.config.baggage_attributes {
+            if let Some(value) = baggage.get(key.clone()) {
+                attributes.push(KeyValue::new(attribute.clone(), value.clone()));
+            }
+        }
+        if !self.config.attribute_keys.is_empty() {
+            attributes = attributes.into_iter().map(|attribute| self.config.remap_key(attribute)).collect();
+        }
+        let layer_attributes = attributes.len();
+        if !self.config.attribute_extractors.is_empty() {
+            let extractors = &self.config.attribute_extractors;
+            let (with_req, extracted) = with_parts(req, |parts| {
+                extractors.iter().flat_map(|extract| extract(parts)).collect::<Vec<_>>()
+            });
+            req = with_req;
+            attributes.extend(extracted);
+        }
+        builder.attributes = if attributes.is_empty() { None } else { Some(attributes) };
+        if let Some(hook) = &self.config.span_builder_hook {
+            req = with_parts(req, |parts| hook(parts, &mut builder)).0;
+        }
+        if self.config.custom_attribute_prefix.is_some() {
+            if let Some(attributes) = &mut builder.attributes {
+                for attribute in attributes.iter_mut().skip(layer_attributes) {
+                    *attribute = self.config.custom_attribute(attribute.clone());
+                }
+            }
+        }
+        let error_only = self.config.hold_back_error_only(&mut builder);
+        self.config.sample(&tracer.0, &mut builder, &parent_context, decision);
+        let cx = if self.config.tail_policy.is_enabled() {
+            tail::start(&tracer.0, builder, &parent_context, self.config.tail_policy.clone())
+        } else {
+            tracer.build_with_context(builder, &parent_context)
+        };
+        let cx = if error_only.is_empty() { cx } else { cx.with_value(ErrorOnlyAttributes(error_only)) };
+        (req, cx, request_id)
+    }
+
+    /// Run the request observer and the asynchronous attribute hook on `req` and insert the
+    /// enabled extensions for handlers, returning the request, the pending attributes and the
+    /// span events handle.
+    fn prepare_request<B>(
+        &self,
+        mut req: Request<B>,
+        cx: &Context,
+    ) -> (Request<B>, Option<BoxFuture<'static, Vec<KeyValue>>>, Option<SpanEvents>) {
+        if let Some(on_request) = &self.config.on_request {
+            req = with_parts(req, |parts| on_request(parts)).0;
+        }
+        let pending_attributes = match &self.config.async_attributes {
+            Some(hook) => {
+                let (with_req, pending) = with_parts(req, |parts| hook(parts));
+                req = with_req;
+                Some(pending)
+            }
+            None => None,
+        };
+        if self.config.request_context {
+            req.extensions_mut().insert(RequestContext::new(cx.clone()));
         }
+        let span_events = if self.config.span_events {
+            let events = SpanEvents::default();
+            req.extensions_mut().insert(events.clone());
+            Some(events)
+        } else {
+            None
+        };
+        (req, pending_attributes, span_events)
+    }
+
+    /// The tracer this [`Service`] builds its spans with, for creating related spans with the same
+    /// instrumentation scope.
+    #[must_use]
+    pub fn tracer(&self) -> &TracerHandle {
+        &self.tracer
+    }
+
+    /// Create a [`Service`] wrapping `inner` that builds its spans with a no-op tracer.
+    ///
+    /// This is the test-friendly entry point for crates that embed the middleware: no tracer
+    /// provider needs to be installed, and the service never touches the global tracer provider,
+    /// so tests stay hermetic regardless of which other tests configure OpenTelemetry. Requests
+    /// still pass through the full middleware logic, but the spans record nothing. Unlike a
+    /// [`Layer`] without [`Layer::with_propagator`], it doesn't use the global propagator either,
+    /// so no trace context is extracted from requests or injected into responses.
+    #[must_use]
+    pub fn noop(inner: S) -> Self {
+        let config = Config {
+            tracer: Some(BoxedTracer::from_provider(&NoopTracerProvider::new(), None)),
+            propagator: Some(Arc::new(TextMapCompositePropagator::new(Vec::new()))),
+            propagation_warning: false,
+            ..Config::default()
+        };
+        Self::with_config(inner, Arc::new(config), None)
     }
 }
 
@@ -99,9 +3265,9 @@ impl<B, ResBody, S> tower_service::Service<Request<B>> for Service<S>
 where
     S: tower_service::Service<Request<B>, Response = Response<ResBody>>,
     S::Future: 'static + Send,
-    B: 'static,
-    S::Error: std::fmt::Debug + StdError,
-    S: Clone
+    B: Send + 'static,
+    S::Error: std::fmt::Debug + StdError + 'static,
+    S: Clone + Send + 'static,
 {
     type Error = S::Error;
     type Future = Pin<Box<CF<Self::Response, Self::Error>>>;
@@ -109,81 +3275,118 @@ where
 
     #[inline]
     fn poll_ready(&mut self, cx: &mut std::task::Context<'_>) -> Poll<Result<(), Self::Error>> {
-        self.inner.poll_ready(cx)
+        let poll = self.inner.poll_ready(cx);
+        if poll.is_pending() && self.config.ready_wait {
+            self.pending_since.get_or_insert_with(Instant::now);
+        }
+        #[cfg(feature = "metrics")]
+        if poll.is_pending() && self.readiness.is_none() && self.config.abandonment_metric {
+            self.readiness = self.metrics.as_ref().map(metrics::Metrics::await_readiness);
+        }
+        poll
     }
 
-    fn call(&mut self, mut req: Request<B>) -> Self::Future {
-        let parent_context = opentelemetry::global::get_text_map_propagator(|propagator| {
-            propagator.extract(&HeaderCarrier::new(req.headers_mut()))
-        });
-        // let conn_info = req.connection_info();
-        let uri = req.uri();
-        let mut builder = self
-            .tracer
-            .span_builder(uri.path().to_string())
-            .with_kind(SpanKind::Server);
-        if let Some(trace_id) = parent_context.get::<TraceId>() {
-            builder = builder.with_trace_id(*trace_id);
-        }
-        if let Some(span_id) = parent_context.get::<SpanId>() {
-            builder = builder.with_span_id(*span_id);
-        }
-        let mut attributes = Vec::with_capacity(11);
-        attributes.push(HTTP_METHOD.string(http_method_str(req.method())));
-        attributes.push(HTTP_FLAVOR.string(http_flavor(req.version())));
-        attributes.push(HTTP_URL.string(uri.to_string()));
-
-        if let Some(host_name) = SYSTEM.host_name() {
-            attributes.push(NET_HOST_NAME.string(host_name));
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let ready_wait = self.finish_readiness();
+        if self.is_untraced(&req) {
+            return self.call_untraced(req);
         }
+        let (req, decision) = match &self.config.request_sampler {
+            Some(decide) => with_parts(req, |parts| Some(decide(parts))),
+            None => (req, None),
+        };
+        if decision == Some(SamplingDecision::Drop) {
+            return self.call_untraced(req);
+        }
+        let Some(permit) = self.span_permit(&req) else {
+            return self.call_untraced(req);
+        };
+        let (req, cx, request_id) = self.start_span(req, decision);
+        self.config.record_ready_wait(&cx, ready_wait);
+        let is_tunnel = req.method() == Method::CONNECT;
+        let version = req.version();
+        let is_head = req.method() == Method::HEAD;
+        let request_route = self.config.request_route(&req);
+        let attachment = self.config.attach_context.then(|| cx.clone().attach());
+        let (req, pending_attributes, span_events) = self.prepare_request(req, &cx);
+        #[cfg(feature = "tracing")]
+        let tracing_span =
+            if self.config.tracing_span { tracing_span::request_span(&req, &cx) } else { tracing::Span::none() };
+
+        #[cfg(feature = "metrics")]
+        let metrics = self.metrics.as_ref().map(|metrics| metrics.start(&req, self.config.metric_method_original));
 
-        if let Some(path) = uri.path_and_query() {
-            attributes.push(HTTP_TARGET.string(path.as_str().to_string()));
+        let started = Instant::now();
+        let started_at = SystemTime::now();
+        let config = self.config.clone();
+        let mut guard = SpanGuard::new(cx.clone()).with_permit(permit);
+        #[cfg(feature = "tracing")]
+        let response_tracing_span = tracing_span.clone();
+        let fut = match pending_attributes {
+            // Take the service that was driven to readiness and leave a clone in its place, so
+            // it is only called once the span carries the awaited attributes.
+            Some(pending) => {
+                let clone = self.inner.clone();
+                let mut inner = std::mem::replace(&mut self.inner, clone);
+                let async_config = config.clone();
+                Either::Left(async move {
+                    async_config.record_async_attributes(pending.await);
+                    inner.call(req).await
+                })
+            }
+            None => Either::Right(self.inner.call(req)),
         }
-        if let Some(user_agent) = req
-            .headers()
-            .get(header::USER_AGENT)
-            .and_then(|s| s.to_str().ok())
-        {
-            attributes.push(HTTP_USER_AGENT.string(user_agent.to_string()));
-        }
-        builder.attributes = Some(attributes);
-        let span = self.tracer.build(builder);
-        let cx = Context::current_with_span(span);
-        let attachment = cx.clone().attach();
-
-        let fut = self
-            .inner
-            .call(req)
-            .with_context(cx.clone())
-            .map(move |res| match res {
+        .with_context(cx)
+        .map(move |res| {
+            config.record_duration(guard.context(), started, started_at);
+            if let Some(events) = &span_events {
+                events.drain_into(guard.context());
+            }
+            match res {
                 Ok(mut ok_res) => {
-                    opentelemetry::global::get_text_map_propagator(|propagator| {
-                        propagator.inject(&mut HeaderCarrier::new(ok_res.headers_mut()));
-                    });
-                    let span = cx.span();
-                    span.set_attribute(HTTP_STATUS_CODE.i64(i64::from(ok_res.status().as_u16())));
-                    if ok_res.status().is_server_error() {
-                        span.set_status(
-                            StatusCode::Error,
-                            ok_res
-                                .status()
-                                .canonical_reason()
-                                .map(ToString::to_string)
-                                .unwrap_or_default(),
-                        );
-                    };
-                    span.end();
+                    let keep_tunnel = is_tunnel && config.tunnel_spans && ok_res.status().is_success();
+                    let defer = !keep_tunnel && config.deferred_span_end;
+                    #[cfg(feature = "metrics")]
+                    let body_metrics =
+                        metrics.and_then(|metrics| metrics.finish_response(&ok_res, config.rejection_metric, defer));
+                    ok_res = config.observe_response(ok_res, started);
+                    config.record_response(guard.context(), &mut ok_res, version, is_head);
+                    config.override_route(guard.context(), MatchedRoute::from_extensions(ok_res.extensions()).or(request_route));
+                    #[cfg(feature = "tracing")]
+                    tracing_span::record_response(&response_tracing_span, &ok_res);
+                    if let Some(id) = request_id {
+                        config.echo_request_id(id, &mut ok_res);
+                    }
+                    if keep_tunnel {
+                        ok_res.extensions_mut().insert(Tunnel::new(guard));
+                    } else if defer {
+                        let deferred = DeferredSpan::new(guard);
+                        #[cfg(feature = "metrics")]
+                        let deferred = deferred.with_metrics(body_metrics);
+                        ok_res.extensions_mut().insert(deferred);
+                    } else {
+                        guard.end();
+                    }
                     Ok(ok_res)
                 }
                 Err(error) => {
-                    let span = cx.span();
-                    span.set_status(StatusCode::Error, format!("{:?}", error));
-                    span.record_exception_with_stacktrace(&error, Backtrace::force_capture().to_string());
-                    span.end();
+                    #[cfg(feature = "metrics")]
+                    if let Some(metrics) = metrics {
+                        metrics.finish::<ResBody>(None, false);
+                    }
+                    config.record_error(guard.context(), &error);
+                    if let Some(on_response) = &config.on_response {
+                        on_response(None, started.elapsed());
+                    }
+                    config.override_route(guard.context(), request_route);
+                    guard.end();
                     Err(error)
                 }
-            });
+            }
+        });
+
+        #[cfg(feature = "tracing")]
+        let fut = tracing::Instrument::instrument(fut, tracing_span);
 
         drop(attachment);
         Box::pin(fut)
@@ -192,15 +3395,22 @@ where
 
 struct HeaderCarrier<'a> {
     headers: &'a mut http::HeaderMap,
+    policy: InvalidValuePolicy,
 }
 
 impl<'a> HeaderCarrier<'a> {
     fn new(headers: &'a mut http::HeaderMap) -> Self {
-        HeaderCarrier { headers }
+        HeaderCarrier { headers, policy: InvalidValuePolicy::Drop }
+    }
+
+    /// Apply `policy` to injected values that aren't valid in HTTP headers.
+    fn with_policy(mut self, policy: InvalidValuePolicy) -> Self {
+        self.policy = policy;
+        self
     }
 }
 
-impl<'a> Extractor for HeaderCarrier<'a> {
+impl Extractor for HeaderCarrier<'_> {
     fn get(&self, key: &str) -> Option<&str> {
         self.headers.get(key).and_then(|v| v.to_str().ok())
     }
@@ -210,14 +3420,26 @@ impl<'a> Extractor for HeaderCarrier<'a> {
     }
 }
 
-impl<'a> Injector for HeaderCarrier<'a> {
+impl Injector for HeaderCarrier<'_> {
+    /// Set the header `key`, skipping keys that aren't valid in HTTP headers or gRPC metadata and
+    /// handling invalid values according to the carrier's [`InvalidValuePolicy`]. Binary `-bin`
+    /// values must already be base64-encoded by the propagator.
     fn set(&mut self, key: &str, value: String) {
-        self.headers.insert(
-            HeaderName::from_bytes(key.as_bytes()).expect("invalid header name"),
-            HeaderValue::from_str(&value).expect("invalid header value"),
-        );
+        // Percent-encoding applies to every value, so a valid value with a `%` in it can't be
+        // mistaken for an encoded one.
+        let value = if self.policy != InvalidValuePolicy::PercentEncode && value.bytes().all(is_header_safe) {
+            HeaderValue::try_from(value).ok()
+        } else {
+            self.policy.encode(&value)
+        };
+        match (HeaderName::from_bytes(key.as_bytes()), value) {
+            (Ok(name), Some(value)) => {
+                self.headers.insert(name, value);
+            }
+            _ => global::handle_error(global::Error::Other(format!("skipped invalid propagation header {key:?}"))),
+        }
     }
 }
 
 #[cfg(test)]
-mod tests {}
+mod tests;
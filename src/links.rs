@@ -0,0 +1,53 @@
+//! Span links to the upstream operations a batched request stands for.
+//!
+//! A gateway that batches several upstream operations into one request can list their contexts
+//! in a header configured with [`Layer::with_link_header`]. Each value is one or more
+//! comma-separated contexts in the W3C `traceparent` format, and the header may be repeated:
+//!
+//! ```text
+//! x-batch-traceparents: 00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01, 00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00
+//! ```
+//!
+//! [`Layer::with_link_header`]: crate::Layer::with_link_header
+use std::collections::HashSet;
+
+use http::{HeaderMap, HeaderName};
+use opentelemetry::trace::{Link, SpanContext, SpanId, TraceFlags, TraceId, TraceState};
+
+/// Parse up to `max` distinct contexts from the `name` headers into links, skipping `parent` and
+/// any malformed entries.
+pub(crate) fn from_headers(headers: &HeaderMap, name: &HeaderName, max: usize, parent: &SpanContext) -> Vec<Link> {
+    let mut seen = HashSet::new();
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|entry| parse_traceparent(entry.trim()))
+        .filter(|cx| !(cx.trace_id() == parent.trace_id() && cx.span_id() == parent.span_id()))
+        .filter(|cx| seen.insert((cx.trace_id(), cx.span_id())))
+        .take(max)
+        .map(|cx| Link::new(cx, Vec::new()))
+        .collect()
+}
+
+/// Parse a `traceparent` value into a remote span context.
+fn parse_traceparent(value: &str) -> Option<SpanContext> {
+    let mut parts = value.split('-');
+    let (version, trace_id, span_id, flags) = (parts.next()?, parts.next()?, parts.next()?, parts.next()?);
+    if version.len() != 2 || version == "ff" || trace_id.len() != 32 || span_id.len() != 16 || flags.len() != 2 {
+        return None;
+    }
+    if version == "00" && parts.next().is_some() {
+        return None;
+    }
+    let flags = u8::from_str_radix(flags, 16).ok()?;
+    let cx = SpanContext::new(
+        TraceId::from_hex(trace_id).ok()?,
+        SpanId::from_hex(span_id).ok()?,
+        TraceFlags::new(flags) & TraceFlags::SAMPLED,
+        true,
+        TraceState::default(),
+    );
+    cx.is_valid().then_some(cx)
+}
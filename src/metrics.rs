@@ -0,0 +1,263 @@
+//! Request duration and count metrics, recorded alongside the spans.
+use std::{borrow::Cow, fmt, time::Instant};
+
+use http::{Request, Response};
+use opentelemetry::{
+    global,
+    metrics::{Counter, MeterProvider, Unit, ValueRecorder},
+    Key, KeyValue,
+};
+use opentelemetry_semantic_conventions::trace::{HTTP_METHOD, HTTP_ROUTE, HTTP_STATUS_CODE};
+
+use crate::{
+    http_flavor, http_method_str, MatchedRoute, ERROR_TYPE, ERROR_TYPE_CANCELLED, HTTP_REQUEST_METHOD_ORIGINAL,
+    OTHER_METHOD,
+};
+
+/// The HTTP version of the request, with the same values as `http.flavor`.
+const NETWORK_PROTOCOL_VERSION: Key = Key::from_static_str("network.protocol.version");
+
+/// Marks a response as produced by the handler rather than rejected on the way to it.
+///
+/// With [`Layer::with_rejection_metric`] enabled, error responses (`4xx` and `5xx`) that don't
+/// carry this marker in their extensions are counted as rejections in
+/// `http.server.rejected_requests`. Handlers, or the innermost layer wrapping them, insert it into
+/// every response they produce, so that errors returned by layers between the [`Service`] and the
+/// handler, such as authentication or rate limiting, show up separately.
+///
+/// ```
+/// let mut res = http::Response::new(());
+/// res.extensions_mut().insert(tower_opentelemetry::Handled);
+/// ```
+///
+/// [`Service`]: crate::Service
+/// [`Layer::with_rejection_metric`]: crate::Layer::with_rejection_metric
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Handled;
+
+/// The instruments a [`Service`] records to when [`Layer::with_metrics`] is enabled.
+///
+/// [`Service`]: crate::Service
+/// [`Layer::with_metrics`]: crate::Layer::with_metrics
+#[derive(Debug, Clone)]
+pub(crate) struct Metrics {
+    duration: ValueRecorder<f64>,
+    ttfb: ValueRecorder<f64>,
+    requests: Counter<u64>,
+    rejected: Counter<u64>,
+    excluded: Counter<u64>,
+    span_limited: Counter<u64>,
+    abandoned: Counter<u64>,
+}
+
+impl Metrics {
+    /// Create the instruments from the global meter provider.
+    pub(crate) fn global() -> Self {
+        let meter = global::meter_provider().meter("tower-opentelemetry", Some(env!("CARGO_PKG_VERSION")));
+        Self {
+            duration: meter
+                .f64_value_recorder("http.server.duration")
+                .with_description("Duration of inbound HTTP requests")
+                .with_unit(Unit::new("ms"))
+                .init(),
+            ttfb: meter
+                .f64_value_recorder("http.server.ttfb")
+                .with_description("Time until the response head of inbound HTTP requests was produced")
+                .with_unit(Unit::new("ms"))
+                .init(),
+            requests: meter
+                .u64_counter("http.server.requests")
+                .with_description("Number of inbound HTTP requests")
+                .init(),
+            rejected: meter
+                .u64_counter("http.server.rejected_requests")
+                .with_description("Number of inbound HTTP requests rejected before reaching the handler")
+                .init(),
+            excluded: meter
+                .u64_counter("tower_otel.excluded_requests")
+                .with_description("Number of inbound HTTP requests excluded from tracing")
+                .init(),
+            span_limited: meter
+                .u64_counter("tower_otel.span_limited_requests")
+                .with_description("Number of inbound HTTP requests not traced because too many spans were active")
+                .init(),
+            abandoned: meter
+                .u64_counter("tower_otel.abandoned_requests")
+                .with_description("Number of inbound HTTP requests abandoned while waiting for the service to be ready")
+                .init(),
+        }
+    }
+
+    /// Count `req` as excluded from tracing.
+    pub(crate) fn excluded<B>(&self, req: &Request<B>) {
+        self.excluded.add(1, &[HTTP_METHOD.string(http_method_str(req.method()))]);
+    }
+
+    /// Count `req` as not traced because too many spans were active.
+    pub(crate) fn span_limited<B>(&self, req: &Request<B>) {
+        self.span_limited.add(1, &[HTTP_METHOD.string(http_method_str(req.method()))]);
+    }
+
+    /// Start waiting for the service to become ready, counting an abandoned request unless
+    /// [`PendingReadiness::complete`] is called.
+    pub(crate) fn await_readiness(&self) -> PendingReadiness {
+        PendingReadiness(Some(self.clone()))
+    }
+
+    /// Start measuring `req`, keeping its raw method as a dimension if `method_original` and it
+    /// differs from the normalized one.
+    pub(crate) fn start<B>(&self, req: &Request<B>, method_original: bool) -> PendingRequest {
+        let method = http_method_str(req.method());
+        PendingRequest {
+            metrics: self.clone(),
+            start: Instant::now(),
+            method_original: (method_original && method != req.method().as_str()).then(|| req.method().to_string()),
+            method,
+            flavor: http_flavor(req.version()),
+            route: MatchedRoute::from_extensions(req.extensions()).map(|route| route.0),
+            finished: false,
+        }
+    }
+}
+
+/// A request being measured, recorded once its response is known, or as `cancelled` if it is
+/// dropped before then.
+pub(crate) struct PendingRequest {
+    metrics: Metrics,
+    start: Instant,
+    method: Cow<'static, str>,
+    method_original: Option<String>,
+    flavor: Cow<'static, str>,
+    route: Option<Cow<'static, str>>,
+    finished: bool,
+}
+
+impl PendingRequest {
+    /// Record the request with its response, or with no response when the inner service failed.
+    pub(crate) fn finish<ResBody>(mut self, res: Option<&Response<ResBody>>, count_rejection: bool) {
+        let attributes = self.attributes(res, count_rejection);
+        self.record(&attributes);
+    }
+
+    /// Record the time to the response head of `res` as `http.server.ttfb`, leaving the total
+    /// duration to be recorded once the body has been streamed.
+    pub(crate) fn finish_head<ResBody>(mut self, res: &Response<ResBody>, count_rejection: bool) -> PendingBody {
+        let attributes = self.attributes(Some(res), count_rejection);
+        self.metrics.ttfb.record(self.start.elapsed().as_secs_f64() * 1000.0, &attributes);
+        self.metrics.requests.add(1, &attributes);
+        self.finished = true;
+        PendingBody {
+            metrics: self.metrics.clone(),
+            start: self.start,
+            attributes,
+        }
+    }
+
+    /// Record the request with `res`, leaving the duration to be recorded with the returned
+    /// [`PendingBody`] once the body has been streamed if `defer`.
+    pub(crate) fn finish_response<ResBody>(
+        self,
+        res: &Response<ResBody>,
+        count_rejection: bool,
+        defer: bool,
+    ) -> Option<PendingBody> {
+        if defer {
+            return Some(self.finish_head(res, count_rejection));
+        }
+        self.finish(Some(res), count_rejection);
+        None
+    }
+
+    /// Record the duration and count of the request with `attributes`.
+    fn record(&mut self, attributes: &[KeyValue]) {
+        self.metrics.duration.record(self.start.elapsed().as_secs_f64() * 1000.0, attributes);
+        self.metrics.requests.add(1, attributes);
+        self.finished = true;
+    }
+
+    /// The attributes of the request and its response, counting a rejection if `count_rejection`.
+    ///
+    /// The route dimension is the [`MatchedRoute`] of the response or request, joined to its
+    /// [`RoutePrefix`](crate::RoutePrefix) when nested, or `_OTHER` when neither carries one, so
+    /// the raw path never becomes a metric attribute.
+    fn attributes<ResBody>(&self, res: Option<&Response<ResBody>>, count_rejection: bool) -> Vec<KeyValue> {
+        let route = res
+            .and_then(|res| MatchedRoute::from_extensions(res.extensions()))
+            .map(|route| route.0)
+            .or_else(|| self.route.clone())
+            .unwrap_or(Cow::Borrowed(OTHER_METHOD));
+        let mut attributes = vec![
+            HTTP_METHOD.string(self.method.clone()),
+            NETWORK_PROTOCOL_VERSION.string(self.flavor.clone()),
+            HTTP_ROUTE.string(route),
+        ];
+        if let Some(method) = &self.method_original {
+            attributes.push(HTTP_REQUEST_METHOD_ORIGINAL.string(method.clone()));
+        }
+        if let Some(res) = res {
+            let status = res.status();
+            attributes.push(HTTP_STATUS_CODE.i64(i64::from(status.as_u16())));
+            let is_error = status.is_client_error() || status.is_server_error();
+            if count_rejection && is_error && res.extensions().get::<Handled>().is_none() {
+                self.metrics.rejected.add(1, &attributes);
+            }
+        }
+        attributes
+    }
+}
+
+impl Drop for PendingRequest {
+    fn drop(&mut self) {
+        if !self.finished {
+            let mut attributes = self.attributes::<()>(None, false);
+            attributes.push(ERROR_TYPE.string(ERROR_TYPE_CANCELLED));
+            self.record(&attributes);
+        }
+    }
+}
+
+/// A request whose response head was recorded, waiting for its body to be streamed.
+pub(crate) struct PendingBody {
+    metrics: Metrics,
+    start: Instant,
+    attributes: Vec<KeyValue>,
+}
+
+impl PendingBody {
+    /// Record the time to the last byte of the body as `http.server.duration`.
+    pub(crate) fn finish(self) {
+        self.metrics.duration.record(self.start.elapsed().as_secs_f64() * 1000.0, &self.attributes);
+    }
+}
+
+impl fmt::Debug for PendingBody {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PendingBody").field("start", &self.start).finish_non_exhaustive()
+    }
+}
+
+/// A caller waiting for the service to become ready, counted in `tower_otel.abandoned_requests`
+/// if it is dropped before its request is passed to `call`.
+pub(crate) struct PendingReadiness(Option<Metrics>);
+
+impl PendingReadiness {
+    /// Mark the request as passed to `call`, so it isn't counted.
+    pub(crate) fn complete(mut self) {
+        self.0 = None;
+    }
+}
+
+impl Clone for PendingReadiness {
+    /// A clone of a service waiting for readiness isn't itself waiting, so it isn't counted.
+    fn clone(&self) -> Self {
+        Self(None)
+    }
+}
+
+impl Drop for PendingReadiness {
+    fn drop(&mut self) {
+        if let Some(metrics) = self.0.take() {
+            metrics.abandoned.add(1, &[]);
+        }
+    }
+}
@@ -0,0 +1,93 @@
+//! One-call setup for exporting the layer's spans and metrics over OTLP.
+//!
+//! [`init`] covers the common case of a service that exports everything to an OpenTelemetry
+//! collector. Services that need more control can keep configuring OpenTelemetry themselves and
+//! use [`Layer`] directly.
+#[cfg(feature = "metrics")]
+use std::{sync::Mutex, time::Duration};
+
+#[cfg(feature = "metrics")]
+use lazy_static::lazy_static;
+#[cfg(feature = "metrics")]
+use opentelemetry::{
+    runtime::Runtime,
+    sdk::{
+        metrics::PushController,
+        resource::{EnvResourceDetector, SdkProvidedResourceDetector},
+        Resource,
+    },
+    KeyValue,
+};
+use opentelemetry::{
+    global,
+    runtime::Tokio,
+    sdk::propagation::{BaggagePropagator, TextMapCompositePropagator, TraceContextPropagator},
+    trace::TraceError,
+};
+use opentelemetry_otlp::WithExportConfig;
+
+use crate::Layer;
+
+#[cfg(feature = "metrics")]
+lazy_static! {
+    /// The controller exporting the metrics, which stops exporting when dropped.
+    pub(crate) static ref METRICS: Mutex<Option<PushController>> = Mutex::new(None);
+}
+
+/// Install the W3C Trace Context and Baggage propagators and an OTLP tracer provider as the
+/// globals, and return a [`Layer`] that uses them.
+///
+/// The exporter is configured from the standard environment variables, such as
+/// `OTEL_EXPORTER_OTLP_ENDPOINT` and `OTEL_EXPORTER_OTLP_TIMEOUT`, and the service's resource
+/// from `OTEL_SERVICE_NAME` and `OTEL_RESOURCE_ATTRIBUTES`. Spans are exported in batches on the
+/// Tokio runtime, so this must be called from within one. Call [`shutdown`] before exiting to
+/// flush the remaining spans.
+///
+/// With the `metrics` feature, an OTLP meter provider exporting on the same runtime is installed
+/// as well, and the returned layer records [`Layer::with_metrics`] to it.
+///
+/// # Errors
+///
+/// Returns an error if an exporter can't be built.
+pub fn init() -> Result<Layer, TraceError> {
+    global::set_text_map_propagator(TextMapCompositePropagator::new(vec![
+        Box::new(TraceContextPropagator::new()),
+        Box::new(BaggagePropagator::new()),
+    ]));
+    opentelemetry_otlp::new_pipeline()
+        .tracing()
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_env())
+        .install_batch(Tokio)?;
+    #[cfg(feature = "metrics")]
+    {
+        init_metrics()?;
+        Ok(Layer::new().with_metrics(true))
+    }
+    #[cfg(not(feature = "metrics"))]
+    Ok(Layer::new())
+}
+
+/// Install the OTLP meter provider as the global one, keeping its controller until [`shutdown`].
+#[cfg(feature = "metrics")]
+fn init_metrics() -> Result<(), TraceError> {
+    let resource = Resource::from_detectors(
+        Duration::ZERO,
+        vec![Box::new(SdkProvidedResourceDetector), Box::new(EnvResourceDetector::new())],
+    );
+    let controller = opentelemetry_otlp::new_pipeline()
+        .metrics(|worker| Tokio.spawn(Box::pin(worker)), |period| Tokio.interval(period))
+        .with_resource(resource.iter().map(|(key, value)| KeyValue::new(key.clone(), value.clone())))
+        .with_exporter(opentelemetry_otlp::new_exporter().tonic().with_env())
+        .build()
+        .map_err(|error| TraceError::Other(error.into()))?;
+    *METRICS.lock().unwrap_or_else(std::sync::PoisonError::into_inner) = Some(controller);
+    Ok(())
+}
+
+/// Flush the remaining spans, and metrics with the `metrics` feature, and shut down the
+/// exporters installed by [`init`].
+pub fn shutdown() {
+    global::shutdown_tracer_provider();
+    #[cfg(feature = "metrics")]
+    drop(METRICS.lock().unwrap_or_else(std::sync::PoisonError::into_inner).take());
+}
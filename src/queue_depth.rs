@@ -0,0 +1,20 @@
+//! Queue depths reported by buffering layers that run before the [`Service`].
+//!
+//! [`Service`]: crate::Service
+
+/// The number of requests queued ahead of this one when it entered an outer buffer, recorded as
+/// `http.server.queue_depth`.
+///
+/// Layers such as `tower::buffer` hold requests until the inner service is ready, which shows up
+/// as unexplained latency in the request span. A buffering layer that knows its queue depth can
+/// insert it into the request extensions before the request reaches the [`Service`], and the
+/// attribute is omitted for requests without it.
+///
+/// ```
+/// let mut req = http::Request::new(());
+/// req.extensions_mut().insert(tower_opentelemetry::QueueDepth(3));
+/// ```
+///
+/// [`Service`]: crate::Service
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct QueueDepth(pub usize);
@@ -0,0 +1,23 @@
+//! Receipt timestamps set by layers that run before the [`Service`].
+//!
+//! [`Service`]: crate::Service
+use std::time::SystemTime;
+
+/// The time a request was received, inserted into the request extensions by an outer layer.
+///
+/// A request can wait in an outer layer, such as a concurrency limit, before it reaches the
+/// [`Service`]. When [`Layer::with_received_at_start_time`] is enabled and this extension is
+/// present, spans start at this time instead of when the [`Service`] is called, so their duration
+/// includes that wait.
+///
+/// ```
+/// use std::time::SystemTime;
+///
+/// let mut req = http::Request::new(());
+/// req.extensions_mut().insert(tower_opentelemetry::ReceivedAt(SystemTime::now()));
+/// ```
+///
+/// [`Service`]: crate::Service
+/// [`Layer::with_received_at_start_time`]: crate::Layer::with_received_at_start_time
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReceivedAt(pub SystemTime);
@@ -0,0 +1,78 @@
+//! Replacing the configuration of running services without rebuilding them.
+use std::{
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, PoisonError, RwLock,
+    },
+};
+
+use crate::{Config, Layer};
+
+/// A shared handle that swaps the configuration of every [`Service`] built by a [`Layer`] while
+/// requests are in flight.
+///
+/// Install a clone with [`Layer::with_reload_handle`] and keep another, for example in an admin
+/// endpoint, to change exclusions, sampling or any other option at runtime. [`reload`] replaces
+/// the whole configuration with that of another [`Layer`], closures included, so every option
+/// can be reloaded. Requests already in flight finish with the configuration they started with,
+/// and each service picks up the new one on its next request. Checking for a reload costs a
+/// single relaxed atomic load per request.
+///
+/// A few options hold state that a reload starts afresh rather than carrying over:
+///
+/// - the tracer and instruments are rebuilt from [`Layer::with_tracer_provider`] and
+///   [`Layer::with_metrics`] of the new configuration,
+/// - the count of [`Layer::with_max_active_spans`] starts at zero, ignoring spans still active,
+/// - a reload handle installed on the new configuration is ignored, since services keep
+///   following the handle they were built with.
+///
+/// ```
+/// let handle = tower_opentelemetry::ReloadHandle::new();
+/// let layer = tower_opentelemetry::Layer::new().with_reload_handle(handle.clone());
+/// handle.reload(tower_opentelemetry::Layer::new().with_excluded_paths(["/healthz"]));
+/// ```
+///
+/// [`Service`]: crate::Service
+/// [`reload`]: ReloadHandle::reload
+#[derive(Clone, Default)]
+pub struct ReloadHandle {
+    shared: Arc<Shared>,
+}
+
+#[derive(Default)]
+struct Shared {
+    /// Incremented by every reload, so services can tell that their configuration is stale.
+    generation: AtomicU64,
+    config: RwLock<Option<Config>>,
+}
+
+impl ReloadHandle {
+    /// Create a handle that hasn't reloaded anything yet.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Replace the configuration of every service following this handle with that of `layer`.
+    pub fn reload(&self, layer: Layer) {
+        *self.shared.config.write().unwrap_or_else(PoisonError::into_inner) = Some(layer.config);
+        self.shared.generation.fetch_add(1, Ordering::Release);
+    }
+
+    /// The number of reloads so far.
+    pub(crate) fn generation(&self) -> u64 {
+        self.shared.generation.load(Ordering::Relaxed)
+    }
+
+    /// The configuration of the latest reload, if any.
+    pub(crate) fn config(&self) -> Option<Config> {
+        self.shared.config.read().unwrap_or_else(PoisonError::into_inner).clone()
+    }
+}
+
+impl fmt::Debug for ReloadHandle {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReloadHandle").field("generation", &self.generation()).finish_non_exhaustive()
+    }
+}
@@ -0,0 +1,374 @@
+//! Capture of a bounded prefix of the request body as a span event.
+//!
+//! The [`Layer`] in this module wraps the request body so the bytes streamed to the handler can be
+//! copied, up to a configured limit, and attached to the request's span as an
+//! `http.request.body` event once the body ends or is dropped. Apply it only to the routes being
+//! debugged, inside the main [`crate::Layer`] so the span is current when the request arrives.
+//!
+//! With [`Layer::with_content_length`] it records the size of the body as
+//! `http.request_content_length` when the request arrives, preferring the body's exact size hint
+//! over the `Content-Length` header so sizes of bodies without the header are known too.
+//!
+//! With [`Layer::with_read_duration`] it records how long the body took to stream, separating
+//! slow uploads from slow handlers.
+//!
+//! With [`Layer::with_body_consumed`] it also records whether the handler drained the body, since
+//! handlers that leave it unread can break connection reuse. This is only available for bodies
+//! implementing [`http_body::Body`]; other request types can't be wrapped and never carry the
+//! attribute.
+//!
+//! # Cost
+//!
+//! Every wrapped request holds a buffer of up to `max_bytes` bytes until its body finishes, and
+//! each data frame is copied into it until the limit is reached. Streaming is preserved, since
+//! frames are passed on as soon as they are copied, but the extra copy and the per-request buffer
+//! make this unsuitable for hot paths or large uploads. Tracking whether the body was consumed
+//! alone costs a flag per request and no copying, with a `max_bytes` of zero.
+use std::{
+    fmt,
+    io::IoSlice,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+    time::Instant,
+};
+
+use bytes::Buf;
+use http::{HeaderMap, Request};
+use http_body::SizeHint;
+#[cfg(feature = "metrics")]
+use opentelemetry::metrics::{MeterProvider, Unit, ValueRecorder};
+use opentelemetry::{trace::TraceContextExt, Context, Key};
+use opentelemetry_semantic_conventions::trace::HTTP_REQUEST_CONTENT_LENGTH;
+
+/// The captured bytes, after redaction.
+const HTTP_REQUEST_BODY_PREFIX: Key = Key::from_static_str("http.request.body.prefix");
+
+/// Whether the body was longer than the captured prefix.
+const HTTP_REQUEST_BODY_TRUNCATED: Key = Key::from_static_str("http.request.body.truncated");
+
+/// The time from the first to the last data frame of the body, in milliseconds.
+const HTTP_REQUEST_BODY_READ_DURATION_MS: Key = Key::from_static_str("http.request.body.read_duration_ms");
+
+/// Whether the handler read the body to its end.
+const HTTP_REQUEST_BODY_CONSUMED: Key = Key::from_static_str("http.request.body_consumed");
+
+type Redactor = dyn Fn(&[u8]) -> String + Send + Sync;
+
+/// [`Layer`] that records up to `max_bytes` of the request body as a span event.
+///
+/// The layer applies to every request reaching it and has no route or path filter of its own:
+/// scoping it to the routes being debugged is left to the router, such as by adding it to just
+/// those routes.
+///
+/// [`Layer`]: tower_layer::Layer
+#[derive(Clone)]
+pub struct Layer {
+    config: Arc<Config>,
+}
+
+#[derive(Clone)]
+struct Config {
+    max_bytes: usize,
+    redactor: Option<Arc<Redactor>>,
+    consumed: bool,
+    content_length: bool,
+    read_duration: bool,
+    #[cfg(feature = "metrics")]
+    read_duration_metric: Option<ValueRecorder<f64>>,
+}
+
+impl Layer {
+    /// Create a new [`Layer`] that records at most `max_bytes` of each request body.
+    ///
+    /// With a `max_bytes` of zero nothing is captured and no event is recorded.
+    #[must_use]
+    pub fn new(max_bytes: usize) -> Self {
+        Self {
+            config: Arc::new(Config {
+                max_bytes,
+                redactor: None,
+                consumed: false,
+                content_length: false,
+                read_duration: false,
+                #[cfg(feature = "metrics")]
+                read_duration_metric: None,
+            }),
+        }
+    }
+
+    /// Render the captured bytes through `redactor` before they are recorded.
+    ///
+    /// By default the bytes are recorded as lossy UTF-8.
+    #[must_use]
+    pub fn with_redactor<F>(mut self, redactor: F) -> Self
+    where
+        F: Fn(&[u8]) -> String + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.config).redactor = Some(Arc::new(redactor));
+        self
+    }
+
+    /// Whether to record the size of each request body as `http.request_content_length`, from its
+    /// exact size hint or else the `Content-Length` header.
+    ///
+    /// The attribute is omitted when neither is known, such as for chunked uploads. Disabled by
+    /// default.
+    #[must_use]
+    pub fn with_content_length(mut self, enabled: bool) -> Self {
+        Arc::make_mut(&mut self.config).content_length = enabled;
+        self
+    }
+
+    /// Whether to record the time from the first to the last data frame of each request body as
+    /// `http.request.body.read_duration_ms`.
+    ///
+    /// The attribute is omitted for bodies without data. With the `metrics` feature the duration
+    /// is also recorded in the `http.server.request.body.read_duration` histogram of the global
+    /// meter provider. Disabled by default.
+    #[must_use]
+    pub fn with_read_duration(mut self, enabled: bool) -> Self {
+        let config = Arc::make_mut(&mut self.config);
+        config.read_duration = enabled;
+        #[cfg(feature = "metrics")]
+        {
+            config.read_duration_metric = enabled.then(|| {
+                opentelemetry::global::meter_provider()
+                    .meter("tower-opentelemetry", Some(env!("CARGO_PKG_VERSION")))
+                    .f64_value_recorder("http.server.request.body.read_duration")
+                    .with_description("Time from the first to the last data frame of inbound HTTP request bodies")
+                    .with_unit(Unit::new("ms"))
+                    .init()
+            });
+        }
+        self
+    }
+
+    /// Whether to record `http.request.body_consumed` on the request's span, telling whether
+    /// the body was read to its end before it was dropped.
+    ///
+    /// Bodies that are empty from the start count as consumed, and bodies that fail while their
+    /// data or trailers are read don't. Disabled by default.
+    #[must_use]
+    pub fn with_body_consumed(mut self, enabled: bool) -> Self {
+        Arc::make_mut(&mut self.config).consumed = enabled;
+        self
+    }
+}
+
+impl fmt::Debug for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Layer")
+            .field("max_bytes", &self.config.max_bytes)
+            .field("consumed", &self.config.consumed)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> tower_layer::Layer<S> for Layer {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// Middleware [`Service`] that wraps request bodies in a capturing [`Body`].
+///
+/// [`Service`]: tower_service::Service
+#[derive(Clone)]
+pub struct Service<S> {
+    inner: S,
+    config: Arc<Config>,
+}
+
+impl<S> fmt::Debug for Service<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Service")
+            .field("max_bytes", &self.config.max_bytes)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, B> tower_service::Service<Request<B>> for Service<S>
+where
+    S: tower_service::Service<Request<Body<B>>>,
+    B: http_body::Body,
+{
+    type Error = S::Error;
+    type Future = S::Future;
+    type Response = S::Response;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request<B>) -> Self::Future {
+        let cx = Context::current();
+        if self.config.content_length {
+            if let Some(size) = crate::body_size(&req.body().size_hint(), req.headers()) {
+                cx.span().set_attribute(HTTP_REQUEST_CONTENT_LENGTH.i64(size));
+            }
+        }
+        let config = self.config.clone();
+        self.inner.call(req.map(|inner| Body::new(inner, cx, config)))
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Request body that copies a bounded prefix of the bytes it yields.
+    ///
+    /// The prefix is recorded on the span that was current when the request reached the
+    /// [`Service`], once the body ends or is dropped.
+    #[project = BodyProj]
+    pub struct Body<B> {
+        #[pin]
+        inner: B,
+        captured: Vec<u8>,
+        truncated: bool,
+        finished: bool,
+        recorded: bool,
+        frames: Option<(Instant, Instant)>,
+        cx: Context,
+        config: Arc<Config>,
+    }
+
+    impl<B> PinnedDrop for Body<B> {
+        fn drop(this: Pin<&mut Self>) {
+            this.project().record();
+        }
+    }
+}
+
+impl<B> Body<B>
+where
+    B: http_body::Body,
+{
+    fn new(inner: B, cx: Context, config: Arc<Config>) -> Self {
+        Self {
+            finished: inner.is_end_stream(),
+            inner,
+            captured: Vec::new(),
+            truncated: false,
+            recorded: false,
+            frames: None,
+            cx,
+            config,
+        }
+    }
+}
+
+impl<B> fmt::Debug for Body<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Body")
+            .field("captured", &self.captured.len())
+            .field("truncated", &self.truncated)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B> BodyProj<'_, B> {
+    fn capture(&mut self, data: &impl Buf) {
+        // Bytes after a gap would no longer be a prefix of the body.
+        if *self.truncated {
+            return;
+        }
+        let wanted = data.remaining().min(self.config.max_bytes - self.captured.len());
+        let mut slices = [IoSlice::new(&[]); 16];
+        let filled = data.chunks_vectored(&mut slices);
+        let mut copied = 0;
+        for slice in &slices[..filled] {
+            let take = slice.len().min(wanted - copied);
+            self.captured.extend_from_slice(&slice[..take]);
+            copied += take;
+        }
+        // `chunks_vectored` may expose fewer chunks than the frame has, as few as one, and the
+        // rest can't be reached without consuming the frame before the handler gets it.
+        *self.truncated = copied < data.remaining();
+    }
+
+    fn record(&mut self) {
+        if std::mem::replace(self.recorded, true) {
+            return;
+        }
+        if self.config.consumed {
+            self.cx.span().set_attribute(HTTP_REQUEST_BODY_CONSUMED.bool(*self.finished));
+        }
+        if let Some((first, last)) = self.frames.filter(|_| self.config.read_duration) {
+            let millis = last.duration_since(first).as_secs_f64() * 1000.0;
+            self.cx.span().set_attribute(HTTP_REQUEST_BODY_READ_DURATION_MS.f64(millis));
+            #[cfg(feature = "metrics")]
+            if let Some(metric) = &self.config.read_duration_metric {
+                metric.record(millis, &[]);
+            }
+        }
+        if self.config.max_bytes == 0 {
+            return;
+        }
+        let prefix = match &self.config.redactor {
+            Some(redactor) => redactor(self.captured),
+            None => String::from_utf8_lossy(self.captured).into_owned(),
+        };
+        self.cx.span().add_event(
+            "http.request.body",
+            vec![
+                HTTP_REQUEST_BODY_PREFIX.string(prefix),
+                HTTP_REQUEST_BODY_TRUNCATED.bool(*self.truncated),
+            ],
+        );
+    }
+}
+
+impl<B> http_body::Body for Body<B>
+where
+    B: http_body::Body,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let mut this = self.project();
+        let result = futures_util::ready!(this.inner.as_mut().poll_data(cx));
+        match &result {
+            Some(Ok(data)) => {
+                let now = Instant::now();
+                let (first, _) = this.frames.get_or_insert((now, now));
+                *this.frames = Some((*first, now));
+                this.capture(data);
+                *this.finished = this.inner.is_end_stream();
+            }
+            Some(Err(_)) => {
+                *this.finished = false;
+                this.record();
+            }
+            None => {
+                *this.finished = true;
+                // Trailers that are still to come can fail, so wait for them or the drop.
+                if this.inner.is_end_stream() {
+                    this.record();
+                }
+            }
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_trailers(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let mut this = self.project();
+        let result = futures_util::ready!(this.inner.as_mut().poll_trailers(cx));
+        *this.finished = result.is_ok();
+        this.record();
+        Poll::Ready(result)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,58 @@
+//! A cloneable handle on the request's context for work that outlives the handler's task.
+use std::future::Future;
+
+use opentelemetry::{trace::FutureExt, Context, ContextGuard};
+
+/// The [`Context`] holding the request's span, inserted into the request extensions when
+/// [`Layer::with_request_context`] is enabled.
+///
+/// The context is only current on the task that polls the request's future, so work passed to
+/// `tokio::spawn` or a thread pool loses it and its spans become new roots. Clone the handle out
+/// of the extensions and [`wrap`] the spawned future, or [`attach`] it on the new thread, so the
+/// spans created there are children of the request's span:
+///
+/// ```
+/// use http::Request;
+/// use tower_opentelemetry::RequestContext;
+///
+/// fn handler(req: Request<()>) {
+///     if let Some(cx) = req.extensions().get::<RequestContext>().cloned() {
+///         std::thread::spawn(move || {
+///             let _guard = cx.attach();
+///             // spans started here are children of the request's span
+///         });
+///     }
+/// }
+/// # handler(Request::new(()));
+/// ```
+///
+/// [`Layer::with_request_context`]: crate::Layer::with_request_context
+/// [`wrap`]: RequestContext::wrap
+/// [`attach`]: RequestContext::attach
+#[derive(Debug, Clone)]
+pub struct RequestContext {
+    cx: Context,
+}
+
+impl RequestContext {
+    pub(crate) fn new(cx: Context) -> Self {
+        Self { cx }
+    }
+
+    /// The request's [`Context`].
+    #[must_use]
+    pub fn context(&self) -> &Context {
+        &self.cx
+    }
+
+    /// Make the request's context current on this thread until the returned guard is dropped.
+    #[must_use = "the context is only current until the guard is dropped"]
+    pub fn attach(&self) -> ContextGuard {
+        self.cx.clone().attach()
+    }
+
+    /// Make the request's context current whenever `future` is polled.
+    pub fn wrap<F: Future>(&self, future: F) -> impl Future<Output = F::Output> {
+        future.with_context(self.cx.clone())
+    }
+}
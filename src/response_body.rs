@@ -0,0 +1,291 @@
+//! Observation of response bodies until they end, for spans that outlive the response.
+//!
+//! The [`Layer`] in this module wraps the response body so attributes only known once the body
+//! has been streamed, such as trailers, can be recorded on the request's span before it ends.
+//! It relies on the [`DeferredSpan`] that [`crate::Layer::with_deferred_span_end`] puts into the
+//! response extensions, so it must be applied outside the main [`crate::Layer`], and it passes
+//! bodies through untouched when the response carries no [`DeferredSpan`].
+//!
+//! With [`Layer::with_content_length`] the size of the body is recorded as
+//! `http.response_content_length` as soon as the response arrives, from the body's exact size
+//! hint or else the `Content-Length` header.
+//!
+//! The span ends when the body's trailers have been read, when the body fails or when it is
+//! dropped, whichever comes first. Bodies without trailers simply won't produce the trailer
+//! attributes.
+//!
+//! # Cost
+//!
+//! Each wrapped response holds the span until its body ends, and counting the response size adds
+//! a little work per data frame. Both are opt-in.
+use std::{
+    convert::TryFrom,
+    fmt,
+    future::Future,
+    pin::Pin,
+    sync::Arc,
+    task::{Context as TaskContext, Poll},
+};
+
+use bytes::Buf;
+use http::{HeaderMap, HeaderName, Response};
+use http_body::SizeHint;
+use opentelemetry::{trace::TraceContextExt, Key};
+use opentelemetry_semantic_conventions::trace::HTTP_RESPONSE_CONTENT_LENGTH;
+
+use crate::DeferredSpan;
+
+/// The number of bytes of the response body that were streamed.
+const HTTP_RESPONSE_BODY_SIZE: Key = Key::from_static_str("http.response.body.size");
+
+/// [`Layer`] that records attributes from response bodies on deferred request spans.
+///
+/// [`Layer`]: tower_layer::Layer
+#[derive(Clone, Default)]
+pub struct Layer {
+    config: Arc<Config>,
+}
+
+#[derive(Clone, Default)]
+struct Config {
+    trailers: Vec<(HeaderName, Key)>,
+    body_size: bool,
+    content_length: bool,
+}
+
+impl Layer {
+    /// Create a new [`Layer`] that records nothing until configured.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Record the response trailers in `names` as `http.response.trailer.<name>`, with dashes in
+    /// the name replaced by underscores, such as `http.response.trailer.grpc_status`.
+    ///
+    /// Only these trailers are recorded, since trailers may carry arbitrary data.
+    #[must_use]
+    pub fn with_trailers<I>(mut self, names: I) -> Self
+    where
+        I: IntoIterator<Item = HeaderName>,
+    {
+        let trailers = names
+            .into_iter()
+            .map(|name| {
+                let key = Key::new(format!("http.response.trailer.{}", name.as_str().replace('-', "_")));
+                (name, key)
+            })
+            .collect();
+        Arc::make_mut(&mut self.config).trailers = trailers;
+        self
+    }
+
+    /// Whether to count the bytes of the response body as they are streamed and record the total
+    /// as `http.response.body.size` when the span ends.
+    ///
+    /// Unlike `Content-Length`, this covers chunked and streamed responses, and reflects what was
+    /// actually produced when the client goes away mid-stream. Disabled by default.
+    #[must_use]
+    pub fn with_body_size(mut self, enabled: bool) -> Self {
+        Arc::make_mut(&mut self.config).body_size = enabled;
+        self
+    }
+
+    /// Whether to record the size of the response body as `http.response_content_length` when
+    /// the response arrives, from the body's exact size hint or else the `Content-Length` header.
+    ///
+    /// The attribute is omitted when neither is known, such as for streamed responses.
+    /// Disabled by default.
+    #[must_use]
+    pub fn with_content_length(mut self, enabled: bool) -> Self {
+        Arc::make_mut(&mut self.config).content_length = enabled;
+        self
+    }
+}
+
+impl fmt::Debug for Layer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Layer")
+            .field("trailers", &self.config.trailers.len())
+            .field("body_size", &self.config.body_size)
+            .field("content_length", &self.config.content_length)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S> tower_layer::Layer<S> for Layer {
+    type Service = Service<S>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        Service {
+            inner,
+            config: self.config.clone(),
+        }
+    }
+}
+
+/// Middleware [`Service`] that wraps response bodies in an observing [`Body`].
+///
+/// [`Service`]: tower_service::Service
+#[derive(Clone)]
+pub struct Service<S> {
+    inner: S,
+    config: Arc<Config>,
+}
+
+impl<S> fmt::Debug for Service<S> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Service")
+            .field("trailers", &self.config.trailers.len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, R, B> tower_service::Service<R> for Service<S>
+where
+    S: tower_service::Service<R, Response = Response<B>>,
+    B: http_body::Body,
+{
+    type Error = S::Error;
+    type Future = ResponseFuture<S::Future>;
+    type Response = Response<Body<B>>;
+
+    #[inline]
+    fn poll_ready(&mut self, cx: &mut TaskContext<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: R) -> Self::Future {
+        ResponseFuture {
+            inner: self.inner.call(req),
+            config: self.config.clone(),
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Response future of [`Service`], wrapping the body of the response.
+    pub struct ResponseFuture<F> {
+        #[pin]
+        inner: F,
+        config: Arc<Config>,
+    }
+}
+
+impl<F> fmt::Debug for ResponseFuture<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ResponseFuture").finish_non_exhaustive()
+    }
+}
+
+impl<F, B, E> Future for ResponseFuture<F>
+where
+    F: Future<Output = Result<Response<B>, E>>,
+    B: http_body::Body,
+{
+    type Output = Result<Response<Body<B>>, E>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Self::Output> {
+        let this = self.project();
+        let mut res = futures_util::ready!(this.inner.poll(cx))?;
+        let span = res.extensions_mut().remove::<DeferredSpan>();
+        let config = this.config.clone();
+        if let Some(span) = span.as_ref().filter(|_| config.content_length) {
+            if let Some(size) = crate::body_size(&res.body().size_hint(), res.headers()) {
+                span.context().span().set_attribute(HTTP_RESPONSE_CONTENT_LENGTH.i64(size));
+            }
+        }
+        Poll::Ready(Ok(res.map(|inner| Body {
+            inner,
+            span,
+            size: 0,
+            config,
+        })))
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// Response body that records what it observed on the request's span once it ends.
+    #[project = BodyProj]
+    pub struct Body<B> {
+        #[pin]
+        inner: B,
+        span: Option<DeferredSpan>,
+        size: u64,
+        config: Arc<Config>,
+    }
+
+    impl<B> PinnedDrop for Body<B> {
+        fn drop(this: Pin<&mut Self>) {
+            this.project().end();
+        }
+    }
+}
+
+impl<B> fmt::Debug for Body<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Body")
+            .field("deferred", &self.span.is_some())
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B> BodyProj<'_, B> {
+    fn record_trailers(&mut self, trailers: &HeaderMap) {
+        let span = match self.span {
+            Some(span) => span.context().span(),
+            None => return,
+        };
+        for (name, key) in &self.config.trailers {
+            if let Some(value) = trailers.get(name).and_then(|value| value.to_str().ok()) {
+                span.set_attribute(key.clone().string(value.to_string()));
+            }
+        }
+    }
+
+    fn end(&mut self) {
+        if let Some(span) = self.span.take() {
+            if self.config.body_size {
+                let size = i64::try_from(*self.size).unwrap_or(i64::MAX);
+                span.context().span().set_attribute(HTTP_RESPONSE_BODY_SIZE.i64(size));
+            }
+        }
+    }
+}
+
+impl<B> http_body::Body for Body<B>
+where
+    B: http_body::Body,
+{
+    type Data = B::Data;
+    type Error = B::Error;
+
+    fn poll_data(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        let mut this = self.project();
+        let result = futures_util::ready!(this.inner.as_mut().poll_data(cx));
+        match &result {
+            Some(Ok(data)) => *this.size += data.remaining() as u64,
+            Some(Err(_)) => this.end(),
+            None => {}
+        }
+        Poll::Ready(result)
+    }
+
+    fn poll_trailers(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Result<Option<HeaderMap>, Self::Error>> {
+        let mut this = self.project();
+        let result = futures_util::ready!(this.inner.as_mut().poll_trailers(cx));
+        if let Ok(Some(trailers)) = &result {
+            this.record_trailers(trailers);
+        }
+        this.end();
+        Poll::Ready(result)
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
@@ -0,0 +1,176 @@
+//! Routing details that routers can expose to the layer through extensions.
+use std::borrow::Cow;
+
+use opentelemetry::{
+    trace::{SpanRef, StatusCode},
+    KeyValue,
+};
+
+/// Identifies which of several mounted routers handled a request, recorded as `http.router`.
+///
+/// The [`Service`] looks for this in the request extensions when it is called, and in the
+/// response extensions when the response is produced. A router mounted behind the [`Service`]
+/// only sees the request after the [`Service`] has, so it should insert the label into its
+/// responses.
+///
+/// ```
+/// use tower_opentelemetry::RouterLabel;
+///
+/// let mut res = http::Response::new(());
+/// res.extensions_mut().insert(RouterLabel::new("admin"));
+/// ```
+///
+/// [`Service`]: crate::Service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RouterLabel(pub Cow<'static, str>);
+
+impl RouterLabel {
+    /// Create a label for the router named `name`.
+    pub fn new<T>(name: T) -> Self
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        Self(name.into())
+    }
+}
+
+/// The route template that matched a request, such as `/users/{id}`, recorded as `http.route`.
+///
+/// Like [`RouterLabel`], it is read from the request extensions when the [`Service`] is called
+/// and from the response extensions when the response is produced. The template, never the raw
+/// path, is also the route dimension of the request metrics, so it must not contain path
+/// parameters.
+///
+/// ```
+/// use tower_opentelemetry::MatchedRoute;
+///
+/// let mut res = http::Response::new(());
+/// res.extensions_mut().insert(MatchedRoute::new("/users/{id}"));
+/// ```
+///
+/// [`Service`]: crate::Service
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MatchedRoute(pub Cow<'static, str>);
+
+impl MatchedRoute {
+    /// Create a route from its `template`.
+    pub fn new<T>(template: T) -> Self
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        Self(template.into())
+    }
+
+    /// The route in `extensions`, joined to the [`RoutePrefix`] of the routers it is nested in.
+    pub(crate) fn from_extensions(extensions: &http::Extensions) -> Option<Self> {
+        let route = extensions.get::<Self>()?;
+        match extensions.get::<RoutePrefix>() {
+            Some(RoutePrefix(prefix)) => Some(Self(join(prefix, &route.0).into())),
+            None => Some(route.clone()),
+        }
+    }
+}
+
+/// The template a router is mounted at when nested in another router, such as `/admin`, which
+/// is joined to the relative [`MatchedRoute`] of the nested router.
+///
+/// A router that only knows the route relative to its mount point inserts its [`MatchedRoute`]
+/// next to the prefix of its mount point, and every [`MatchedRoute`] the layer records, on the
+/// span and as the route dimension of the request metrics, is then the full template. Routers
+/// nested several levels deep extend the prefix of their parent with [`RoutePrefix::nest`].
+///
+/// ```
+/// use tower_opentelemetry::{MatchedRoute, RoutePrefix};
+///
+/// let mut res = http::Response::new(());
+/// res.extensions_mut().insert(RoutePrefix::new("/admin"));
+/// res.extensions_mut().insert(MatchedRoute::new("/users/{id}"));
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RoutePrefix(pub Cow<'static, str>);
+
+impl RoutePrefix {
+    /// Create the prefix of a router mounted at `template`.
+    pub fn new<T>(template: T) -> Self
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        Self(template.into())
+    }
+
+    /// The prefix of a router mounted at `template` within the router of this prefix.
+    #[must_use]
+    pub fn nest(&self, template: &str) -> Self {
+        Self(join(&self.0, template).into())
+    }
+}
+
+/// Join `route` to the `prefix` it is mounted at, with a single slash between them and none
+/// after the prefix when `route` is the root.
+fn join(prefix: &str, route: &str) -> String {
+    let prefix = prefix.trim_end_matches('/');
+    let route = route.trim_start_matches('/');
+    match (prefix.is_empty(), route.is_empty()) {
+        (true, _) => format!("/{route}"),
+        (false, true) => prefix.to_string(),
+        (false, false) => format!("{prefix}/{route}"),
+    }
+}
+
+/// Attributes and a status applied to the spans of one route when they end, whatever the
+/// outcome, for [`Layer::with_route_override`].
+///
+/// ```
+/// use opentelemetry::{trace::StatusCode, KeyValue};
+/// use tower_opentelemetry::RouteOverride;
+///
+/// let deprecated = RouteOverride::new()
+///     .with_attribute(KeyValue::new("deprecated", true))
+///     .with_status(StatusCode::Error, "deprecated endpoint");
+/// ```
+///
+/// [`Layer::with_route_override`]: crate::Layer::with_route_override
+#[derive(Debug, Clone, Default)]
+pub struct RouteOverride {
+    attributes: Vec<KeyValue>,
+    status: Option<(StatusCode, Cow<'static, str>)>,
+}
+
+impl RouteOverride {
+    /// Create an override that changes nothing.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add `attribute` to the spans of the route.
+    #[must_use]
+    pub fn with_attribute(mut self, attribute: KeyValue) -> Self {
+        self.attributes.push(attribute);
+        self
+    }
+
+    /// Set the status of the spans of the route to `code` with `description`, replacing the
+    /// status derived from the response or error.
+    ///
+    /// OpenTelemetry has no warning status, so endpoints that only need flagging are better
+    /// marked with an attribute.
+    #[must_use]
+    pub fn with_status<T>(mut self, code: StatusCode, description: T) -> Self
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        self.status = Some((code, description.into()));
+        self
+    }
+
+    /// Apply the override to `span`.
+    pub(crate) fn apply(&self, span: &SpanRef<'_>) {
+        for attribute in &self.attributes {
+            span.set_attribute(attribute.clone());
+        }
+        if let Some((code, description)) = &self.status {
+            span.set_status(*code, description.to_string());
+        }
+    }
+}
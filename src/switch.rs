@@ -0,0 +1,63 @@
+//! A runtime switch for turning tracing off without rebuilding the service.
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+/// A shared on/off switch for the spans of every [`Service`] built by a [`Layer`].
+///
+/// Install a clone with [`Layer::with_switch`] and keep another, for example in an admin
+/// endpoint, to shed the telemetry load during an incident. While the switch is off, requests
+/// pass straight through to the inner service as if they were excluded: no span is started and
+/// no context is extracted or injected. Checking the switch costs a single relaxed atomic load
+/// per request. Switches start on.
+///
+/// ```
+/// let switch = tower_opentelemetry::TracingSwitch::new();
+/// let layer = tower_opentelemetry::Layer::new().with_switch(switch.clone());
+/// switch.disable();
+/// assert!(!switch.is_enabled());
+/// ```
+///
+/// [`Service`]: crate::Service
+/// [`Layer`]: crate::Layer
+/// [`Layer::with_switch`]: crate::Layer::with_switch
+#[derive(Debug, Clone)]
+pub struct TracingSwitch {
+    enabled: Arc<AtomicBool>,
+}
+
+impl TracingSwitch {
+    /// Create a switch that is on.
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Turn tracing back on.
+    pub fn enable(&self) {
+        self.set(true);
+    }
+
+    /// Turn tracing off.
+    pub fn disable(&self) {
+        self.set(false);
+    }
+
+    /// Turn tracing on or off.
+    pub fn set(&self, enabled: bool) {
+        self.enabled.store(enabled, Ordering::Relaxed);
+    }
+
+    /// Whether tracing is on.
+    #[must_use]
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed)
+    }
+}
+
+impl Default for TracingSwitch {
+    fn default() -> Self {
+        Self { enabled: Arc::new(AtomicBool::new(true)) }
+    }
+}
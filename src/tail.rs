@@ -0,0 +1,243 @@
+//! Local tail sampling of the server span, deciding whether to export it once it ends.
+//!
+//! With [`Layer::with_tail_sampling`] or [`Layer::with_min_export_duration`] the server span is
+//! recorded into a [`BufferedSpan`] instead of the tracer. Once it ends, the sampling function
+//! sees the response status and the duration and, if it keeps the span, the buffered data is
+//! replayed into the tracer as a sampled span with the same ids, start time, attributes, events,
+//! links and status.
+//!
+//! # Cost
+//!
+//! Every request is recorded in full until it ends, including those that are dropped, whereas a
+//! head sampler skips recording unsampled spans altogether. Only the server span is tail-sampled:
+//! its context is marked as sampled so handlers can propagate it, and child spans follow that
+//! decision and are exported whether or not the server span is kept.
+//!
+//! With [`Layer::with_dry_run`] the buffered span is logged instead and never exported.
+//!
+//! [`Layer::with_tail_sampling`]: crate::Layer::with_tail_sampling
+//! [`Layer::with_min_export_duration`]: crate::Layer::with_min_export_duration
+//! [`Layer::with_dry_run`]: crate::Layer::with_dry_run
+use std::{
+    borrow::Cow,
+    sync::{
+        atomic::{AtomicU16, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use opentelemetry::{
+    sdk::trace::{SamplingDecision, SamplingResult},
+    trace::{Event, Span, SpanBuilder, SpanContext, SpanId, StatusCode, TraceContextExt, TraceFlags},
+    Context, KeyValue,
+};
+
+use crate::tracer::BoxedTracer;
+
+/// Decides, from the response status and the duration, whether to export a server span.
+///
+/// The status is `None` when no response was recorded: the inner service failed, or its future
+/// was dropped before completing, such as for a request the client cancelled.
+pub(crate) type TailSampler = dyn Fn(Option<http::StatusCode>, Duration) -> bool + Send + Sync;
+
+/// A server span recorded locally until it ends and its export is decided.
+pub(crate) struct BufferedSpan {
+    span_context: SpanContext,
+    pending: Option<Pending>,
+}
+
+struct Pending {
+    tracer: BoxedTracer,
+    parent: Context,
+    builder: SpanBuilder,
+    status: ResponseStatus,
+    policy: Policy,
+}
+
+/// The response status of a buffered span, stored in its context for the [`Service`] to set when
+/// it records the response.
+///
+/// The status is kept apart from the attributes, whose keys may be remapped.
+///
+/// [`Service`]: crate::Service
+#[derive(Clone)]
+pub(crate) struct ResponseStatus {
+    span_id: SpanId,
+    status: Arc<AtomicU16>,
+}
+
+impl ResponseStatus {
+    /// Set the status if `cx` holds the buffered span itself rather than a span nested in it.
+    pub(crate) fn set(cx: &Context, status: http::StatusCode) {
+        if let Some(this) = cx.get::<Self>().filter(|this| this.span_id == cx.span().span_context().span_id()) {
+            this.status.store(status.as_u16(), Ordering::Relaxed);
+        }
+    }
+
+    fn get(&self) -> Option<http::StatusCode> {
+        http::StatusCode::from_u16(self.status.load(Ordering::Relaxed)).ok()
+    }
+}
+
+/// How a buffered span is decided once it ends.
+#[derive(Clone, Default)]
+pub(crate) struct Policy {
+    /// Drops spans that aren't errors and end faster than this.
+    pub(crate) min_duration: Option<Duration>,
+    /// Decides the spans that weren't dropped for their duration, see [`TailSampler`] for what a
+    /// missing status means.
+    pub(crate) sampler: Option<Arc<TailSampler>>,
+    /// Logs every span instead of exporting it.
+    #[cfg(feature = "dry-run")]
+    pub(crate) dry_run: bool,
+}
+
+impl Policy {
+    /// Whether the policy decides anything, so spans have to be buffered.
+    pub(crate) fn is_enabled(&self) -> bool {
+        #[cfg(feature = "dry-run")]
+        if self.dry_run {
+            return true;
+        }
+        self.min_duration.is_some() || self.sampler.is_some()
+    }
+}
+
+/// Start buffering the span of `builder` as a child of `parent`, returning `parent` with the
+/// buffered span set as active.
+pub(crate) fn start(tracer: &BoxedTracer, mut builder: SpanBuilder, parent: &Context, policy: Policy) -> Context {
+    let parent_span = parent.span();
+    let parent_context = parent_span.span_context();
+    let trace_id = if parent_context.is_valid() {
+        parent_context.trace_id()
+    } else {
+        builder.trace_id.unwrap_or_else(|| tracer.new_trace_id())
+    };
+    let span_id = tracer.new_span_id();
+    let span_context = SpanContext::new(
+        trace_id,
+        span_id,
+        TraceFlags::SAMPLED,
+        false,
+        parent_context.trace_state().clone(),
+    );
+    builder.trace_id = Some(trace_id);
+    builder.span_id = Some(span_id);
+    builder.start_time.get_or_insert_with(SystemTime::now);
+    let status = ResponseStatus {
+        span_id,
+        status: Arc::default(),
+    };
+    parent
+        .with_span(BufferedSpan {
+            span_context,
+            pending: Some(Pending {
+                tracer: tracer.clone(),
+                parent: parent.clone(),
+                builder,
+                status: status.clone(),
+                policy,
+            }),
+        })
+        .with_value(status)
+}
+
+impl Span for BufferedSpan {
+    fn add_event_with_timestamp<T>(&mut self, name: T, timestamp: SystemTime, attributes: Vec<KeyValue>)
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        if let Some(pending) = &mut self.pending {
+            let events = pending.builder.events.get_or_insert_with(Vec::new);
+            events.push(Event::new(name, timestamp, attributes, 0));
+        }
+    }
+
+    fn span_context(&self) -> &SpanContext {
+        &self.span_context
+    }
+
+    fn is_recording(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    fn set_attribute(&mut self, attribute: KeyValue) {
+        if let Some(pending) = &mut self.pending {
+            pending.builder.attributes.get_or_insert_with(Vec::new).push(attribute);
+        }
+    }
+
+    fn set_status(&mut self, code: StatusCode, message: String) {
+        if let Some(pending) = &mut self.pending {
+            pending.builder.status_code = Some(code);
+            pending.builder.status_message = Some(message.into());
+        }
+    }
+
+    fn update_name<T>(&mut self, new_name: T)
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        if let Some(pending) = &mut self.pending {
+            pending.builder.name = new_name.into();
+        }
+    }
+
+    fn end_with_timestamp(&mut self, timestamp: SystemTime) {
+        let Some(mut pending) = self.pending.take() else {
+            return;
+        };
+        let start = pending.builder.start_time.unwrap_or(timestamp);
+        let duration = timestamp.duration_since(start).unwrap_or_default();
+        #[cfg(feature = "dry-run")]
+        if pending.policy.dry_run {
+            log(&pending.builder, duration);
+            return;
+        }
+        let is_error = pending.builder.status_code == Some(StatusCode::Error);
+        if !is_error && pending.policy.min_duration.is_some_and(|min| duration < min) {
+            return;
+        }
+        if let Some(sampler) = &pending.policy.sampler {
+            if !sampler(pending.status.get(), duration) {
+                return;
+            }
+        }
+        pending.builder.sampling_result = Some(SamplingResult {
+            decision: SamplingDecision::RecordAndSample,
+            attributes: Vec::new(),
+            trace_state: self.span_context.trace_state().clone(),
+        });
+        let cx = pending.tracer.build_with_context(pending.builder, &pending.parent);
+        cx.span().end_with_timestamp(timestamp);
+    }
+}
+
+/// Log the span of `builder` as a `tracing` event instead of exporting it.
+#[cfg(feature = "dry-run")]
+fn log(builder: &SpanBuilder, duration: Duration) {
+    let attributes: Vec<String> = builder
+        .attributes
+        .iter()
+        .flatten()
+        .map(|kv| format!("{}={}", kv.key.as_str(), kv.value))
+        .collect();
+    let events: Vec<&str> = builder.events.iter().flatten().map(|event| event.name.as_ref()).collect();
+    tracing::info!(
+        target: "tower_opentelemetry::dry_run",
+        name = %builder.name,
+        kind = ?builder.span_kind,
+        status = ?builder.status_code,
+        ?duration,
+        attributes = %attributes.join(" "),
+        events = %events.join(","),
+        "server span not exported",
+    );
+}
+
+impl Drop for BufferedSpan {
+    fn drop(&mut self) {
+        self.end();
+    }
+}
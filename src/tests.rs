@@ -0,0 +1,3428 @@
+use std::{convert::Infallible, sync::Mutex};
+
+use opentelemetry::{
+    baggage::BaggageExt,
+    propagation::TextMapPropagator,
+    sdk::{
+        export::trace::SpanData,
+        propagation::{BaggagePropagator, TextMapCompositePropagator, TraceContextPropagator},
+        trace::{self as sdktrace, Sampler, SpanProcessor},
+    },
+    trace::{Span as _, TraceResult},
+    Value,
+};
+use tower::{service_fn, ServiceExt};
+
+use super::*;
+
+fn init_propagator() {
+    static INIT: Once = Once::new();
+    INIT.call_once(|| {
+        let propagators: Vec<Box<dyn TextMapPropagator + Send + Sync>> =
+            vec![Box::new(TraceContextPropagator::new()), Box::new(BaggagePropagator::new())];
+        global::set_text_map_propagator(TextMapCompositePropagator::new(propagators));
+    });
+}
+
+fn provider(sampler: Sampler) -> sdktrace::TracerProvider {
+    sdktrace::TracerProvider::builder()
+        .with_config(sdktrace::config().with_sampler(sampler))
+        .build()
+}
+
+/// Collects every span that ends, so tests can assert on the exported data.
+#[derive(Debug, Clone, Default)]
+struct Recorder(Arc<Mutex<Vec<SpanData>>>);
+
+impl Recorder {
+    fn spans(&self) -> Vec<SpanData> {
+        self.0.lock().unwrap().clone()
+    }
+
+    fn single(&self) -> SpanData {
+        let mut spans = self.spans();
+        assert_eq!(spans.len(), 1, "expected exactly one span");
+        spans.remove(0)
+    }
+}
+
+impl SpanProcessor for Recorder {
+    fn on_start(&self, _span: &mut sdktrace::Span, _cx: &Context) {}
+
+    fn on_end(&self, span: SpanData) {
+        self.0.lock().unwrap().push(span);
+    }
+
+    fn force_flush(&self) -> TraceResult<()> {
+        Ok(())
+    }
+
+    fn shutdown(&mut self) -> TraceResult<()> {
+        Ok(())
+    }
+}
+
+fn recording_provider() -> (sdktrace::TracerProvider, Recorder) {
+    let recorder = Recorder::default();
+    let provider = sdktrace::TracerProvider::builder()
+        .with_span_processor(recorder.clone())
+        .build();
+    (provider, recorder)
+}
+
+fn attribute(span: &SpanData, key: &Key) -> Option<Value> {
+    span.attributes.get(key).cloned()
+}
+
+fn call(layer: &Layer, req: Request<()>) -> Response<()> {
+    init_propagator();
+    let service = tower_layer::Layer::layer(
+        layer,
+        service_fn(|_req: Request<()>| async { Ok::<_, Infallible>(Response::new(())) }),
+    );
+    futures_executor::block_on(service.oneshot(req)).unwrap()
+}
+
+/// The single span a layer configured by `configure` records for `req`.
+fn span_for(configure: fn(Layer) -> Layer, req: Request<()>) -> SpanData {
+    let (provider, recorder) = recording_provider();
+    call(&configure(Layer::new().with_tracer_provider(&provider)), req);
+    recorder.single()
+}
+
+/// The single span a layer configured by `configure` records for a response carrying `header`.
+fn span_for_response(configure: fn(Layer) -> Layer, header: Option<(HeaderName, &'static str)>) -> SpanData {
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let service = tower_layer::Layer::layer(
+        &configure(Layer::new().with_tracer_provider(&provider)),
+        service_fn(move |_: Request<()>| {
+            let header = header.clone();
+            async move {
+                let mut res = Response::new(());
+                if let Some((name, value)) = header {
+                    res.headers_mut().insert(name, HeaderValue::from_static(value));
+                }
+                Ok::<_, Infallible>(res)
+            }
+        }),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    recorder.single()
+}
+
+/// A request of `version` arriving on the connection described by `info`.
+fn on_connection(version: Version, info: ConnectionInfo) -> Request<()> {
+    let mut req = Request::builder().version(version).body(()).unwrap();
+    req.extensions_mut().insert(info);
+    req
+}
+
+/// A layer configuration, the request it sees, and the attribute it should record.
+type RequestCase = (fn(Layer) -> Layer, Request<()>, Key, Option<Value>);
+
+#[test]
+fn request_attributes_are_recorded_as_configured() {
+    let negotiation = ConnectionInfo {
+        http2_negotiation: Some(Http2Negotiation::PriorKnowledge),
+        ..ConnectionInfo::default()
+    };
+    let priority = ConnectionInfo {
+        http2_priority: Some(Http2Priority { weight: Some(220), stream_dependency: Some(3) }),
+        ..ConnectionInfo::default()
+    };
+    let pushed = ConnectionInfo {
+        http2_pushed: Some(true),
+        ..ConnectionInfo::default()
+    };
+    let sni = ConnectionInfo {
+        tls_server_name: Some("api.example.com".to_string()),
+        ..ConnectionInfo::default()
+    };
+    let with_host = |host| Request::builder().header("host", host).body(()).unwrap();
+    let idempotency_key = |req: http::request::Builder| req.header("idempotency-key", "order-8e1f").body(()).unwrap();
+    let with_method = |method| Request::builder().method(method).body(()).unwrap();
+    let with_uri = |uri| Request::builder().uri(uri).body(()).unwrap();
+    let mut cases: Vec<RequestCase> = vec![
+        (
+            |layer| layer.with_http2_negotiation(true),
+            on_connection(Version::HTTP_2, negotiation.clone()),
+            NETWORK_PROTOCOL_NEGOTIATION,
+            Some(Value::from("prior_knowledge")),
+        ),
+        (|layer| layer.with_http2_negotiation(true), on_connection(Version::HTTP_11, negotiation), NETWORK_PROTOCOL_NEGOTIATION, None),
+        (
+            |layer| layer.with_http2_priority(true),
+            on_connection(Version::HTTP_2, priority.clone()),
+            HTTP2_PRIORITY_WEIGHT,
+            Some(Value::I64(220)),
+        ),
+        (
+            |layer| layer.with_http2_priority(true),
+            on_connection(Version::HTTP_2, priority.clone()),
+            HTTP2_PRIORITY_STREAM_DEPENDENCY,
+            Some(Value::I64(3)),
+        ),
+        (|layer| layer.with_http2_priority(true), on_connection(Version::HTTP_11, priority), HTTP2_PRIORITY_WEIGHT, None),
+        (|layer| layer.with_http2_pushed(true), on_connection(Version::HTTP_2, pushed.clone()), HTTP2_PUSHED, Some(Value::Bool(true))),
+        (|layer| layer.with_http2_pushed(true), on_connection(Version::HTTP_11, pushed), HTTP2_PUSHED, None),
+        (
+            |layer| layer.with_http2_pushed(true),
+            on_connection(Version::HTTP_2, ConnectionInfo::default()),
+            HTTP2_PUSHED,
+            None,
+        ),
+        (
+            |layer| layer.with_tls_server_name(true),
+            on_connection(Version::HTTP_11, sni),
+            TLS_SERVER_NAME,
+            Some(Value::from("api.example.com")),
+        ),
+        (|layer| layer.with_tls_server_name(true), Request::new(()), TLS_SERVER_NAME, None),
+        (
+            |layer| layer.with_idempotency_key_header(HeaderName::from_static("idempotency-key")),
+            idempotency_key(Request::builder()),
+            HTTP_REQUEST_HAS_IDEMPOTENCY_KEY,
+            Some(Value::Bool(true)),
+        ),
+        (
+            |layer| layer.with_idempotency_key_header(HeaderName::from_static("idempotency-key")),
+            Request::new(()),
+            HTTP_REQUEST_HAS_IDEMPOTENCY_KEY,
+            Some(Value::Bool(false)),
+        ),
+        (|layer| layer, with_method("PURGE"), HTTP_METHOD, Some(Value::from("_OTHER"))),
+        (|layer| layer, with_method("PURGE"), HTTP_REQUEST_METHOD_ORIGINAL, Some(Value::from("PURGE"))),
+        (|layer| layer, with_method("GET"), HTTP_REQUEST_METHOD_ORIGINAL, None),
+        (
+            |layer| layer.with_query_param_count(true),
+            with_uri("/search?q=otel&&page=2&"),
+            HTTP_QUERY_PARAM_COUNT,
+            Some(Value::I64(2)),
+        ),
+        (|layer| layer.with_query_param_count(true), with_uri("/search"), HTTP_QUERY_PARAM_COUNT, None),
+        (|layer| layer, with_uri("/search?q=otel"), HTTP_QUERY_PARAM_COUNT, None),
+    ];
+    let hosts = |layer: Layer| layer.with_host_authority(true).with_invalid_host_marker(true);
+    for host in ["exa mple.com", "bad\"host", "[::1"] {
+        cases.push((hosts, with_host(host), HTTP_HOST_INVALID, Some(Value::Bool(true))));
+        cases.push((hosts, with_host(host), HTTP_HOST, None));
+        cases.push((hosts, with_host(host), NET_HOST_PORT, None));
+    }
+    cases.push((hosts, with_host("example.com"), HTTP_HOST_INVALID, None));
+    cases.push((hosts, with_host("example.com"), HTTP_HOST, Some(Value::from("example.com"))));
+    for (configure, req, key, expected) in cases {
+        assert_eq!(attribute(&span_for(configure, req), &key), expected, "{}", key.as_str());
+    }
+    let span = span_for(
+        |layer| layer.with_idempotency_key_header(HeaderName::from_static("idempotency-key")),
+        idempotency_key(Request::builder()),
+    );
+    assert!(!span.attributes.iter().any(|(_, value)| value.as_str() == "order-8e1f"));
+}
+
+#[test]
+fn unix_peers_are_recorded_when_enabled() {
+    let peer = ConnectionInfo {
+        unix_peer: Some(UnixPeer {
+            path: Some("/run/app.sock".into()),
+            uid: Some(1000),
+            pid: Some(4242),
+        }),
+        ..ConnectionInfo::default()
+    };
+    let span = span_for(|layer| layer.with_unix_peer(true), on_connection(Version::HTTP_11, peer.clone()));
+    assert_eq!(attribute(&span, &NET_PEER_NAME), Some(Value::from("/run/app.sock")));
+    assert_eq!(attribute(&span, &PEER_UID), Some(Value::I64(1000)));
+    assert_eq!(attribute(&span, &PEER_PID), Some(Value::I64(4242)));
+
+    let span = span_for(|layer| layer, on_connection(Version::HTTP_11, peer.clone()));
+    assert_eq!(attribute(&span, &PEER_UID), None);
+    let tcp = ConnectionInfo {
+        peer_addr: Some(([10, 0, 0, 1], 443).into()),
+        ..peer
+    };
+    let span = span_for(|layer| layer.with_unix_peer(true), on_connection(Version::HTTP_11, tcp));
+    assert_eq!(attribute(&span, &PEER_UID), None);
+    assert_eq!(attribute(&span, &PEER_PID), None);
+}
+
+#[test]
+fn request_headers_are_recorded_as_configured() {
+    const FIREFOX: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:109.0) Gecko/20100101 Firefox/115.0";
+    const REFERER: &str = "https://example.com/login?token=secret";
+    let with_header = |name, value| Request::builder().header(name, value).body(()).unwrap();
+    let with_user_agent = |user_agent| with_header(header::USER_AGENT, user_agent);
+    let classified = |layer: Layer| layer.with_user_agent(UserAgentRecording::Classified);
+    let omitted = |layer: Layer| layer.with_user_agent(UserAgentRecording::Omit);
+    let with_referer = |referer| with_header(header::REFERER, referer);
+    let cases: Vec<RequestCase> = vec![
+        (|layer| layer, with_user_agent(FIREFOX), HTTP_USER_AGENT, Some(Value::from(FIREFOX))),
+        (|layer| layer, with_user_agent(FIREFOX), HTTP_USER_AGENT_CLASS, None),
+        (classified, with_user_agent(FIREFOX), HTTP_USER_AGENT_CLASS, Some(Value::from("browser"))),
+        (classified, with_user_agent(FIREFOX), HTTP_USER_AGENT, None),
+        (classified, with_user_agent("Googlebot/2.1"), HTTP_USER_AGENT_CLASS, Some(Value::from("bot"))),
+        (classified, with_user_agent("curl/8.0.1"), HTTP_USER_AGENT_CLASS, Some(Value::from("bot"))),
+        (classified, with_user_agent("okhttp/4.9"), HTTP_USER_AGENT_CLASS, Some(Value::from("unknown"))),
+        (omitted, with_user_agent(FIREFOX), HTTP_USER_AGENT, None),
+        (omitted, with_user_agent(FIREFOX), HTTP_USER_AGENT_CLASS, None),
+        (|layer| layer, with_referer(REFERER), HTTP_REQUEST_HEADER_REFERER, None),
+        (
+            |layer| layer.with_referer(RefererRecording::WithoutQuery),
+            with_referer(REFERER),
+            HTTP_REQUEST_HEADER_REFERER,
+            Some(Value::from("https://example.com/login")),
+        ),
+        (
+            |layer| layer.with_referer(RefererRecording::WithoutQuery),
+            with_referer("https://example.com/docs#install"),
+            HTTP_REQUEST_HEADER_REFERER,
+            Some(Value::from("https://example.com/docs")),
+        ),
+        (
+            |layer| layer.with_referer(RefererRecording::Full),
+            with_referer(REFERER),
+            HTTP_REQUEST_HEADER_REFERER,
+            Some(Value::from(REFERER)),
+        ),
+        (
+            |layer| layer.with_accept_encoding(true),
+            with_header(header::ACCEPT_ENCODING, "gzip, br"),
+            HTTP_REQUEST_HEADER_ACCEPT_ENCODING,
+            Some(Value::from("gzip, br")),
+        ),
+        (|layer| layer.with_accept_encoding(true), Request::new(()), HTTP_REQUEST_HEADER_ACCEPT_ENCODING, None),
+        (|layer| layer, with_header(header::ACCEPT_ENCODING, "gzip"), HTTP_REQUEST_HEADER_ACCEPT_ENCODING, None),
+        (
+            |layer| layer.with_accept(true),
+            with_header(header::ACCEPT, "application/json, */*;q=0.1"),
+            HTTP_REQUEST_HEADER_ACCEPT,
+            Some(Value::from("application/json, */*;q=0.1")),
+        ),
+        (|layer| layer.with_accept(true), Request::new(()), HTTP_REQUEST_HEADER_ACCEPT, None),
+        (|layer| layer, with_header(header::ACCEPT, "text/html"), HTTP_REQUEST_HEADER_ACCEPT, None),
+    ];
+    for (configure, req, key, expected) in cases {
+        assert_eq!(attribute(&span_for(configure, req), &key), expected, "{}", key.as_str());
+    }
+}
+
+/// A layer configuration, the response header it sees, and the attribute it should record.
+type ResponseCase = (fn(Layer) -> Layer, Option<(HeaderName, &'static str)>, Key, Option<Value>);
+
+#[test]
+fn response_headers_are_recorded_as_configured() {
+    let cases: Vec<ResponseCase> = vec![
+        (
+            |layer| layer.with_content_language(true),
+            Some((header::CONTENT_LANGUAGE, "de-DE")),
+            HTTP_RESPONSE_HEADER_CONTENT_LANGUAGE,
+            Some(Value::from("de-DE")),
+        ),
+        (|layer| layer.with_content_language(true), None, HTTP_RESPONSE_HEADER_CONTENT_LANGUAGE, None),
+        (|layer| layer.with_response_age(true), Some((header::AGE, "120")), HTTP_RESPONSE_HEADER_AGE, Some(Value::I64(120))),
+        (|layer| layer.with_response_age(true), Some((header::AGE, "soon")), HTTP_RESPONSE_HEADER_AGE, None),
+        (|layer| layer.with_response_age(true), Some((header::AGE, "-5")), HTTP_RESPONSE_HEADER_AGE, None),
+        (
+            |layer| layer.with_response_content_type(true),
+            Some((header::CONTENT_TYPE, "application/json; charset=utf-8")),
+            HTTP_RESPONSE_CONTENT_TYPE,
+            Some(Value::from("application/json; charset=utf-8")),
+        ),
+        (|layer| layer.with_response_content_type(true), None, HTTP_RESPONSE_CONTENT_TYPE, None),
+        (|layer| layer, Some((header::CONTENT_TYPE, "text/html")), HTTP_RESPONSE_CONTENT_TYPE, None),
+        (
+            |layer| layer.with_cache_hit_header(HeaderName::from_static("x-cache"), |value| value.starts_with("HIT")),
+            Some((HeaderName::from_static("x-cache"), "HIT from edge")),
+            CACHE_HIT,
+            Some(Value::Bool(true)),
+        ),
+        (
+            |layer| layer.with_cache_hit_header(HeaderName::from_static("x-cache"), |value| value.starts_with("HIT")),
+            Some((HeaderName::from_static("x-cache"), "MISS")),
+            CACHE_HIT,
+            Some(Value::Bool(false)),
+        ),
+        (
+            |layer| layer.with_cache_hit_header(HeaderName::from_static("x-cache"), |value| value.starts_with("HIT")),
+            None,
+            CACHE_HIT,
+            None,
+        ),
+        (|layer| layer.with_status_text(true), None, HTTP_STATUS_TEXT, Some(Value::from("OK"))),
+        (|layer| layer, None, HTTP_STATUS_TEXT, None),
+        (
+            |layer| layer.with_content_encoding(true),
+            Some((header::CONTENT_ENCODING, "gzip")),
+            HTTP_RESPONSE_HEADER_CONTENT_ENCODING,
+            Some(Value::from("gzip")),
+        ),
+        (|layer| layer.with_content_encoding(true), None, HTTP_RESPONSE_HEADER_CONTENT_ENCODING, None),
+        (|layer| layer, Some((header::CONTENT_ENCODING, "br")), HTTP_RESPONSE_HEADER_CONTENT_ENCODING, None),
+    ];
+    for (configure, header, key, expected) in cases {
+        assert_eq!(attribute(&span_for_response(configure, header), &key), expected, "{}", key.as_str());
+    }
+}
+
+/// The controller of the SDK meter provider installed globally for the metrics tests.
+#[cfg(feature = "metrics")]
+struct Measurements(opentelemetry::sdk::metrics::PullController);
+
+#[cfg(feature = "metrics")]
+impl Measurements {
+    /// Install the meter provider if no test has yet, and hold it until the guard is dropped.
+    ///
+    /// Instruments are bound to the global provider when a layer enabling them is built, so
+    /// tests lock this first. Holding the lock keeps other metrics tests from recording while a
+    /// test compares its measurements.
+    fn lock() -> std::sync::MutexGuard<'static, Self> {
+        use opentelemetry::sdk::{export::metrics::ExportKindSelector, metrics::selectors::simple::Selector};
+
+        lazy_static! {
+            static ref MEASUREMENTS: Mutex<Measurements> = {
+                let controller = opentelemetry::sdk::metrics::controllers::pull(
+                    Box::new(Selector::Inexpensive),
+                    Box::new(ExportKindSelector::Cumulative),
+                )
+                .with_cache_period(std::time::Duration::ZERO)
+                .build();
+                global::set_meter_provider(controller.provider());
+                Mutex::new(Measurements(controller))
+            };
+        }
+        MEASUREMENTS.lock().unwrap_or_else(std::sync::PoisonError::into_inner)
+    }
+
+    /// The total of the counter `name`, or the number of values recorded by the value recorder
+    /// `name`, over the attribute sets that carry all of `attributes`.
+    fn count(&mut self, name: &str, attributes: &[KeyValue]) -> u64 {
+        use opentelemetry::sdk::{
+            export::metrics::{CheckpointSet, Count, ExportKindSelector, Sum},
+            metrics::aggregators::{MinMaxSumCountAggregator, SumAggregator},
+        };
+
+        self.0.collect().unwrap();
+        let mut total = 0;
+        self.0
+            .try_for_each(&ExportKindSelector::Cumulative, &mut |record| {
+                let matches = attributes.iter().all(|expected| {
+                    record.attributes().iter().any(|(key, value)| *key == expected.key && *value == expected.value)
+                });
+                let Some(aggregator) = record.aggregator().filter(|_| record.descriptor().name() == name && matches)
+                else {
+                    return Ok(());
+                };
+                let any = aggregator.as_any();
+                if let Some(sum) = any.downcast_ref::<SumAggregator>() {
+                    total += sum.sum()?.to_u64(record.descriptor().number_kind());
+                } else if let Some(recorder) = any.downcast_ref::<MinMaxSumCountAggregator>() {
+                    total += recorder.count()?;
+                }
+                Ok(())
+            })
+            .unwrap();
+        total
+    }
+}
+
+/// A service that isn't ready on its first poll if created with `true`.
+#[derive(Clone)]
+struct NotReadyOnce(bool);
+
+impl tower_service::Service<Request<()>> for NotReadyOnce {
+    type Error = Infallible;
+    type Future = futures_util::future::Ready<Result<Response<()>, Infallible>>;
+    type Response = Response<()>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Infallible>> {
+        if std::mem::replace(&mut self.0, false) {
+            Poll::Pending
+        } else {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    fn call(&mut self, _req: Request<()>) -> Self::Future {
+        futures_util::future::ready(Ok(Response::new(())))
+    }
+}
+
+#[test]
+fn unsampled_spans_are_not_injected_into_the_response() {
+    let provider = provider(Sampler::AlwaysOff);
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let res = call(&layer, Request::new(()));
+    assert!(res.headers().get("traceparent").is_none());
+}
+
+#[test]
+fn sampled_spans_are_injected_into_the_response() {
+    let provider = provider(Sampler::AlwaysOn);
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let res = call(&layer, Request::new(()));
+    assert!(res.headers().get("traceparent").is_some());
+}
+
+#[test]
+fn traceresponse_and_trace_id_headers_echo_the_span_context() {
+    let provider = provider(Sampler::AlwaysOn);
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_traceresponse(true)
+        .with_trace_id_header(HeaderName::from_static("x-trace-id"));
+    let res = call(&layer, Request::new(()));
+    let traceparent = res.headers()["traceparent"].to_str().unwrap();
+    assert_eq!(res.headers()["traceresponse"], traceparent);
+    assert_eq!(res.headers()["x-trace-id"], traceparent[3..35]);
+}
+
+#[test]
+fn legacy_trace_id_header_continues_the_trace() {
+    let provider = provider(Sampler::AlwaysOn);
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_legacy_trace_id_header(HeaderName::from_static("x-correlation-id"));
+    let req = Request::builder()
+        .header("x-correlation-id", "4bf92f35-77b3-4da6-a3ce-929d0e0e4736")
+        .body(())
+        .unwrap();
+    let res = call(&layer, req);
+    let traceparent = res.headers()["traceparent"].to_str().unwrap();
+    assert_eq!(&traceparent[3..35], "4bf92f3577b34da6a3ce929d0e0e4736");
+}
+
+#[test]
+fn query_trace_context_is_a_fallback_for_headers() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_query_trace_context("tp");
+    let query = "/hook?x=1&tp=00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    call(&layer, Request::builder().uri(query).body(()).unwrap());
+    let req = Request::builder()
+        .uri(query)
+        .header("traceparent", "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-01")
+        .body(())
+        .unwrap();
+    call(&layer, req);
+    let spans = recorder.spans();
+    assert_eq!(format!("{:032x}", spans[0].span_context.trace_id()), "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert_eq!(format!("{:032x}", spans[1].span_context.trace_id()), "0af7651916cd43dd8448eb211c80319c");
+}
+
+#[test]
+fn allowlisted_baggage_is_recorded_as_attributes() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_baggage_attributes(["user.tier", "region"]);
+    let req = Request::builder()
+        .header("baggage", "user.tier=gold,session=abc")
+        .body(())
+        .unwrap();
+    call(&layer, req);
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &Key::new("baggage.user.tier")), Some(Value::from("gold")));
+    assert_eq!(attribute(&span, &Key::new("baggage.region")), None);
+    assert_eq!(attribute(&span, &Key::new("baggage.session")), None);
+}
+
+#[test]
+fn services_can_be_built_without_the_layer_trait() {
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let service = Service::new(
+        service_fn(|_req: Request<()>| async { Ok::<_, Infallible>(Response::new(())) }),
+        &Layer::new().with_tracer_provider(&provider),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    assert_eq!(recorder.single().span_kind, SpanKind::Server);
+}
+
+#[test]
+fn inbound_baggage_is_injected_into_outbound_requests() {
+    init_propagator();
+    let provider = provider(Sampler::AlwaysOn);
+    let client = tower_layer::Layer::layer(
+        &client::Layer::new().with_tracer_provider(&provider),
+        service_fn(|req: Request<()>| async move {
+            let mut res = Response::new(());
+            *res.headers_mut() = req.headers().clone();
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    let server = tower_layer::Layer::layer(
+        &Layer::new().with_tracer_provider(&provider),
+        service_fn(move |_req: Request<()>| {
+            let client = client.clone();
+            async move {
+                let outbound = client.oneshot(Request::new(())).await?;
+                assert_eq!(
+                    Context::current().baggage().get("tenant").map(ToString::to_string),
+                    Some("acme".to_string())
+                );
+                Ok::<_, Infallible>(outbound)
+            }
+        }),
+    );
+    let req = Request::builder()
+        .header("baggage", "tenant=acme")
+        .body(())
+        .unwrap();
+    let outbound = futures_executor::block_on(server.oneshot(req)).unwrap();
+    assert_eq!(outbound.headers()["baggage"], "tenant=acme");
+    assert!(outbound.headers().get("traceparent").is_some());
+}
+
+#[test]
+fn client_layers_inject_with_their_own_propagator() {
+    init_propagator();
+    let provider = provider(Sampler::AlwaysOn);
+    let echo = service_fn(|req: Request<()>| async move {
+        let mut res = Response::new(());
+        *res.headers_mut() = req.headers().clone();
+        Ok::<_, Infallible>(res)
+    });
+    let client = tower_layer::Layer::layer(
+        &client::Layer::new()
+            .with_tracer_provider(&provider)
+            .with_propagator(opentelemetry::sdk::propagation::BaggagePropagator::new()),
+        echo,
+    );
+    let _attached = Context::current_with_baggage(vec![KeyValue::new("tenant", "acme")]).attach();
+    let outbound = futures_executor::block_on(client.oneshot(Request::new(()))).unwrap();
+    assert_eq!(outbound.headers()["baggage"], "tenant=acme");
+    assert!(outbound.headers().get("traceparent").is_none());
+}
+
+#[test]
+fn inject_context_continues_the_current_span() {
+    init_propagator();
+    let provider = provider(Sampler::AlwaysOn);
+    let server = tower_layer::Layer::layer(
+        &Layer::new().with_tracer_provider(&provider),
+        service_fn(|_req: Request<()>| async move {
+            let mut outbound = Request::new(());
+            client::inject_context(outbound.headers_mut());
+            let mut res = Response::new(());
+            res.extensions_mut().insert(Context::current().span().span_context().trace_id());
+            *res.headers_mut() = outbound.headers().clone();
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    let res = futures_executor::block_on(server.oneshot(Request::new(()))).unwrap();
+    let trace_id = res.extensions().get::<TraceId>().unwrap();
+    let traceparent = res.headers()["traceparent"].to_str().unwrap();
+    assert_eq!(traceparent[3..35], format!("{trace_id:032x}"));
+}
+
+#[test]
+fn configured_headers_are_forwarded_through_the_client_layer() {
+    init_propagator();
+    let provider = provider(Sampler::AlwaysOn);
+    let echo = service_fn(|req: Request<()>| async move {
+        let mut res = Response::new(());
+        *res.headers_mut() = req.headers().clone();
+        Ok::<_, Infallible>(res)
+    });
+    let forwarding = tower_layer::Layer::layer(
+        &client::Layer::new().with_tracer_provider(&provider).with_forwarded_headers(true),
+        echo,
+    );
+    let plain = tower_layer::Layer::layer(&client::Layer::new().with_tracer_provider(&provider), echo);
+    let server = tower_layer::Layer::layer(
+        &Layer::new()
+            .with_tracer_provider(&provider)
+            .with_forwarded_headers([HeaderName::from_static("x-tenant-id")]),
+        service_fn(move |_req: Request<()>| {
+            let (forwarding, plain) = (forwarding.clone(), plain.clone());
+            async move {
+                let plain = plain.oneshot(Request::new(())).await?;
+                assert!(plain.headers().get("x-tenant-id").is_none());
+                forwarding.oneshot(Request::new(())).await
+            }
+        }),
+    );
+    let req = Request::builder()
+        .header("x-tenant-id", "acme")
+        .header("x-session", "secret")
+        .body(())
+        .unwrap();
+    let outbound = futures_executor::block_on(server.oneshot(req)).unwrap();
+    assert_eq!(outbound.headers()["x-tenant-id"], "acme");
+    assert!(outbound.headers().get("x-session").is_none());
+}
+
+#[test]
+fn headers_are_forwarded_for_requests_without_a_span() {
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let echo = service_fn(|req: Request<()>| async move {
+        let mut res = Response::new(());
+        *res.headers_mut() = req.headers().clone();
+        Ok::<_, Infallible>(res)
+    });
+    let client = tower_layer::Layer::layer(
+        &client::Layer::new().with_tracer_provider(&provider).with_forwarded_headers(true),
+        echo,
+    );
+    let server = tower_layer::Layer::layer(
+        &Layer::new()
+            .with_tracer_provider(&provider)
+            .with_forwarded_headers([HeaderName::from_static("x-tenant-id")])
+            .with_excluded_paths(["/healthz"])
+            .with_request_sampler(|parts| {
+                if parts.headers.contains_key("x-synthetic") {
+                    SamplingDecision::Drop
+                } else {
+                    SamplingDecision::RecordAndSample
+                }
+            }),
+        service_fn(move |_req: Request<()>| client.clone().oneshot(Request::new(()))),
+    );
+    let dropped = Request::builder()
+        .header("x-synthetic", "1")
+        .header("x-tenant-id", "acme")
+        .header("x-tenant-id", "globex")
+        .body(())
+        .unwrap();
+    let excluded = Request::builder().uri("/healthz").header("x-tenant-id", "acme").body(()).unwrap();
+    for (req, expected) in [(dropped, vec!["acme", "globex"]), (excluded, vec!["acme"])] {
+        let outbound = futures_executor::block_on(server.clone().oneshot(req)).unwrap();
+        let tenants: Vec<_> = outbound.headers().get_all("x-tenant-id").iter().collect();
+        assert_eq!(tenants, expected);
+    }
+    assert!(recorder.spans().iter().all(|span| span.span_kind == SpanKind::Client));
+}
+
+#[test]
+fn context_propagates_between_two_services() {
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let downstream = tower_layer::Layer::layer(
+        &Layer::new().with_tracer_provider(&provider),
+        service_fn(|_req: Request<()>| async { Ok::<_, Infallible>(Response::new(())) }),
+    );
+    let client = tower_layer::Layer::layer(&client::Layer::new().with_tracer_provider(&provider), downstream);
+    let upstream = tower_layer::Layer::layer(
+        &Layer::new().with_tracer_provider(&provider),
+        service_fn(move |_req: Request<()>| client.clone().oneshot(Request::new(()))),
+    );
+    futures_executor::block_on(upstream.oneshot(Request::new(()))).unwrap();
+    let spans = recorder.spans();
+    let [downstream, client, upstream] = &spans[..] else {
+        panic!("expected three spans, got {}", spans.len());
+    };
+    assert_eq!(downstream.span_kind, SpanKind::Server);
+    assert_eq!(client.span_kind, SpanKind::Client);
+    assert_eq!(upstream.span_kind, SpanKind::Server);
+    assert_eq!(downstream.parent_span_id, client.span_context.span_id());
+    assert_eq!(client.parent_span_id, upstream.span_context.span_id());
+    assert_eq!(downstream.span_context.trace_id(), upstream.span_context.trace_id());
+    assert_eq!(upstream.parent_span_id, opentelemetry::trace::SpanId::INVALID);
+}
+
+fn spoofed_request() -> Request<()> {
+    let info = ConnectionInfo {
+        peer_addr: Some(([10, 0, 0, 1], 41_000).into()),
+        ..ConnectionInfo::default()
+    };
+    let mut req = Request::builder()
+        .uri("/spoofed")
+        .header("x-forwarded-proto", "https")
+        .header("x-forwarded-for", "203.0.113.7, 10.0.0.1")
+        .body(())
+        .unwrap();
+    req.extensions_mut().insert(info);
+    req
+}
+
+#[test]
+fn forwarded_headers_are_ignored_unless_trusted() {
+    let (provider, recorder) = recording_provider();
+    call(&Layer::new().with_tracer_provider(&provider), spoofed_request());
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &HTTP_SCHEME), None);
+    assert_eq!(attribute(&span, &HTTP_CLIENT_IP), Some("10.0.0.1".into()));
+}
+
+#[test]
+fn trusted_forwarded_headers_take_precedence() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_trusted_forwarded_headers(true);
+    call(&layer, spoofed_request());
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &HTTP_SCHEME), Some("https".into()));
+    assert_eq!(attribute(&span, &HTTP_CLIENT_IP), Some("203.0.113.7".into()));
+    assert_eq!(attribute(&span, &NET_PEER_IP), Some("10.0.0.1".into()));
+}
+
+#[test]
+fn absolute_form_uris_take_precedence_over_the_host_header() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_trusted_forwarded_headers(true)
+        .with_host_authority(true);
+    for uri in ["http://origin.example:8080/path?q=1", "/path?q=1"] {
+        let req = Request::builder()
+            .uri(uri)
+            .header(header::HOST, "proxy.local:9000")
+            .header("x-forwarded-proto", "https")
+            .body(())
+            .unwrap();
+        call(&layer, req);
+    }
+    let spans = recorder.spans();
+    assert_eq!(attribute(&spans[0], &HTTP_HOST), Some(Value::from("origin.example:8080")));
+    assert_eq!(attribute(&spans[0], &HTTP_SCHEME), Some(Value::from("https")));
+    assert_eq!(attribute(&spans[0], &HTTP_TARGET), Some(Value::from("/path?q=1")));
+    assert_eq!(attribute(&spans[1], &HTTP_HOST), Some(Value::from("proxy.local:9000")));
+    assert_eq!(attribute(&spans[1], &HTTP_SCHEME), Some(Value::from("https")));
+    assert_eq!(attribute(&spans[1], &HTTP_TARGET), Some(Value::from("/path?q=1")));
+}
+
+#[test]
+fn connection_encryption_takes_precedence_for_the_scheme() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_trusted_forwarded_headers(true);
+    for tls in [Some(true), Some(false), None] {
+        let mut req = Request::builder()
+            .uri("http://example.com/")
+            .header("x-forwarded-proto", "https")
+            .body(())
+            .unwrap();
+        req.extensions_mut().insert(ConnectionInfo { tls, ..ConnectionInfo::default() });
+        call(&layer, req);
+    }
+    call(&layer, Request::builder().uri("/").body(()).unwrap());
+    let schemes: Vec<_> = recorder.spans().iter().map(|span| attribute(span, &HTTP_SCHEME)).collect();
+    assert_eq!(
+        schemes,
+        [Some(Value::from("https")), Some(Value::from("http")), Some(Value::from("https")), None]
+    );
+}
+
+#[test]
+fn noop_service_passes_requests_through() {
+    init_propagator();
+    let service = Service::noop(service_fn(|_req: Request<()>| async {
+        assert!(!Context::current().span().span_context().is_valid());
+        Ok::<_, Infallible>(Response::new(()))
+    }));
+    let req = Request::builder()
+        .header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+        .body(())
+        .unwrap();
+    let res = futures_executor::block_on(service.oneshot(req)).unwrap();
+    assert!(res.headers().get("traceparent").is_none());
+}
+
+#[test]
+fn unsampled_spans_are_injected_when_always_enabled() {
+    let provider = provider(Sampler::AlwaysOff);
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_response_injection(ResponseInjection::Always);
+    let res = call(&layer, Request::new(()));
+    assert!(res.headers().get("traceparent").is_some());
+}
+
+#[test]
+fn request_start_time_is_recorded_in_the_configured_format() {
+    let (provider, recorder) = recording_provider();
+    let before = SystemTime::now();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_request_start_time(StartTimeFormat::EpochNanos);
+    call(&layer, Request::new(()));
+    let span = recorder.single();
+    match attribute(&span, &HTTP_REQUEST_START_TIME) {
+        Some(Value::I64(nanos)) => {
+            let before = before.duration_since(UNIX_EPOCH).unwrap().as_nanos();
+            assert!(u128::try_from(nanos).unwrap() >= before);
+        }
+        other => panic!("unexpected start time {:?}", other),
+    }
+}
+
+#[test]
+fn request_start_time_defaults_to_rfc3339_with_nanoseconds() {
+    let (provider, recorder) = recording_provider();
+    let before = SystemTime::now();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_request_start_time(StartTimeFormat::default());
+    call(&layer, Request::new(()));
+    let span = recorder.single();
+    match attribute(&span, &HTTP_REQUEST_START_TIME) {
+        Some(Value::String(text)) => {
+            let (_, fraction) = text.split_once('.').unwrap();
+            assert_eq!(fraction.len(), "000000000Z".len());
+            assert!(fraction.ends_with('Z'));
+            assert!(humantime::parse_rfc3339(&text).unwrap() >= before);
+        }
+        other => panic!("unexpected start time {:?}", other),
+    }
+}
+
+#[test]
+fn handlers_can_add_events_through_extensions() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_span_events(true);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|req: Request<()>| async move {
+            req.extensions().get::<SpanEvents>().unwrap().add("handler.event", Vec::new());
+            Ok::<_, Infallible>(Response::new(()))
+        }),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    let span = recorder.single();
+    assert!(span.events.iter().any(|event| event.name == "handler.event"));
+}
+
+#[test]
+fn query_strings_are_recorded_where_configured() {
+    let cases = [
+        (QueryRecording::Target, "/search", "/search?q=otel"),
+        (QueryRecording::TargetAndName, "/search?q=otel", "/search?q=otel"),
+        (QueryRecording::Omit, "/search", "/search"),
+    ];
+    for (query, name, target) in cases {
+        let (provider, recorder) = recording_provider();
+        let req = Request::builder().uri("/search?q=otel").body(()).unwrap();
+        call(&Layer::new().with_tracer_provider(&provider).with_query(query), req);
+        let span = recorder.single();
+        assert_eq!(span.name, name, "{query:?}");
+        assert_eq!(attribute(&span, &HTTP_TARGET), Some(Value::from(target)), "{query:?}");
+    }
+}
+
+#[test]
+fn deployment_environment_is_read_from_the_configured_variable() {
+    std::env::set_var("TOWER_OPENTELEMETRY_TEST_ENVIRONMENT", "staging");
+    let span = span_for(
+        |layer| layer.with_deployment_environment_var("TOWER_OPENTELEMETRY_TEST_ENVIRONMENT"),
+        Request::new(()),
+    );
+    assert_eq!(attribute(&span, &DEPLOYMENT_ENVIRONMENT), Some(Value::from("staging")));
+    let span = span_for(
+        |layer| layer.with_deployment_environment_var("TOWER_OPENTELEMETRY_TEST_UNSET"),
+        Request::new(()),
+    );
+    assert_eq!(attribute(&span, &DEPLOYMENT_ENVIRONMENT), None);
+}
+
+#[test]
+fn long_span_names_are_truncated_on_a_char_boundary() {
+    assert_eq!(truncate_span_name("/caf\u{e9}/menu".into(), 8), "/caf...");
+    assert_eq!(truncate_span_name("/short".into(), 8), "/short");
+    assert_eq!(truncate_span_name("/caf\u{e9}".into(), 5), "/c...");
+    assert_eq!(truncate_span_name("/\u{e9}t\u{e9}".into(), 3), "/\u{e9}");
+    for (max, truncated) in [(0, ""), (1, "/"), (2, "/u"), (3, "/us")] {
+        assert_eq!(truncate_span_name("/users".into(), max), truncated);
+    }
+    assert_eq!(truncate_span_name("/\u{e9}".into(), 2), "/");
+}
+
+#[test]
+fn spans_start_at_the_received_at_extension_when_enabled() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_received_at_start_time(true);
+    let received_at = SystemTime::now() - std::time::Duration::from_secs(5);
+    let mut req = Request::new(());
+    req.extensions_mut().insert(ReceivedAt(received_at));
+    call(&layer, req);
+    assert_eq!(recorder.single().start_time, received_at);
+}
+
+#[test]
+fn tracer_handle_extension_overrides_the_configured_tracer() {
+    let default_provider = provider(Sampler::AlwaysOn);
+    let (tenant_provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&default_provider);
+    let mut req = Request::new(());
+    req.extensions_mut().insert(TracerHandle::from_provider(&tenant_provider));
+    call(&layer, req);
+    recorder.single();
+}
+
+/// A provider that records the schema URL of every tracer it creates.
+#[derive(Default)]
+struct SchemaUrls(Mutex<Vec<Option<&'static str>>>);
+
+impl TracerProvider for SchemaUrls {
+    type Tracer = opentelemetry::trace::noop::NoopTracer;
+
+    fn versioned_tracer(
+        &self,
+        _name: impl Into<Cow<'static, str>>,
+        _version: Option<&'static str>,
+        schema_url: Option<&'static str>,
+    ) -> Self::Tracer {
+        self.0.lock().unwrap().push(schema_url);
+        opentelemetry::trace::noop::NoopTracer::new()
+    }
+
+    fn force_flush(&self) -> Vec<TraceResult<()>> {
+        Vec::new()
+    }
+}
+
+#[test]
+fn tracer_handles_report_the_given_schema_url() {
+    let provider = SchemaUrls::default();
+    let _ = TracerHandle::from_provider(&provider);
+    let _ = TracerHandle::from_provider_with_schema_url(&provider, "https://opentelemetry.io/schemas/1.9.0");
+    assert_eq!(*provider.0.lock().unwrap(), [None, Some("https://opentelemetry.io/schemas/1.9.0")]);
+}
+
+#[test]
+fn layers_report_their_schema_url_to_later_providers() {
+    let schema_url = "https://opentelemetry.io/schemas/1.9.0";
+    let provider = SchemaUrls::default();
+    let _ = Layer::new().with_tracer_provider(&provider);
+    let _ = Layer::new().with_schema_url(schema_url).with_tracer_provider(&provider);
+    assert_eq!(*provider.0.lock().unwrap(), [None, Some(schema_url)]);
+}
+
+#[test]
+fn privacy_mode_drops_the_url_and_hashes_addresses() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_privacy_mode();
+    let mut req = spoofed_request();
+    *req.uri_mut() = "/spoofed?token=secret".parse().unwrap();
+    req.headers_mut().insert(header::USER_AGENT, HeaderValue::from_static("curl/7.79.1"));
+    call(&layer, req);
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &HTTP_URL), None);
+    assert_eq!(attribute(&span, &HTTP_USER_AGENT), None);
+    assert_eq!(attribute(&span, &HTTP_TARGET), Some("/spoofed".into()));
+    let peer_ip = attribute(&span, &NET_PEER_IP).unwrap().as_str().into_owned();
+    assert_ne!(peer_ip, "10.0.0.1");
+}
+
+#[test]
+fn request_ids_are_reused_or_generated_and_echoed() {
+    let provider = provider(Sampler::AlwaysOff);
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_request_id(HeaderName::from_static("x-request-id"))
+        .with_request_id_generator(|| "generated".to_string());
+    let res = call(&layer, Request::new(()));
+    assert_eq!(res.headers()["x-request-id"], "generated");
+    let req = Request::builder().header("x-request-id", "inbound").body(()).unwrap();
+    let res = call(&layer, req);
+    assert_eq!(res.headers()["x-request-id"], "inbound");
+}
+
+#[test]
+fn request_ids_are_recorded_and_echoed_on_error_responses() {
+    let (provider, recorder) = recording_provider();
+    let service = tower_layer::Layer::layer(
+        &Layer::new()
+            .with_tracer_provider(&provider)
+            .with_request_id(HeaderName::from_static("x-request-id")),
+        service_fn(|req: Request<()>| async move {
+            let mut res = Response::new(());
+            res.extensions_mut().insert(req.headers()["x-request-id"].clone());
+            *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    let res = futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    let id = res.headers()["x-request-id"].to_str().unwrap();
+    assert_eq!(res.extensions().get::<HeaderValue>().unwrap(), id);
+    assert!(uuid::Uuid::parse_str(id).is_ok());
+    let span = recorder.single();
+    assert_eq!(span.status_code, StatusCode::Error);
+    assert_eq!(attribute(&span, &HTTP_REQUEST_ID), Some(Value::from(id.to_string())));
+}
+
+#[test]
+fn connect_spans_stay_open_for_the_tunnel_when_enabled() {
+    let (provider, recorder) = recording_provider();
+    let connect = || Request::builder().method(Method::CONNECT).uri("example.com:443").body(()).unwrap();
+    let mut res = call(&Layer::new().with_tracer_provider(&provider).with_tunnel_spans(true), connect());
+    let tunnel = res.extensions_mut().remove::<Tunnel>().unwrap();
+    assert!(tunnel.context().span().span_context().is_valid());
+    assert!(recorder.spans().is_empty());
+    tunnel.end();
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &HTTP_TUNNEL), Some(Value::Bool(true)));
+
+    let res = call(&Layer::new().with_tracer_provider(&provider), connect());
+    assert!(res.extensions().get::<Tunnel>().is_none());
+    assert_eq!(recorder.spans().len(), 2);
+}
+
+#[cfg(feature = "grpc")]
+#[test]
+fn grpc_trace_bin_metadata_is_extracted_and_injected() {
+    let mut metadata = http::HeaderMap::new();
+    metadata.insert("content-type", HeaderValue::from_static("application/grpc"));
+    metadata.insert(
+        "grpc-trace-bin",
+        HeaderValue::from_static("AABL+S81d7NNpqPOkp0ODkc2AQDwZ6oLqQK3AgE"),
+    );
+    let propagator = GrpcTraceBinPropagator::new();
+    let cx = propagator.extract(&HeaderCarrier::new(&mut metadata));
+    let span_context = cx.span().span_context().clone();
+    assert_eq!(span_context.trace_id(), TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap());
+    assert_eq!(span_context.span_id(), opentelemetry::trace::SpanId::from_hex("00f067aa0ba902b7").unwrap());
+    assert!(span_context.is_sampled() && span_context.is_remote());
+
+    let mut outbound = http::HeaderMap::new();
+    propagator.inject_context(&cx, &mut HeaderCarrier::new(&mut outbound));
+    assert_eq!(outbound["grpc-trace-bin"], metadata["grpc-trace-bin"]);
+}
+
+#[test]
+fn trusted_hops_select_from_the_right_of_the_chain() {
+    let client_ip = |hops: usize, chain: &[&str]| {
+        let (provider, recorder) = recording_provider();
+        let layer = Layer::new()
+            .with_tracer_provider(&provider)
+            .with_trusted_forwarded_headers(true)
+            .with_trusted_hops(hops);
+        let mut req = Request::new(());
+        for value in chain {
+            req.headers_mut().append("x-forwarded-for", HeaderValue::from_str(value).unwrap());
+        }
+        call(&layer, req);
+        attribute(&recorder.single(), &HTTP_CLIENT_IP)
+    };
+    let chain = ["198.51.100.9, 2001:db8::7", "[2001:db8::1]:443, 10.0.0.2"];
+    assert_eq!(client_ip(1, &chain), Some("10.0.0.2".into()));
+    assert_eq!(client_ip(2, &chain), Some("2001:db8::1".into()));
+    assert_eq!(client_ip(3, &chain), Some("2001:db8::7".into()));
+    assert_eq!(client_ip(9, &chain), Some("198.51.100.9".into()));
+    assert_eq!(client_ip(0, &chain), Some("198.51.100.9".into()));
+}
+
+#[test]
+fn trusted_hops_are_independent_of_trusting_forwarded_headers() {
+    let client_ip = |layer: Layer| {
+        let (provider, recorder) = recording_provider();
+        let req = Request::builder().header("x-forwarded-for", "198.51.100.9, 10.0.0.2").body(()).unwrap();
+        call(&layer.with_tracer_provider(&provider), req);
+        attribute(&recorder.single(), &HTTP_CLIENT_IP)
+    };
+    assert_eq!(client_ip(Layer::new().with_trusted_hops(1)), None);
+    let trusted = Layer::new().with_trusted_forwarded_headers(true);
+    assert_eq!(client_ip(trusted.with_trusted_hops(0)), Some("198.51.100.9".into()));
+    let trusted = Layer::new().with_trusted_forwarded_headers(true);
+    assert_eq!(client_ip(trusted.with_trusted_hops(1)), Some("10.0.0.2".into()));
+}
+
+#[test]
+fn structured_exceptions_record_the_error_type() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_structured_exceptions(true);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|_req: Request<()>| async {
+            Err::<Response<()>, _>(std::io::Error::new(std::io::ErrorKind::Other, "connection reset"))
+        }),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap_err();
+    let span = recorder.single();
+    let event = span.events.iter().find(|event| event.name == "exception").unwrap();
+    let value = |key: &Key| event.attributes.iter().find(|kv| &kv.key == key).map(|kv| kv.value.clone());
+    assert_eq!(value(&EXCEPTION_TYPE), Some("std::io::error::Error".into()));
+    assert_eq!(value(&EXCEPTION_MESSAGE), Some("connection reset".into()));
+}
+
+#[test]
+fn errors_record_a_low_cardinality_error_type() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_error_type(|error| (error.to_string() == "overloaded").then_some("overloaded"));
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|req: Request<()>| async move {
+            let error = match req.uri().path() {
+                "/timeout" => std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out"),
+                "/overloaded" => std::io::Error::new(std::io::ErrorKind::Other, "overloaded"),
+                _ => std::io::Error::new(std::io::ErrorKind::Other, "connection reset"),
+            };
+            Err::<Response<()>, _>(error)
+        }),
+    );
+    for uri in ["/timeout", "/overloaded", "/reset"] {
+        let req = Request::builder().uri(uri).body(()).unwrap();
+        futures_executor::block_on(service.clone().oneshot(req)).unwrap_err();
+    }
+    let types: Vec<_> = recorder.spans().iter().map(|span| attribute(span, &ERROR_TYPE)).collect();
+    assert_eq!(
+        types,
+        [
+            Some(Value::from("timeout")),
+            Some(Value::from("overloaded")),
+            Some(Value::from("std::io::error::Error")),
+        ]
+    );
+}
+
+#[test]
+fn error_types_of_failed_requests_follow_the_attribute_keys() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_attribute_keys([("error.type", "app.error_kind")]);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|_: Request<()>| async {
+            Err::<Response<()>, _>(std::io::Error::new(std::io::ErrorKind::TimedOut, "timed out"))
+        }),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap_err();
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &ERROR_TYPE), None);
+    assert_eq!(attribute(&span, &Key::new("app.error_kind")), Some(Value::from("timeout")));
+}
+
+#[test]
+fn host_port_is_explicit_or_derived_from_a_known_scheme() {
+    let host_port = |uri: &str, host: Option<&str>, proto: Option<&str>| {
+        let (provider, recorder) = recording_provider();
+        let layer = Layer::new()
+            .with_tracer_provider(&provider)
+            .with_trusted_forwarded_headers(true);
+        let mut req = Request::builder().uri(uri);
+        if let Some(host) = host {
+            req = req.header(header::HOST, host);
+        }
+        if let Some(proto) = proto {
+            req = req.header("x-forwarded-proto", proto);
+        }
+        call(&layer, req.body(()).unwrap());
+        attribute(&recorder.single(), &NET_HOST_PORT)
+    };
+    assert_eq!(host_port("http://example.com:8080/", None, None), Some(Value::I64(8080)));
+    assert_eq!(host_port("/", Some("example.com:8443"), None), Some(Value::I64(8443)));
+    assert_eq!(host_port("https://example.com/", None, None), Some(Value::I64(443)));
+    assert_eq!(host_port("/", Some("example.com"), Some("https")), Some(Value::I64(443)));
+    assert_eq!(host_port("/", Some("example.com"), Some("http")), Some(Value::I64(80)));
+    assert_eq!(host_port("/", Some("example.com"), None), None);
+}
+
+#[test]
+fn link_headers_are_deduplicated_and_capped() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_link_header(HeaderName::from_static("x-batch-traceparents"), 2);
+    let first = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    let second = "00-0af7651916cd43dd8448eb211c80319c-b7ad6b7169203331-00";
+    let third = "00-5cf92f3577b34da6a3ce929d0e0e4736-10f067aa0ba902b7-01";
+    let req = Request::builder()
+        .header("x-batch-traceparents", format!("{first}, not-a-context, {first}"))
+        .header("x-batch-traceparents", format!("{second},{third}"))
+        .body(())
+        .unwrap();
+    call(&layer, req);
+    let span = recorder.single();
+    let linked: Vec<_> = span.links.iter().map(|link| link.span_context().trace_id()).collect();
+    assert_eq!(
+        linked,
+        vec![
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap(),
+            TraceId::from_hex("0af7651916cd43dd8448eb211c80319c").unwrap(),
+        ]
+    );
+}
+
+#[test]
+fn excluded_paths_are_not_traced() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_excluded_paths(vec!["/healthz"]);
+    let res = call(&layer, Request::builder().uri("/healthz").body(()).unwrap());
+    assert!(res.headers().get("traceparent").is_none());
+    assert!(recorder.spans().is_empty());
+    call(&layer, Request::builder().uri("/healthz/deep").body(()).unwrap());
+    assert_eq!(recorder.single().name, "/healthz/deep");
+}
+
+#[test]
+fn excluded_methods_are_not_traced() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_excluded_methods(vec![Method::OPTIONS])
+        .with_excluded_paths(vec!["/healthz"]);
+    let res = call(&layer, Request::builder().method(Method::OPTIONS).uri("/users").body(()).unwrap());
+    assert!(res.headers().get("traceparent").is_none());
+    call(&layer, Request::builder().uri("/healthz").body(()).unwrap());
+    assert!(recorder.spans().is_empty());
+    call(&layer, Request::builder().method(Method::POST).uri("/users").body(()).unwrap());
+    assert_eq!(attribute(&recorder.single(), &HTTP_METHOD), Some(Value::from("POST")));
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn excluded_requests_are_counted_by_method() {
+    let mut measurements = Measurements::lock();
+    let options = [HTTP_METHOD.string("OPTIONS")];
+    let get = [HTTP_METHOD.string("GET")];
+    let excluded = |measurements: &mut Measurements| {
+        (
+            measurements.count("tower_otel.excluded_requests", &options),
+            measurements.count("tower_otel.excluded_requests", &get),
+        )
+    };
+    let before = excluded(&mut measurements);
+    let provider = provider(Sampler::AlwaysOn);
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_excluded_methods(vec![Method::OPTIONS])
+        .with_excluded_paths(vec!["/healthz"])
+        .with_metrics(true);
+    call(&layer, Request::builder().uri("/healthz").body(()).unwrap());
+    call(&layer.clone().with_exclusion_metric(true), Request::builder().method(Method::OPTIONS).body(()).unwrap());
+    call(&layer.with_exclusion_metric(true), Request::builder().uri("/healthz").body(()).unwrap());
+    assert_eq!(excluded(&mut measurements), (before.0 + 1, before.1 + 1));
+}
+
+#[test]
+fn attribute_keys_are_renamed() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_attribute_keys([("http.status_code", "http.response.status_code"), ("http.method", "http.request.method")]);
+    call(&layer, Request::builder().uri("/users").body(()).unwrap());
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &HTTP_METHOD), None);
+    assert_eq!(attribute(&span, &HTTP_STATUS_CODE), None);
+    assert_eq!(attribute(&span, &Key::new("http.request.method")), Some(Value::from("GET")));
+    assert_eq!(attribute(&span, &Key::new("http.response.status_code")), Some(Value::I64(200)));
+    assert_eq!(attribute(&span, &HTTP_TARGET), Some(Value::from("/users")));
+}
+
+#[test]
+fn router_labels_are_read_from_the_request_and_response() {
+    let mut req = Request::new(());
+    req.extensions_mut().insert(RouterLabel::new("public"));
+    let span = span_for(|layer| layer, req);
+    assert_eq!(attribute(&span, &HTTP_ROUTER), Some(Value::from("public")));
+
+    let (provider, recorder) = recording_provider();
+    let service = tower_layer::Layer::layer(
+        &Layer::new().with_tracer_provider(&provider),
+        service_fn(|_req: Request<()>| async {
+            let mut res = Response::new(());
+            res.extensions_mut().insert(RouterLabel::new("admin"));
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    assert_eq!(attribute(&recorder.single(), &HTTP_ROUTER), Some(Value::from("admin")));
+}
+
+#[test]
+fn minimal_attributes_keep_only_method_route_and_status() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_minimal_attributes(true);
+    let mut req = Request::builder()
+        .uri("https://example.com/users/42?page=2")
+        .header("user-agent", "curl/8.0")
+        .body(())
+        .unwrap();
+    req.extensions_mut().insert(MatchedRoute::new("/users/:id"));
+    call(&layer, req);
+    let span = recorder.single();
+    let mut keys: Vec<_> = span.attributes.iter().map(|(key, _)| key.as_str().to_string()).collect();
+    keys.sort();
+    assert_eq!(keys, vec!["http.method", "http.route", "http.status_code"]);
+}
+
+#[test]
+fn minimal_attributes_for_methods_skip_the_request_details() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_minimal_attributes_for([Method::HEAD]);
+    for method in [Method::HEAD, Method::GET] {
+        let req = Request::builder().method(method).uri("/healthz").header("user-agent", "kube-probe/1.27");
+        call(&layer, req.body(()).unwrap());
+    }
+    let spans = recorder.spans();
+    let mut keys: Vec<_> = spans[0].attributes.iter().map(|(key, _)| key.as_str().to_string()).collect();
+    keys.sort();
+    assert_eq!(keys, vec!["http.method", "http.status_code"]);
+    assert_eq!(attribute(&spans[1], &HTTP_TARGET), Some(Value::from("/healthz")));
+    assert_eq!(attribute(&spans[1], &HTTP_USER_AGENT), Some(Value::from("kube-probe/1.27")));
+}
+
+#[test]
+fn bare_spans_build_without_an_attribute_list() {
+    let (provider, recorder) = recording_provider();
+    let allocated = Arc::new(Mutex::new(None));
+    let seen = allocated.clone();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_bare_spans(true)
+        .with_span_builder_hook(move |_, builder| {
+            *seen.lock().unwrap() = Some(builder.attributes.is_some());
+        });
+    let req = Request::builder()
+        .uri("http://example.com/users/1?page=2")
+        .header(header::USER_AGENT, "curl/8.0")
+        .body(())
+        .unwrap();
+    call(&layer, req);
+    assert_eq!(*allocated.lock().unwrap(), Some(false));
+    let span = recorder.single();
+    let keys: Vec<_> = span.attributes.iter().map(|(key, _)| key.as_str().to_string()).collect();
+    assert_eq!(keys, vec!["http.status_code"]);
+}
+
+/// Counts the allocations made on each thread, so a test can check a path doesn't allocate.
+struct CountingAllocator;
+
+thread_local! {
+    static ALLOCATIONS: std::cell::Cell<usize> = const { std::cell::Cell::new(0) };
+}
+
+// SAFETY: every call is forwarded to the system allocator unchanged.
+unsafe impl std::alloc::GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: std::alloc::Layout) -> *mut u8 {
+        let _ = ALLOCATIONS.try_with(|count| count.set(count.get() + 1));
+        std::alloc::System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: std::alloc::Layout) {
+        std::alloc::System.dealloc(ptr, layout);
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+/// The number of allocations `f` makes on the current thread.
+fn allocations(f: impl FnOnce()) -> usize {
+    let before = ALLOCATIONS.with(std::cell::Cell::get);
+    f();
+    ALLOCATIONS.with(std::cell::Cell::get) - before
+}
+
+#[test]
+fn bare_spans_allocate_no_request_attributes() {
+    let req = Request::builder()
+        .uri("http://example.com/users/1?page=2")
+        .header(header::USER_AGENT, "curl/8.0")
+        .body(())
+        .unwrap();
+    let (bare, full) = (Layer::new().with_bare_spans(true), Layer::new());
+    assert_eq!(allocations(|| drop(bare.config.request_attributes(&req))), 0);
+    assert_ne!(allocations(|| drop(full.config.request_attributes(&req))), 0);
+}
+
+#[test]
+fn bare_spans_keep_explicitly_enabled_request_headers() {
+    let span = span_for(
+        |layer| {
+            layer
+                .with_bare_spans(true)
+                .with_accept(true)
+                .with_idempotency_key_header(HeaderName::from_static("idempotency-key"))
+                .with_retry_header(HeaderName::from_static("x-retry"), |value| value != "0")
+        },
+        Request::builder()
+            .header(header::ACCEPT, "application/json")
+            .header("idempotency-key", "abc")
+            .header("x-retry", "1")
+            .body(())
+            .unwrap(),
+    );
+    assert_eq!(attribute(&span, &HTTP_REQUEST_HEADER_ACCEPT), Some(Value::from("application/json")));
+    assert_eq!(attribute(&span, &HTTP_REQUEST_HAS_IDEMPOTENCY_KEY), Some(Value::Bool(true)));
+    assert_eq!(attribute(&span, &HTTP_REQUEST_IS_RETRY), Some(Value::Bool(true)));
+    assert_eq!(attribute(&span, &HTTP_METHOD), None);
+}
+
+#[test]
+fn error_responses_carry_the_trace_headers() {
+    init_propagator();
+    let provider = provider(Sampler::AlwaysOn);
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_trace_id_header(HeaderName::from_static("x-trace-id"));
+    for status in [http::StatusCode::NOT_FOUND, http::StatusCode::SERVICE_UNAVAILABLE] {
+        let service = tower_layer::Layer::layer(
+            &layer,
+            service_fn(move |_req: Request<()>| async move {
+                Ok::<_, Infallible>(Response::builder().status(status).body(()).unwrap())
+            }),
+        );
+        let res = futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+        assert_eq!(res.status(), status);
+        let traceparent = res.headers()["traceparent"].to_str().unwrap();
+        let trace_id = res.headers()["x-trace-id"].to_str().unwrap();
+        assert_eq!(traceparent.split('-').nth(1), Some(trace_id));
+    }
+}
+
+#[cfg(feature = "grpc")]
+#[test]
+fn grpc_web_calls_record_the_rpc_attributes() {
+    use opentelemetry_semantic_conventions::trace::{RPC_METHOD, RPC_SERVICE, RPC_SYSTEM};
+
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_grpc(true);
+    let req = Request::builder()
+        .method(Method::POST)
+        .uri("/helloworld.Greeter/SayHello")
+        .header("content-type", "application/grpc-web-text+proto")
+        .body(())
+        .unwrap();
+    call(&layer, req);
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &RPC_SYSTEM), Some(Value::from("grpc")));
+    assert_eq!(attribute(&span, &RPC_SERVICE), Some(Value::from("helloworld.Greeter")));
+    assert_eq!(attribute(&span, &RPC_METHOD), Some(Value::from("SayHello")));
+}
+
+#[test]
+fn attribute_extractors_run_in_order() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_attribute_extractor(|parts| vec![KeyValue::new("tenant", parts.uri.path().to_string())])
+        .with_attribute_extractor(|_| vec![KeyValue::new("flag", true), KeyValue::new("tenant", "override")])
+        .with_custom_attribute_prefix("app.");
+    call(&layer, Request::builder().uri("/acme").body(()).unwrap());
+    let span = recorder.single();
+    let custom: Vec<_> = span
+        .attributes
+        .iter()
+        .filter(|(key, _)| key.as_str().starts_with("app."))
+        .map(|(key, value)| (key.as_str().to_string(), value.clone()))
+        .collect();
+    assert_eq!(attribute(&span, &Key::new("app.flag")), Some(Value::Bool(true)));
+    assert_eq!(attribute(&span, &Key::new("app.tenant")), Some(Value::from("override")));
+    assert_eq!(custom.len(), 2);
+}
+
+#[test]
+fn custom_attributes_are_prefixed() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_custom_attribute_prefix("app.")
+        .with_span_builder_hook(|_parts, builder| {
+            builder.attributes.get_or_insert_with(Vec::new).push(KeyValue::new("tenant", "acme"));
+        })
+        .with_async_attributes(|_parts| async { vec![KeyValue::new("plan", "pro")] });
+    call(&layer, Request::new(()));
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &Key::new("app.tenant")), Some(Value::from("acme")));
+    assert_eq!(attribute(&span, &Key::new("app.plan")), Some(Value::from("pro")));
+    assert_eq!(attribute(&span, &HTTP_METHOD), Some(Value::from("GET")));
+}
+
+#[test]
+fn async_attributes_resolve_before_the_inner_future_is_polled() {
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_async_attributes(|_parts| async {
+        let mut yielded = false;
+        futures_util::future::poll_fn(move |cx| {
+            if std::mem::replace(&mut yielded, true) {
+                return Poll::Ready(());
+            }
+            cx.waker().wake_by_ref();
+            Poll::Pending
+        })
+        .await;
+        vec![KeyValue::new("plan", "pro")]
+    });
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|_req: Request<()>| async {
+            Context::current().span().add_event("handler.started", Vec::new());
+            Ok::<_, Infallible>(Response::new(()))
+        }),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    let span = recorder.single();
+    let events: Vec<_> = span.events.iter().map(|event| event.name.clone()).collect();
+    assert_eq!(events, [ASYNC_ATTRIBUTES_EVENT, "handler.started"]);
+    assert_eq!(attribute(&span, &Key::new("plan")), Some(Value::from("pro")));
+}
+
+/// Records a span event from `call` itself rather than from the future it returns.
+#[derive(Clone)]
+struct RecordsOnCall;
+
+impl tower_service::Service<Request<()>> for RecordsOnCall {
+    type Error = Infallible;
+    type Future = futures_util::future::Ready<Result<Response<()>, Infallible>>;
+    type Response = Response<()>;
+
+    fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Infallible>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, _req: Request<()>) -> Self::Future {
+        Context::current().span().add_event("handler.called", Vec::new());
+        futures_util::future::ready(Ok(Response::new(())))
+    }
+}
+
+#[test]
+fn async_attributes_resolve_before_the_inner_service_is_called() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_async_attributes(|_parts| async { vec![KeyValue::new("plan", "pro")] });
+    let service = tower_layer::Layer::layer(&layer, RecordsOnCall);
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    let span = recorder.single();
+    let events: Vec<_> = span.events.iter().map(|event| event.name.clone()).collect();
+    assert_eq!(events, [ASYNC_ATTRIBUTES_EVENT, "handler.called"]);
+}
+
+#[test]
+fn spans_cover_the_inner_future_and_end_when_it_is_dropped() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let slow = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|_req: Request<()>| async {
+            std::thread::sleep(std::time::Duration::from_millis(50));
+            Ok::<_, Infallible>(Response::new(()))
+        }),
+    );
+    futures_executor::block_on(slow.oneshot(Request::new(()))).unwrap();
+    let span = recorder.single();
+    let elapsed = span.end_time.duration_since(span.start_time).unwrap();
+    assert!(elapsed >= std::time::Duration::from_millis(50), "{:?}", elapsed);
+
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let mut pending = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|_req: Request<()>| futures_util::future::pending::<Result<Response<()>, Infallible>>()),
+    );
+    drop(tower_service::Service::call(&mut pending, Request::new(())));
+    assert_eq!(recorder.spans().len(), 1);
+}
+
+#[test]
+fn chunked_responses_are_recorded_for_http1_only() {
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_chunked_response(true);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|req: Request<()>| async move {
+            let mut res = Response::new(());
+            if let Some(length) = req.headers().get("x-length") {
+                res.headers_mut().insert(header::CONTENT_LENGTH, length.clone());
+            }
+            if let Some(coding) = req.headers().get("x-coding") {
+                res.headers_mut().insert(header::TRANSFER_ENCODING, coding.clone());
+            }
+            if let Some(status) = req.headers().get("x-status") {
+                *res.status_mut() = http::StatusCode::from_bytes(status.as_bytes()).unwrap();
+            }
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    let requests = [
+        Request::builder().header("x-coding", "gzip, chunked"),
+        Request::builder().header("x-length", "0"),
+        Request::builder(),
+        Request::builder().version(Version::HTTP_2),
+        Request::builder().header("x-status", "204"),
+        Request::builder().header("x-status", "304"),
+        Request::builder().header("x-status", "103"),
+        Request::builder().method(Method::HEAD),
+    ];
+    for req in requests {
+        futures_executor::block_on(service.clone().oneshot(req.body(()).unwrap())).unwrap();
+    }
+    let spans = recorder.spans();
+    assert_eq!(attribute(&spans[0], &HTTP_RESPONSE_CHUNKED), Some(Value::Bool(true)));
+    assert_eq!(attribute(&spans[1], &HTTP_RESPONSE_CHUNKED), Some(Value::Bool(false)));
+    assert_eq!(attribute(&spans[2], &HTTP_RESPONSE_CHUNKED), Some(Value::Bool(true)));
+    assert_eq!(attribute(&spans[3], &HTTP_RESPONSE_CHUNKED), None);
+    for span in &spans[4..] {
+        assert_eq!(attribute(span, &HTTP_RESPONSE_CHUNKED), Some(Value::Bool(false)));
+    }
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn layers_are_built_from_deserialized_config() {
+    let config: config::Config = serde_json::from_str(
+        r#"{"excluded_paths": ["/healthz"], "user_agent": "classified", "minimal_attributes_for": ["HEAD"]}"#,
+    )
+    .unwrap();
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::from_config(config).with_tracer_provider(&provider);
+    call(&layer, Request::builder().uri("/healthz").body(()).unwrap());
+    call(&layer, Request::builder().header("user-agent", "curl/8.0").body(()).unwrap());
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &HTTP_USER_AGENT), None);
+    assert!(attribute(&span, &HTTP_USER_AGENT_CLASS).is_some());
+
+    let invalid = serde_json::from_str::<config::Config>(r#"{"request_id_header": "not a header"}"#);
+    assert!(invalid.unwrap_err().to_string().contains("expected a header name"));
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn trusted_hops_in_config_leave_forwarded_headers_untrusted() {
+    let config: config::Config =
+        serde_json::from_str(r#"{"trusted_forwarded_headers": false, "trusted_hops": 1}"#).unwrap();
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::from_config(config).with_tracer_provider(&provider);
+    call(&layer, Request::builder().header("x-forwarded-for", "198.51.100.9").body(()).unwrap());
+    assert_eq!(attribute(&recorder.single(), &HTTP_CLIENT_IP), None);
+}
+
+#[test]
+fn status_class_is_recorded_when_enabled() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_status_class(true);
+    call(&layer, Request::new(()));
+    assert_eq!(attribute(&recorder.single(), &HTTP_STATUS_CLASS), Some(Value::from("2xx")));
+}
+
+#[test]
+fn route_matched_reflects_the_route_extensions() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_route_matched(true);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|req: Request<()>| async move {
+            let mut res = Response::new(());
+            if req.uri().path() == "/users/42" {
+                res.extensions_mut().insert(MatchedRoute::new("/users/:id"));
+            }
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    for uri in ["/users/42", "/unknown"] {
+        let req = Request::builder().uri(uri).body(()).unwrap();
+        futures_executor::block_on(service.clone().oneshot(req)).unwrap();
+    }
+    let spans = recorder.spans();
+    assert_eq!(attribute(&spans[0], &HTTP_ROUTE_MATCHED), Some(Value::Bool(true)));
+    assert_eq!(attribute(&spans[1], &HTTP_ROUTE_MATCHED), Some(Value::Bool(false)));
+}
+
+#[test]
+fn tail_sampling_decides_on_the_final_status() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_tail_sampling(|status, _| match status {
+            Some(status) => status.is_server_error(),
+            None => true,
+        });
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|req: Request<()>| async move {
+            let mut res = Response::new(());
+            if req.uri().path() == "/fail" {
+                *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+            }
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    let mut traceparents = Vec::new();
+    for uri in ["/ok", "/fail"] {
+        let req = Request::builder().uri(uri).header("user-agent", "curl/8.0").body(()).unwrap();
+        let res = futures_executor::block_on(service.clone().oneshot(req)).unwrap();
+        traceparents.push(res.headers()["traceparent"].to_str().unwrap().to_string());
+    }
+    let span = recorder.single();
+    assert_eq!(span.name, "/fail");
+    assert_eq!(attribute(&span, &HTTP_STATUS_CODE), Some(Value::I64(500)));
+    assert_eq!(attribute(&span, &HTTP_USER_AGENT), Some(Value::from("curl/8.0")));
+    assert!(traceparents[1].contains(&span.span_context.span_id().to_string()));
+
+    let mut pending = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|_req: Request<()>| futures_util::future::pending::<Result<Response<()>, Infallible>>()),
+    );
+    drop(tower_service::Service::call(&mut pending, Request::builder().uri("/cancelled").body(()).unwrap()));
+    let names: Vec<_> = recorder.spans().into_iter().map(|span| span.name).collect();
+    assert_eq!(names, ["/fail", "/cancelled"]);
+}
+
+#[test]
+fn tail_sampling_sees_the_status_when_its_key_is_remapped() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_attribute_keys([("http.status_code", "http.response.status_code")])
+        .with_tail_sampling(|status, _| status.is_some_and(|status| status.is_server_error()));
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|req: Request<()>| async move {
+            let mut res = Response::new(());
+            if req.uri().path() == "/fail" {
+                *res.status_mut() = http::StatusCode::BAD_GATEWAY;
+            }
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    for uri in ["/ok", "/fail"] {
+        let req = Request::builder().uri(uri).body(()).unwrap();
+        futures_executor::block_on(service.clone().oneshot(req)).unwrap();
+    }
+    let span = recorder.single();
+    assert_eq!(span.name, "/fail");
+    assert_eq!(attribute(&span, &Key::new("http.response.status_code")), Some(Value::I64(502)));
+}
+
+#[test]
+fn min_export_duration_applies_before_the_tail_sampler() {
+    let (provider, recorder) = recording_provider();
+    let consulted = Arc::new(Mutex::new(Vec::new()));
+    let seen = consulted.clone();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_min_export_duration(Duration::MAX)
+        .with_tail_sampling(move |status, _| {
+            seen.lock().unwrap().push(status);
+            true
+        });
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|req: Request<()>| async move {
+            let mut res = Response::new(());
+            if req.uri().path() == "/fail" {
+                *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+            }
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    for uri in ["/fast", "/fail"] {
+        futures_executor::block_on(service.clone().oneshot(Request::builder().uri(uri).body(()).unwrap())).unwrap();
+    }
+    let names: Vec<_> = recorder.spans().into_iter().map(|span| span.name).collect();
+    assert_eq!(names, ["/fail"]);
+    assert_eq!(*consulted.lock().unwrap(), [Some(http::StatusCode::INTERNAL_SERVER_ERROR)]);
+}
+
+#[test]
+fn child_spans_are_exported_when_the_buffered_server_span_is_dropped() {
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let tracer = provider.tracer("handler");
+    let layer = Layer::new().with_tracer_provider(&provider).with_tail_sampling(|_, _| false);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(move |_req: Request<()>| {
+            tracer.start_with_context("child", &Context::current()).end();
+            async { Ok::<_, Infallible>(Response::new(())) }
+        }),
+    );
+    let res = futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    let child = recorder.single();
+    assert_eq!(child.name, "child");
+    let traceparent = res.headers()["traceparent"].to_str().unwrap();
+    assert_eq!(traceparent.split('-').nth(2), Some(child.parent_span_id.to_string().as_str()));
+}
+
+#[test]
+fn min_export_duration_drops_fast_successful_spans() {
+    let (provider, recorder) = recording_provider();
+    for min in [Duration::ZERO, Duration::MAX] {
+        let layer = Layer::new().with_tracer_provider(&provider).with_min_export_duration(min);
+        let service = tower_layer::Layer::layer(
+            &layer,
+            service_fn(|req: Request<()>| async move {
+                let mut res = Response::new(());
+                if req.uri().path() == "/fail" {
+                    *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+                }
+                Ok::<_, Infallible>(res)
+            }),
+        );
+        for uri in ["/fast", "/fail"] {
+            futures_executor::block_on(service.clone().oneshot(Request::builder().uri(uri).body(()).unwrap())).unwrap();
+        }
+    }
+    let names: Vec<_> = recorder.spans().into_iter().map(|span| span.name).collect();
+    assert_eq!(names, ["/fast", "/fail", "/fail"]);
+}
+
+#[test]
+fn layer_sampler_overrides_the_provider_sampler() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_sampler(Sampler::AlwaysOff);
+    call(&layer, Request::new(()));
+    assert!(recorder.spans().is_empty());
+    let layer = Layer::new().with_tracer_provider(&provider).with_sampler(Sampler::AlwaysOn);
+    call(&layer, Request::new(()));
+    assert_eq!(recorder.spans().len(), 1);
+}
+
+#[test]
+fn layer_sampled_and_buffered_spans_use_the_provider_id_generator() {
+    use opentelemetry::trace::{IdGenerator, SpanId};
+
+    /// Generates the same ids every time.
+    #[derive(Debug)]
+    struct FixedIds;
+
+    impl IdGenerator for FixedIds {
+        fn new_trace_id(&self) -> TraceId {
+            TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap()
+        }
+
+        fn new_span_id(&self) -> SpanId {
+            SpanId::from_hex("00f067aa0ba902b7").unwrap()
+        }
+    }
+
+    /// Hides the SDK tracer, and so its id generator, like the global provider does.
+    struct Opaque(sdktrace::TracerProvider);
+    struct OpaqueTracer(sdktrace::Tracer);
+
+    impl TracerProvider for Opaque {
+        type Tracer = OpaqueTracer;
+
+        fn versioned_tracer(
+            &self,
+            name: impl Into<Cow<'static, str>>,
+            version: Option<&'static str>,
+            schema_url: Option<&'static str>,
+        ) -> Self::Tracer {
+            OpaqueTracer(self.0.versioned_tracer(name, version, schema_url))
+        }
+
+        fn force_flush(&self) -> Vec<TraceResult<()>> {
+            self.0.force_flush()
+        }
+    }
+
+    impl Tracer for OpaqueTracer {
+        type Span = sdktrace::Span;
+
+        fn start_with_context<T>(&self, name: T, parent_cx: &Context) -> Self::Span
+        where
+            T: Into<Cow<'static, str>>,
+        {
+            self.0.start_with_context(name, parent_cx)
+        }
+
+        fn span_builder<T>(&self, name: T) -> SpanBuilder
+        where
+            T: Into<Cow<'static, str>>,
+        {
+            self.0.span_builder(name)
+        }
+
+        fn build_with_context(&self, builder: SpanBuilder, parent_cx: &Context) -> Self::Span {
+            self.0.build_with_context(builder, parent_cx)
+        }
+    }
+
+    let recorder = Recorder::default();
+    let provider = sdktrace::TracerProvider::builder()
+        .with_config(sdktrace::config().with_id_generator(FixedIds))
+        .with_span_processor(recorder.clone())
+        .build();
+    let sampled = Layer::new().with_tracer_provider(&provider).with_sampler(Sampler::AlwaysOn);
+    call(&sampled, Request::new(()));
+    let buffered = Layer::new().with_tracer_provider(&provider).with_tail_sampling(|_, _| true);
+    call(&buffered, Request::new(()));
+    for span in recorder.spans() {
+        assert_eq!(span.span_context.trace_id(), FixedIds.new_trace_id());
+    }
+    assert_eq!(recorder.spans()[1].span_context.span_id(), FixedIds.new_span_id());
+    let opaque = Opaque(provider);
+    call(&Layer::new().with_tracer_provider(&opaque).with_sampler(Sampler::AlwaysOn), Request::new(()));
+    assert_eq!(recorder.spans()[2].span_context.trace_id(), FixedIds.new_trace_id());
+}
+
+#[test]
+fn request_sampler_drops_requests_but_keeps_their_parent_context() {
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_request_sampler(|parts| {
+        if parts.headers.contains_key("x-synthetic") {
+            SamplingDecision::Drop
+        } else {
+            SamplingDecision::RecordAndSample
+        }
+    });
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|_: Request<()>| async {
+            let trace_id = Context::current().span().span_context().trace_id();
+            let mut res = Response::new(());
+            res.headers_mut().insert("x-trace-id", format!("{trace_id:032x}").parse().unwrap());
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    let req = Request::builder()
+        .header("traceparent", "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01")
+        .header("x-synthetic", "1")
+        .body(())
+        .unwrap();
+    let res = futures_executor::block_on(service.clone().oneshot(req)).unwrap();
+    assert_eq!(res.headers()["x-trace-id"], "4bf92f3577b34da6a3ce929d0e0e4736");
+    assert!(recorder.spans().is_empty());
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    assert_eq!(recorder.spans().len(), 1);
+}
+
+#[test]
+fn request_sampler_overrides_the_layer_and_provider_samplers() {
+    let recorder = Recorder::default();
+    let provider = sdktrace::TracerProvider::builder()
+        .with_config(sdktrace::config().with_sampler(Sampler::AlwaysOff))
+        .with_span_processor(recorder.clone())
+        .build();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_sampler(Sampler::AlwaysOff)
+        .with_request_sampler(|parts| {
+            if parts.headers.contains_key("x-record-only") {
+                SamplingDecision::RecordOnly
+            } else {
+                SamplingDecision::RecordAndSample
+            }
+        });
+    call(&layer, Request::new(()));
+    call(&layer, Request::builder().header("x-record-only", "1").body(()).unwrap());
+    let spans = recorder.spans();
+    assert_eq!(spans.len(), 2);
+    assert!(spans[0].span_context.is_sampled());
+    assert!(!spans[1].span_context.is_sampled());
+}
+
+#[test]
+fn error_only_attributes_are_recorded_on_errors_only() {
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let service = tower_layer::Layer::layer(
+        &Layer::new()
+            .with_tracer_provider(&provider)
+            .with_error_only_attributes([HTTP_URL]),
+        service_fn(|req: Request<()>| async move {
+            let mut res = Response::new(());
+            if req.uri().path() == "/fail" {
+                *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+            }
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    for path in ["/ok", "/fail"] {
+        let req = Request::builder().uri(path).body(()).unwrap();
+        futures_executor::block_on(service.clone().oneshot(req)).unwrap();
+    }
+    let spans = recorder.spans();
+    assert_eq!(attribute(&spans[0], &HTTP_URL), None);
+    assert!(attribute(&spans[0], &HTTP_METHOD).is_some());
+    assert_eq!(attribute(&spans[1], &HTTP_URL), Some(Value::from("/fail")));
+}
+
+#[test]
+fn classifiers_decide_which_statuses_are_failures() {
+    let status_code = |layer: Layer, status: u16| {
+        let (provider, recorder) = recording_provider();
+        let service = tower_layer::Layer::layer(
+            &layer.with_tracer_provider(&provider),
+            service_fn(move |_: Request<()>| async move {
+                let mut res = Response::new(());
+                *res.status_mut() = http::StatusCode::from_u16(status).unwrap();
+                Ok::<_, Infallible>(res)
+            }),
+        );
+        futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+        recorder.single().status_code
+    };
+    assert_eq!(status_code(Layer::new(), 404), StatusCode::Unset);
+    assert_eq!(status_code(Layer::new(), 503), StatusCode::Error);
+    let client_errors = || Layer::new().with_classifier(StatusInRangeAsFailures::new(400..=499));
+    assert_eq!(status_code(client_errors(), 404), StatusCode::Error);
+    assert_eq!(status_code(client_errors(), 503), StatusCode::Unset);
+}
+
+#[test]
+fn successful_responses_get_an_ok_status_when_enabled() {
+    assert_eq!(span_for(|layer| layer, Request::new(())).status_code, StatusCode::Unset);
+    assert_eq!(span_for(|layer| layer.with_ok_status(true), Request::new(())).status_code, StatusCode::Ok);
+    let (provider, recorder) = recording_provider();
+    let service = tower_layer::Layer::layer(
+        &Layer::new().with_tracer_provider(&provider).with_ok_status(true),
+        service_fn(|_req: Request<()>| async {
+            let mut res = Response::new(());
+            *res.status_mut() = http::StatusCode::BAD_GATEWAY;
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    assert_eq!(recorder.single().status_code, StatusCode::Error);
+}
+
+#[test]
+fn span_status_extensions_override_the_classifier() {
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let service = tower_layer::Layer::layer(
+        &Layer::new().with_tracer_provider(&provider),
+        service_fn(|req: Request<()>| async move {
+            let mut res = Response::new(());
+            *res.status_mut() = http::StatusCode::INTERNAL_SERVER_ERROR;
+            if req.uri().path() == "/expected" {
+                res.extensions_mut().insert(SpanStatus::Ok);
+            } else {
+                res.extensions_mut().insert(SpanStatus::Error("quota exceeded".into()));
+            }
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    for path in ["/expected", "/quota"] {
+        let req = Request::builder().uri(path).body(()).unwrap();
+        futures_executor::block_on(service.clone().oneshot(req)).unwrap();
+    }
+    let spans = recorder.spans();
+    assert_eq!(spans[0].status_code, StatusCode::Ok);
+    assert_eq!(spans[1].status_code, StatusCode::Error);
+    assert_eq!(spans[1].status_message, "quota exceeded");
+}
+
+#[test]
+fn response_failure_marks_successful_responses_as_errors() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|_: Request<()>| async {
+            let mut res = Response::new(());
+            res.extensions_mut().insert(ResponseFailure("query failed".into()));
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &HTTP_STATUS_CODE), Some(Value::I64(200)));
+    assert_eq!(span.status_code, StatusCode::Error);
+    assert_eq!(span.status_message, "query failed");
+}
+
+#[test]
+fn response_errors_are_recorded_on_successful_responses() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_response_error(true);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|_: Request<()>| async {
+            let mut res = Response::new(());
+            res.extensions_mut().insert(ResponseError::new("validation", "field `id` is missing").with_count(3));
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    let span = recorder.single();
+    assert_eq!(span.status_code, StatusCode::Error);
+    assert_eq!(span.status_message, "field `id` is missing");
+    assert_eq!(attribute(&span, &ERROR_TYPE), Some(Value::from("validation")));
+    assert_eq!(attribute(&span, &ERROR_COUNT), Some(Value::I64(3)));
+    let event = span.events.iter().find(|event| event.name == "exception").unwrap();
+    assert!(event.attributes.contains(&EXCEPTION_MESSAGE.string("field `id` is missing")));
+}
+
+#[test]
+fn response_errors_are_skipped_when_the_span_status_is_not_an_error() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_response_error(true)
+        .with_attribute_keys([("error.type", "app.error_kind")]);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|req: Request<()>| async move {
+            let mut res = Response::new(());
+            res.extensions_mut().insert(ResponseError::new("validation", "field `id` is missing"));
+            match req.uri().path() {
+                "/ok" => res.extensions_mut().insert(SpanStatus::Ok),
+                "/unset" => res.extensions_mut().insert(SpanStatus::Unset),
+                _ => None,
+            };
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    for path in ["/ok", "/unset", "/error"] {
+        let req = Request::builder().uri(path).body(()).unwrap();
+        futures_executor::block_on(service.clone().oneshot(req)).unwrap();
+    }
+    let spans = recorder.spans();
+    for span in &spans[..2] {
+        assert_ne!(span.status_code, StatusCode::Error);
+        assert_eq!(attribute(span, &Key::new("app.error_kind")), None);
+        assert!(span.events.iter().all(|event| event.name != "exception"));
+    }
+    assert_eq!(attribute(&spans[2], &ERROR_TYPE), None);
+    assert_eq!(attribute(&spans[2], &Key::new("app.error_kind")), Some(Value::from("validation")));
+}
+
+#[test]
+fn nested_routes_are_joined_to_their_prefix() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|_: Request<()>| async {
+            let mut res = Response::new(());
+            res.extensions_mut().insert(RoutePrefix::new("/admin/").nest("/v1"));
+            res.extensions_mut().insert(MatchedRoute::new("/users/:id"));
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    let res = futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    let route = Some(MatchedRoute::new("/admin/v1/users/:id"));
+    assert_eq!(MatchedRoute::from_extensions(res.extensions()), route);
+    assert_eq!(attribute(&recorder.single(), &HTTP_ROUTE), Some(Value::from("/admin/v1/users/:id")));
+    let mut extensions = http::Extensions::new();
+    extensions.insert(RoutePrefix::new("/admin"));
+    extensions.insert(MatchedRoute::new("/"));
+    assert_eq!(MatchedRoute::from_extensions(&extensions), Some(MatchedRoute::new("/admin")));
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn route_templates_rather_than_paths_are_the_route_dimension_of_metrics() {
+    let mut measurements = Measurements::lock();
+    let template = [HTTP_ROUTE.string("/orders/:id")];
+    let other = [HTTP_ROUTE.string("_OTHER")];
+    let (templated, unrouted) = (
+        measurements.count("http.server.requests", &template),
+        measurements.count("http.server.requests", &other),
+    );
+    let provider = provider(Sampler::AlwaysOn);
+    let layer = Layer::new().with_tracer_provider(&provider).with_metrics(true);
+    let mut req = Request::builder().uri("/orders/17").body(()).unwrap();
+    req.extensions_mut().insert(MatchedRoute::new("/orders/:id"));
+    call(&layer, req);
+    call(&layer, Request::builder().uri("/orders/18").body(()).unwrap());
+    assert_eq!(measurements.count("http.server.requests", &template), templated + 1);
+    assert_eq!(measurements.count("http.server.requests", &other), unrouted + 1);
+    for path in ["/orders/17", "/orders/18"] {
+        assert_eq!(measurements.count("http.server.requests", &[HTTP_ROUTE.string(path)]), 0);
+    }
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn nested_routes_are_the_route_dimension_of_metrics() {
+    let mut measurements = Measurements::lock();
+    let route = [HTTP_ROUTE.string("/nested/v1/users/:id")];
+    let requests = measurements.count("http.server.requests", &route);
+    let provider = provider(Sampler::AlwaysOn);
+    let service = tower_layer::Layer::layer(
+        &Layer::new().with_tracer_provider(&provider).with_metrics(true),
+        service_fn(|_: Request<()>| async {
+            let mut res = Response::new(());
+            res.extensions_mut().insert(RoutePrefix::new("/nested").nest("/v1"));
+            res.extensions_mut().insert(MatchedRoute::new("/users/:id"));
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    assert_eq!(measurements.count("http.server.requests", &route), requests + 1);
+    assert_eq!(measurements.count("http.server.requests", &[HTTP_ROUTE.string("/users/:id")]), 0);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn dropped_requests_are_measured_as_cancelled() {
+    let mut measurements = Measurements::lock();
+    let cancelled = [HTTP_ROUTE.string("/slow"), ERROR_TYPE.string("cancelled")];
+    let (requests, durations) = (
+        measurements.count("http.server.requests", &cancelled),
+        measurements.count("http.server.duration", &cancelled),
+    );
+    let provider = provider(Sampler::AlwaysOn);
+    let mut service = tower_layer::Layer::layer(
+        &Layer::new().with_tracer_provider(&provider).with_metrics(true),
+        service_fn(|_: Request<()>| futures_util::future::pending::<Result<Response<()>, Infallible>>()),
+    );
+    let mut req = Request::new(());
+    req.extensions_mut().insert(MatchedRoute::new("/slow"));
+    drop(tower_service::Service::call(&mut service, req));
+    assert_eq!(measurements.count("http.server.requests", &cancelled), requests + 1);
+    assert_eq!(measurements.count("http.server.duration", &cancelled), durations + 1);
+
+    let mut req = Request::new(());
+    req.extensions_mut().insert(MatchedRoute::new("/slow"));
+    call(&Layer::new().with_tracer_provider(&provider).with_metrics(true), req);
+    assert_eq!(measurements.count("http.server.requests", &cancelled), requests + 1);
+}
+
+#[test]
+fn route_overrides_apply_to_matching_routes() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_route_override(
+        "/v1/users",
+        RouteOverride::new()
+            .with_attribute(KeyValue::new("deprecated", true))
+            .with_status(StatusCode::Error, "deprecated endpoint"),
+    );
+    for route in ["/v1/users", "/v2/users"] {
+        let mut req = Request::new(());
+        req.extensions_mut().insert(MatchedRoute::new(route));
+        call(&layer, req);
+    }
+    let spans = recorder.spans();
+    assert_eq!(attribute(&spans[0], &Key::new("deprecated")), Some(Value::Bool(true)));
+    assert_eq!(spans[0].status_code, StatusCode::Error);
+    assert_eq!(spans[0].status_message, "deprecated endpoint");
+    assert_eq!(attribute(&spans[1], &Key::new("deprecated")), None);
+    assert_eq!(spans[1].status_code, StatusCode::Unset);
+}
+
+#[test]
+fn request_and_response_observers_see_unsampled_requests() {
+    let observed = Arc::new(Mutex::new(Vec::new()));
+    let (on_request, on_response) = (observed.clone(), observed.clone());
+    let layer = Layer::new()
+        .with_tracer_provider(&provider(Sampler::AlwaysOff))
+        .with_on_request(move |parts| on_request.lock().unwrap().push(parts.uri.to_string()))
+        .with_on_response(move |parts, _| {
+            on_response.lock().unwrap().push(parts.map(|parts| parts.status.to_string()).unwrap_or_default());
+        });
+    call(&layer, Request::builder().uri("/users").body(()).unwrap());
+    assert_eq!(*observed.lock().unwrap(), ["/users", "200 OK"]);
+}
+
+#[test]
+fn inner_service_type_or_label_is_recorded() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_inner_service_type(true);
+    call(&layer, Request::new(()));
+    call(&layer.clone().with_inner_service_label("users"), Request::new(()));
+    let spans = recorder.spans();
+    let type_name = attribute(&spans[0], &TOWER_SERVICE).unwrap().as_str().into_owned();
+    assert!(type_name.contains("ServiceFn"), "{}", type_name);
+    assert_eq!(attribute(&spans[1], &TOWER_SERVICE), Some(Value::from("users")));
+}
+
+#[test]
+fn ready_wait_covers_pending_polls() {
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let mut service = tower_layer::Layer::layer(
+        &Layer::new().with_tracer_provider(&provider).with_ready_wait(true),
+        NotReadyOnce(true),
+    );
+    let waker = futures_util::task::noop_waker();
+    let mut cx = std::task::Context::from_waker(&waker);
+    assert!(tower_service::Service::poll_ready(&mut service, &mut cx).is_pending());
+    std::thread::sleep(Duration::from_millis(5));
+    assert!(tower_service::Service::poll_ready(&mut service, &mut cx).is_ready());
+    futures_executor::block_on(tower_service::Service::call(&mut service, Request::new(()))).unwrap();
+    futures_executor::block_on(tower_service::Service::call(&mut service, Request::new(()))).unwrap();
+    let spans = recorder.spans();
+    let wait = |span: &SpanData| match attribute(span, &HTTP_SERVER_READY_WAIT_MS) {
+        Some(Value::F64(millis)) => millis,
+        other => panic!("unexpected ready wait {:?}", other),
+    };
+    assert!(wait(&spans[0]) >= 5.0);
+    assert!(wait(&spans[1]).abs() < f64::EPSILON);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn callers_dropping_a_pending_service_are_counted_as_abandoned() {
+    let mut measurements = Measurements::lock();
+    let abandoned = measurements.count("tower_otel.abandoned_requests", &[]);
+    let provider = provider(Sampler::AlwaysOn);
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_metrics(true)
+        .with_abandonment_metric(true);
+    let pending = tower_layer::Layer::layer(&layer, NotReadyOnce(true)).oneshot(Request::new(()));
+    assert!(FutureExt::now_or_never(pending).is_none());
+    let ready = tower_layer::Layer::layer(&layer, NotReadyOnce(false)).oneshot(Request::new(()));
+    futures_executor::block_on(ready).unwrap();
+    assert_eq!(measurements.count("tower_otel.abandoned_requests", &[]), abandoned + 1);
+}
+
+#[test]
+fn queue_depth_is_recorded_when_a_buffer_reports_it() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let mut req = Request::new(());
+    req.extensions_mut().insert(QueueDepth(3));
+    call(&layer, req);
+    call(&layer, Request::new(()));
+    let spans = recorder.spans();
+    assert_eq!(attribute(&spans[0], &HTTP_SERVER_QUEUE_DEPTH), Some(Value::I64(3)));
+    assert_eq!(attribute(&spans[1], &HTTP_SERVER_QUEUE_DEPTH), None);
+}
+
+#[test]
+fn consumer_spans_carry_messaging_and_http_attributes() {
+    let span = span_for(
+        |layer| layer.with_span_kind(SpanKind::Consumer).with_messaging("kafka", "orders"),
+        Request::builder().method(Method::POST).body(()).unwrap(),
+    );
+    assert_eq!(span.span_kind, SpanKind::Consumer);
+    assert_eq!(attribute(&span, &MESSAGING_SYSTEM), Some(Value::from("kafka")));
+    assert_eq!(attribute(&span, &MESSAGING_DESTINATION), Some(Value::from("orders")));
+    assert_eq!(attribute(&span, &HTTP_METHOD), Some(Value::from("POST")));
+    assert_eq!(span_for(|layer| layer, Request::new(())).span_kind, SpanKind::Server);
+}
+
+#[test]
+fn custom_request_attributes_replace_the_built_in_ones() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_messaging("kafka", "orders")
+        .with_request_attributes(|parts| vec![KeyValue::new("app.method", parts.method.to_string())]);
+    call(&layer, Request::builder().method(Method::POST).body(()).unwrap());
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &Key::new("app.method")), Some(Value::from("POST")));
+    assert_eq!(attribute(&span, &HTTP_METHOD), None);
+    assert_eq!(attribute(&span, &MESSAGING_SYSTEM), None);
+    assert_eq!(attribute(&span, &HTTP_STATUS_CODE), Some(Value::I64(200)));
+}
+
+#[test]
+fn clock_skew_attributes_record_both_clocks() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_clock_skew_attributes(true);
+    call(&layer, Request::new(()));
+    let span = recorder.single();
+    let Some(Value::I64(start)) = attribute(&span, &TIMING_WALL_START) else {
+        panic!("missing wall-clock start");
+    };
+    let Some(Value::I64(end)) = attribute(&span, &TIMING_WALL_END) else {
+        panic!("missing wall-clock end");
+    };
+    assert!(start <= end);
+    assert!(matches!(attribute(&span, &TIMING_MONOTONIC_DURATION_MS), Some(Value::F64(ms)) if ms >= 0.0));
+}
+
+#[test]
+fn detached_calls_still_run_the_future_in_the_context() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_context_attachment(false);
+    let seen = Arc::new(Mutex::new(Vec::new()));
+    let record = seen.clone();
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(move |_req: Request<()>| {
+            let record = record.clone();
+            record.lock().unwrap().push(Context::current().has_active_span());
+            async move {
+                record.lock().unwrap().push(Context::current().has_active_span());
+                Ok::<_, Infallible>(Response::new(()))
+            }
+        }),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    assert_eq!(*seen.lock().unwrap(), vec![false, true]);
+    recorder.single();
+}
+
+#[test]
+fn standard_methods_are_canonicalized_case_insensitively() {
+    let method = |name: &str| http_method_str(&Method::from_bytes(name.as_bytes()).unwrap());
+    assert_eq!(method("GET"), "GET");
+    assert_eq!(method("get"), "GET");
+    assert_eq!(method("Patch"), "PATCH");
+    assert_eq!(method("PURGE"), OTHER_METHOD);
+    assert_eq!(method("purge"), OTHER_METHOD);
+
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider);
+    call(&layer, Request::builder().method("get").body(()).unwrap());
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &HTTP_METHOD), Some(Value::from("GET")));
+    assert_eq!(attribute(&span, &HTTP_REQUEST_METHOD_ORIGINAL), Some(Value::from("get")));
+}
+
+#[test]
+fn detected_attributes_are_recorded_on_every_span() {
+    struct Region;
+
+    impl ResourceDetector for Region {
+        fn detect(&self, _timeout: Duration) -> Resource {
+            Resource::new(vec![KeyValue::new("cloud.region", "eu-west-1")])
+        }
+    }
+
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_detectors(Duration::from_secs(1), vec![Box::new(Region)]);
+    call(&layer, Request::new(()));
+    call(&layer, Request::new(()));
+    for span in recorder.spans() {
+        assert_eq!(attribute(&span, &Key::new("cloud.region")), Some(Value::from("eu-west-1")));
+    }
+}
+
+#[cfg(feature = "detectors")]
+#[test]
+fn container_ids_are_parsed_from_cgroups() {
+    let id = "a".repeat(64);
+    let cgroup_v2 = format!("0::/system.slice/docker-{id}.scope\n");
+    let cgroup_v1 = format!("12:cpu,cpuacct:/kubepods/burstable/pod1/{id}\n1:name=systemd:/\n");
+    assert_eq!(detectors::container_id(&cgroup_v2), Some(id.clone()));
+    assert_eq!(detectors::container_id(&cgroup_v1), Some(id));
+    assert_eq!(detectors::container_id("0::/user.slice/session-2.scope\n"), None);
+}
+
+#[test]
+fn invalid_injected_values_follow_the_policy() {
+    let mut headers = http::HeaderMap::new();
+    let mut carrier = HeaderCarrier::new(&mut headers);
+    carrier.set("x-dropped", "caf\u{e9}".to_string());
+    let mut carrier = HeaderCarrier::new(&mut headers).with_policy(InvalidValuePolicy::PercentEncode);
+    carrier.set("x-percent", "caf\u{e9} 100%\n".to_string());
+    let mut carrier = HeaderCarrier::new(&mut headers).with_policy(InvalidValuePolicy::Base64);
+    carrier.set("x-base64", "caf\u{e9}".to_string());
+    carrier.set("x-valid", "plain".to_string());
+    assert!(headers.get("x-dropped").is_none());
+    assert_eq!(headers["x-percent"], "caf%C3%A9 100%25%0A");
+    assert_eq!(headers["x-base64"], "Y2Fmw6k=");
+    assert_eq!(headers["x-valid"], "plain");
+}
+
+#[test]
+fn percent_encoded_values_decode_to_the_original() {
+    let decode = |encoded: &str| {
+        let mut bytes = Vec::new();
+        let mut rest = encoded.as_bytes();
+        while let Some((&byte, tail)) = rest.split_first() {
+            if byte == b'%' {
+                let hex = std::str::from_utf8(&tail[..2]).unwrap();
+                bytes.push(u8::from_str_radix(hex, 16).unwrap());
+                rest = &tail[2..];
+            } else {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+        String::from_utf8(bytes).unwrap()
+    };
+    for value in ["plain", "100%", "100%25", "caf\u{e9} 100%\n", "%41"] {
+        let mut headers = http::HeaderMap::new();
+        let mut carrier = HeaderCarrier::new(&mut headers).with_policy(InvalidValuePolicy::PercentEncode);
+        carrier.set("x-percent", value.to_string());
+        assert_eq!(decode(headers["x-percent"].to_str().unwrap()), value);
+    }
+}
+
+#[test]
+fn url_recording_strips_the_query() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let request = || {
+        Request::builder()
+            .uri("/users?token=secret")
+            .header("host", "example.com:8080")
+            .header("x-forwarded-proto", "https")
+            .body(())
+            .unwrap()
+    };
+    let no_query = layer.clone().with_url_recording(UrlRecording::NoQuery);
+    call(&no_query, request());
+    call(&no_query.with_trusted_forwarded_headers(true), request());
+    call(&layer.with_url_recording(UrlRecording::PathOnly), request());
+    let urls: Vec<_> = recorder.spans().iter().map(|span| attribute(span, &HTTP_URL)).collect();
+    assert_eq!(
+        urls,
+        [
+            Some(Value::from("/users")),
+            Some(Value::from("https://example.com:8080/users")),
+            Some(Value::from("/users")),
+        ]
+    );
+}
+
+#[test]
+fn host_authority_combines_the_host_and_port() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_host_authority(true);
+    call(&layer, Request::builder().uri("https://example.com/").body(()).unwrap());
+    call(&layer, Request::builder().header("host", "internal:8080").body(()).unwrap());
+    call(&layer, Request::new(()));
+    let spans = recorder.spans();
+    assert_eq!(attribute(&spans[0], &HTTP_HOST), Some(Value::from("example.com:443")));
+    assert_eq!(attribute(&spans[1], &HTTP_HOST), Some(Value::from("internal:8080")));
+    assert_eq!(attribute(&spans[2], &HTTP_HOST), None);
+}
+
+#[test]
+fn switched_off_layers_pass_requests_through() {
+    let (provider, recorder) = recording_provider();
+    let switch = TracingSwitch::new();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_switch(switch.clone());
+    switch.disable();
+    call(&layer, Request::new(()));
+    assert!(recorder.spans().is_empty());
+    switch.enable();
+    call(&layer, Request::new(()));
+    assert_eq!(recorder.spans().len(), 1);
+}
+
+#[cfg(feature = "response-body")]
+#[test]
+fn allowlisted_trailers_are_recorded_before_the_span_ends() {
+    struct TrailersBody(Option<http::HeaderMap>);
+
+    impl http_body::Body for TrailersBody {
+        type Data = bytes::Bytes;
+        type Error = Infallible;
+
+        fn poll_data(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+            Poll::Ready(None)
+        }
+
+        fn poll_trailers(
+            mut self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+            Poll::Ready(Ok(self.0.take()))
+        }
+    }
+
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_deferred_span_end(true);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|_req: Request<()>| async {
+            let mut trailers = http::HeaderMap::new();
+            trailers.insert("grpc-status", HeaderValue::from_static("0"));
+            trailers.insert("grpc-message", HeaderValue::from_static("secret"));
+            Ok::<_, Infallible>(Response::new(TrailersBody(Some(trailers))))
+        }),
+    );
+    let service = tower_layer::Layer::layer(
+        &response_body::Layer::new().with_trailers(vec![HeaderName::from_static("grpc-status")]),
+        service,
+    );
+    let mut body = futures_executor::block_on(service.oneshot(Request::new(()))).unwrap().into_body();
+    assert!(recorder.spans().is_empty());
+    futures_executor::block_on(http_body::Body::trailers(&mut body)).unwrap();
+    let span = recorder.single();
+    assert_eq!(
+        attribute(&span, &Key::new("http.response.trailer.grpc_status")),
+        Some(Value::from("0"))
+    );
+    assert_eq!(attribute(&span, &Key::new("http.response.trailer.grpc_message")), None);
+}
+
+#[test]
+fn reloads_apply_to_running_services() {
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let handle = ReloadHandle::new();
+    let mut service = tower_layer::Layer::layer(
+        &Layer::new().with_tracer_provider(&provider).with_reload_handle(handle.clone()),
+        service_fn(|_req: Request<()>| async { Ok::<_, Infallible>(Response::new(())) }),
+    );
+    let healthz = || Request::builder().uri("/healthz").body(()).unwrap();
+    futures_executor::block_on(tower_service::Service::call(&mut service, healthz())).unwrap();
+    handle.reload(Layer::new().with_tracer_provider(&provider).with_excluded_paths(["/healthz"]));
+    futures_executor::block_on(tower_service::Service::call(&mut service, healthz())).unwrap();
+    assert_eq!(recorder.spans().len(), 1);
+}
+
+#[test]
+fn requests_beyond_the_active_span_limit_are_not_traced() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_deferred_span_end(true)
+        .with_max_active_spans(1);
+    let open = call(&layer, Request::new(()));
+    let passed_through = call(&layer, Request::new(()));
+    assert!(passed_through.headers().get("traceparent").is_none());
+    drop(passed_through);
+    drop(open);
+    assert_eq!(recorder.spans().len(), 1);
+    drop(call(&layer, Request::new(())));
+    assert_eq!(recorder.spans().len(), 2);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn requests_beyond_the_active_span_limit_are_counted() {
+    let mut measurements = Measurements::lock();
+    let limited = measurements.count("tower_otel.span_limited_requests", &[]);
+    let (provider, _recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_metrics(true)
+        .with_deferred_span_end(true)
+        .with_max_active_spans(1);
+    let open = call(&layer, Request::new(()));
+    drop(call(&layer, Request::new(())));
+    drop(open);
+    drop(call(&layer, Request::new(())));
+    assert_eq!(measurements.count("tower_otel.span_limited_requests", &[]), limited + 1);
+}
+
+#[cfg(feature = "serde")]
+#[test]
+fn max_active_spans_in_config_enables_the_limit() {
+    let config: config::Config = serde_json::from_str(r#"{"max_active_spans": 1, "deferred_span_end": true}"#).unwrap();
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::from_config(config).with_tracer_provider(&provider);
+    let open = call(&layer, Request::new(()));
+    drop(call(&layer, Request::new(())));
+    drop(open);
+    assert_eq!(recorder.spans().len(), 1);
+}
+
+#[test]
+fn request_context_extension_parents_spawned_work() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_request_context(true);
+    let spawned = Arc::new(Mutex::new(None));
+    let seen = spawned.clone();
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(move |req: Request<()>| {
+            let cx = req.extensions().get::<RequestContext>().cloned().unwrap();
+            let seen = seen.clone();
+            std::thread::spawn(move || {
+                let _guard = cx.attach();
+                *seen.lock().unwrap() = Some(Context::current().span().span_context().span_id());
+            })
+            .join()
+            .unwrap();
+            async { Ok::<_, Infallible>(Response::new(())) }
+        }),
+    );
+    futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    let span = recorder.single();
+    assert_eq!(*spawned.lock().unwrap(), Some(span.span_context.span_id()));
+}
+
+#[test]
+fn trace_continuation_chooses_parent_link_or_neither() {
+    let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    let remote_trace = TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap();
+    let (provider, recorder) = recording_provider();
+    for continuation in [
+        TraceContinuation::Child,
+        TraceContinuation::NewRootLinked,
+        TraceContinuation::AlwaysNewRoot,
+    ] {
+        let layer = Layer::new()
+            .with_tracer_provider(&provider)
+            .with_trace_continuation(continuation);
+        call(&layer, Request::builder().header("traceparent", traceparent).body(()).unwrap());
+    }
+    let spans = recorder.spans();
+    assert_eq!(spans[0].span_context.trace_id(), remote_trace);
+    assert!(spans[0].links.is_empty());
+    assert_ne!(spans[1].span_context.trace_id(), remote_trace);
+    assert_eq!(spans[1].parent_span_id, opentelemetry::trace::SpanId::INVALID);
+    let linked: Vec<_> = spans[1].links.iter().map(|link| link.span_context().trace_id()).collect();
+    assert_eq!(linked, vec![remote_trace]);
+    assert_ne!(spans[2].span_context.trace_id(), remote_trace);
+    assert!(spans[2].links.is_empty());
+}
+
+#[test]
+fn upstream_continuation_is_recorded() {
+    let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_upstream_continuation(true);
+    call(&layer, Request::builder().header("traceparent", traceparent).body(()).unwrap());
+    call(&layer, Request::new(()));
+    let new_root = layer.with_trace_continuation(TraceContinuation::NewRootLinked);
+    call(&new_root, Request::builder().header("traceparent", traceparent).body(()).unwrap());
+    let continued: Vec<_> = recorder
+        .spans()
+        .iter()
+        .map(|span| attribute(span, &TRACE_CONTINUED_FROM_UPSTREAM))
+        .collect();
+    assert_eq!(
+        continued,
+        [Some(Value::Bool(true)), Some(Value::Bool(false)), Some(Value::Bool(false))]
+    );
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn deferred_responses_record_the_ttfb_before_the_duration() {
+    let mut measurements = Measurements::lock();
+    let route = [HTTP_ROUTE.string("/ttfb")];
+    let counts = |measurements: &mut Measurements| {
+        (
+            measurements.count("http.server.ttfb", &route),
+            measurements.count("http.server.duration", &route),
+        )
+    };
+    let before = counts(&mut measurements);
+    let provider = provider(Sampler::AlwaysOn);
+    let service = tower_layer::Layer::layer(
+        &Layer::new()
+            .with_tracer_provider(&provider)
+            .with_metrics(true)
+            .with_deferred_span_end(true),
+        service_fn(|_: Request<()>| async {
+            let mut res = Response::new(());
+            res.extensions_mut().insert(MatchedRoute::new("/ttfb"));
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    let res = futures_executor::block_on(service.oneshot(Request::new(()))).unwrap();
+    assert_eq!(counts(&mut measurements), (before.0 + 1, before.1));
+    drop(res);
+    assert_eq!(counts(&mut measurements), (before.0 + 1, before.1 + 1));
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn request_metrics_are_broken_down_by_protocol_version() {
+    let mut measurements = Measurements::lock();
+    let counts = |measurements: &mut Measurements, flavor: &'static str| {
+        let attributes = [KeyValue::new("network.protocol.version", flavor)];
+        (
+            measurements.count("http.server.requests", &attributes),
+            measurements.count("http.server.duration", &attributes),
+        )
+    };
+    let (http11, http2) = (counts(&mut measurements, "1.1"), counts(&mut measurements, "2.0"));
+    let provider = provider(Sampler::AlwaysOn);
+    let layer = Layer::new().with_tracer_provider(&provider).with_metrics(true);
+    call(&layer, Request::builder().version(Version::HTTP_2).body(()).unwrap());
+    assert_eq!(counts(&mut measurements, "1.1"), http11);
+    assert_eq!(counts(&mut measurements, "2.0"), (http2.0 + 1, http2.1 + 1));
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn error_responses_without_a_handled_marker_are_counted_as_rejections() {
+    let mut measurements = Measurements::lock();
+    let too_many = [HTTP_STATUS_CODE.i64(429)];
+    let rejections = measurements.count("http.server.rejected_requests", &[]);
+    let too_many_rejections = measurements.count("http.server.rejected_requests", &too_many);
+    let provider = provider(Sampler::AlwaysOn);
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_metrics(true)
+        .with_rejection_metric(true);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|req: Request<()>| async move {
+            let mut res = Response::new(());
+            match req.uri().path() {
+                "/rejected" => *res.status_mut() = http::StatusCode::TOO_MANY_REQUESTS,
+                "/handled" => {
+                    *res.status_mut() = http::StatusCode::NOT_FOUND;
+                    res.extensions_mut().insert(Handled);
+                }
+                _ => {}
+            }
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    for path in ["/rejected", "/handled", "/ok"] {
+        let req = Request::builder().uri(path).body(()).unwrap();
+        futures_executor::block_on(service.clone().oneshot(req)).unwrap();
+    }
+    assert_eq!(measurements.count("http.server.rejected_requests", &[]), rejections + 1);
+    assert_eq!(measurements.count("http.server.rejected_requests", &too_many), too_many_rejections + 1);
+}
+
+#[cfg(feature = "metrics")]
+#[test]
+fn original_methods_are_a_metric_dimension_when_enabled() {
+    let mut measurements = Measurements::lock();
+    let purge = [HTTP_METHOD.string("_OTHER"), HTTP_REQUEST_METHOD_ORIGINAL.string("PURGE")];
+    let requests = measurements.count("http.server.requests", &purge);
+    let provider = provider(Sampler::AlwaysOn);
+    let layer = Layer::new().with_tracer_provider(&provider).with_metrics(true);
+    let purge_request = || Request::builder().method("PURGE").body(()).unwrap();
+    call(&layer, purge_request());
+    assert_eq!(measurements.count("http.server.requests", &purge), requests);
+    call(&layer.with_metric_method_original(true), purge_request());
+    assert_eq!(measurements.count("http.server.requests", &purge), requests + 1);
+}
+
+#[cfg(feature = "response-body")]
+#[test]
+fn streamed_response_bytes_are_counted() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_deferred_span_end(true);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|_req: Request<()>| async {
+            Ok::<_, Infallible>(Response::new(http_body::Full::<bytes::Bytes>::from("hello, world")))
+        }),
+    );
+    let service = tower_layer::Layer::layer(&response_body::Layer::new().with_body_size(true), service);
+    let mut body = futures_executor::block_on(service.oneshot(Request::new(()))).unwrap().into_body();
+    while futures_executor::block_on(http_body::Body::data(&mut body)).is_some() {}
+    drop(body);
+    let span = recorder.single();
+    assert_eq!(attribute(&span, &Key::new("http.response.body.size")), Some(Value::I64(12)));
+}
+
+#[cfg(feature = "request-body")]
+#[test]
+fn unread_request_bodies_are_recorded_as_not_consumed() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let service = tower_layer::Layer::layer(
+        &request_body::Layer::new(0).with_body_consumed(true),
+        service_fn(|req: Request<request_body::Body<http_body::Full<bytes::Bytes>>>| async move {
+            let mut body = req.into_body();
+            if http_body::Body::size_hint(&body).exact() == Some(4) {
+                while http_body::Body::data(&mut body).await.is_some() {}
+            }
+            Ok::<_, Infallible>(Response::new(()))
+        }),
+    );
+    let service = tower_layer::Layer::layer(&layer, service);
+    for body in ["read", "unread"] {
+        let req = Request::new(http_body::Full::from(body));
+        futures_executor::block_on(service.clone().oneshot(req)).unwrap();
+    }
+    let spans = recorder.spans();
+    let consumed = Key::new("http.request.body_consumed");
+    assert_eq!(attribute(&spans[0], &consumed), Some(Value::Bool(true)));
+    assert_eq!(attribute(&spans[1], &consumed), Some(Value::Bool(false)));
+    assert!(spans.iter().all(|span| span.events.is_empty()));
+}
+
+#[cfg(feature = "request-body")]
+#[test]
+fn request_body_prefixes_are_captured_truncated_and_redacted() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let capture = |body_layer: request_body::Layer, body: &'static str| {
+        let service = tower_layer::Layer::layer(
+            &body_layer,
+            service_fn(|req: Request<request_body::Body<http_body::Full<bytes::Bytes>>>| async move {
+                let mut body = req.into_body();
+                while http_body::Body::data(&mut body).await.is_some() {}
+                Ok::<_, Infallible>(Response::new(()))
+            }),
+        );
+        let service = tower_layer::Layer::layer(&layer, service);
+        futures_executor::block_on(service.oneshot(Request::new(http_body::Full::from(body)))).unwrap();
+    };
+    capture(request_body::Layer::new(8), "short");
+    capture(request_body::Layer::new(8), "a longer upload");
+    capture(
+        request_body::Layer::new(8).with_redactor(|bytes| "*".repeat(bytes.len())),
+        "password=hunter2",
+    );
+    let events: Vec<_> = recorder
+        .spans()
+        .into_iter()
+        .map(|span| {
+            let event = span.events.iter().find(|event| event.name == "http.request.body").cloned().unwrap();
+            let value = |key: &str| {
+                event.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| kv.value.clone())
+            };
+            (value("http.request.body.prefix"), value("http.request.body.truncated"))
+        })
+        .collect();
+    assert_eq!(
+        events,
+        [
+            (Some(Value::from("short")), Some(Value::Bool(false))),
+            (Some(Value::from("a longer")), Some(Value::Bool(true))),
+            (Some(Value::from("********")), Some(Value::Bool(true))),
+        ]
+    );
+}
+
+#[cfg(feature = "request-body")]
+#[test]
+fn request_body_frames_with_unreachable_chunks_are_marked_truncated() {
+    /// A data frame made of several chunks, exposing only the first one through the default
+    /// `chunks_vectored`.
+    struct Chunks(std::collections::VecDeque<&'static [u8]>);
+
+    impl bytes::Buf for Chunks {
+        fn remaining(&self) -> usize {
+            self.0.iter().map(|chunk| chunk.len()).sum()
+        }
+
+        fn chunk(&self) -> &[u8] {
+            self.0.front().copied().unwrap_or_default()
+        }
+
+        fn advance(&mut self, mut cnt: usize) {
+            while let Some(front) = self.0.front_mut() {
+                if cnt < front.len() {
+                    *front = &front[cnt..];
+                    return;
+                }
+                cnt -= front.len();
+                self.0.pop_front();
+            }
+        }
+    }
+
+    /// A body of a single frame of `Chunks`.
+    struct ChunkedBody(Option<Chunks>);
+
+    impl http_body::Body for ChunkedBody {
+        type Data = Chunks;
+        type Error = Infallible;
+
+        fn poll_data(
+            mut self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+            Poll::Ready(self.0.take().map(Ok))
+        }
+
+        fn poll_trailers(
+            self: Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+        ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+            Poll::Ready(Ok(None))
+        }
+    }
+
+    let (provider, recorder) = recording_provider();
+    let service = tower_layer::Layer::layer(
+        &request_body::Layer::new(64),
+        service_fn(|req: Request<request_body::Body<ChunkedBody>>| async move {
+            let mut body = req.into_body();
+            while http_body::Body::data(&mut body).await.is_some() {}
+            Ok::<_, Infallible>(Response::new(()))
+        }),
+    );
+    let service = tower_layer::Layer::layer(&Layer::new().with_tracer_provider(&provider), service);
+    let frame = Chunks(vec![&b"name="[..], &b"ferris"[..]].into());
+    futures_executor::block_on(service.oneshot(Request::new(ChunkedBody(Some(frame))))).unwrap();
+    let span = recorder.single();
+    let event = span.events.iter().find(|event| event.name == "http.request.body").unwrap();
+    let value = |key: &str| event.attributes.iter().find(|kv| kv.key.as_str() == key).map(|kv| kv.value.clone());
+    assert_eq!(value("http.request.body.prefix"), Some(Value::from("name=")));
+    assert_eq!(value("http.request.body.truncated"), Some(Value::Bool(true)));
+}
+
+/// A body that yields one data frame and then fails, in its data if `in_data` and otherwise in
+/// its trailers, without ever reporting its end.
+#[cfg(feature = "request-body")]
+struct FailingBody {
+    data: Option<bytes::Bytes>,
+    in_data: bool,
+}
+
+#[cfg(feature = "request-body")]
+impl http_body::Body for FailingBody {
+    type Data = bytes::Bytes;
+    type Error = std::io::Error;
+
+    fn poll_data(
+        mut self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        match self.data.take() {
+            Some(data) => Poll::Ready(Some(Ok(data))),
+            None if self.in_data => Poll::Ready(Some(Err(std::io::ErrorKind::ConnectionReset.into()))),
+            None => Poll::Ready(None),
+        }
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        Poll::Ready(Err(std::io::ErrorKind::ConnectionReset.into()))
+    }
+}
+
+#[cfg(feature = "request-body")]
+#[test]
+fn failed_request_bodies_are_recorded_as_not_consumed() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let service = tower_layer::Layer::layer(
+        &request_body::Layer::new(0).with_body_consumed(true),
+        service_fn(|req: Request<request_body::Body<FailingBody>>| async move {
+            let mut body = req.into_body();
+            while let Some(Ok(_)) = http_body::Body::data(&mut body).await {}
+            let _ = http_body::Body::trailers(&mut body).await;
+            Ok::<_, Infallible>(Response::new(()))
+        }),
+    );
+    let service = tower_layer::Layer::layer(&layer, service);
+    for in_data in [true, false] {
+        let req = Request::new(FailingBody { data: Some("partial".into()), in_data });
+        futures_executor::block_on(service.clone().oneshot(req)).unwrap();
+    }
+    let consumed = Key::new("http.request.body_consumed");
+    for span in recorder.spans() {
+        assert_eq!(attribute(&span, &consumed), Some(Value::Bool(false)));
+    }
+}
+
+#[cfg(feature = "request-body")]
+#[test]
+fn request_body_read_duration_is_recorded_for_bodies_with_data() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let service = tower_layer::Layer::layer(
+        &request_body::Layer::new(0).with_read_duration(true),
+        service_fn(|req: Request<request_body::Body<http_body::Full<bytes::Bytes>>>| async move {
+            let mut body = req.into_body();
+            while http_body::Body::data(&mut body).await.is_some() {}
+            Ok::<_, Infallible>(Response::new(()))
+        }),
+    );
+    let service = tower_layer::Layer::layer(&layer, service);
+    for body in ["upload", ""] {
+        let req = Request::new(http_body::Full::from(body));
+        futures_executor::block_on(service.clone().oneshot(req)).unwrap();
+    }
+    let spans = recorder.spans();
+    let read_duration = Key::new("http.request.body.read_duration_ms");
+    assert!(matches!(attribute(&spans[0], &read_duration), Some(Value::F64(millis)) if millis >= 0.0));
+    assert_eq!(attribute(&spans[1], &read_duration), None);
+}
+
+#[cfg(all(feature = "request-body", feature = "metrics"))]
+#[test]
+fn request_body_read_durations_are_recorded_as_a_metric() {
+    let mut measurements = Measurements::lock();
+    let read_durations = measurements.count("http.server.request.body.read_duration", &[]);
+    let (provider, _recorder) = recording_provider();
+    let service = tower_layer::Layer::layer(
+        &request_body::Layer::new(0).with_read_duration(true),
+        service_fn(|req: Request<request_body::Body<http_body::Full<bytes::Bytes>>>| async move {
+            let mut body = req.into_body();
+            while http_body::Body::data(&mut body).await.is_some() {}
+            Ok::<_, Infallible>(Response::new(()))
+        }),
+    );
+    let service = tower_layer::Layer::layer(&Layer::new().with_tracer_provider(&provider), service);
+    for body in ["upload", ""] {
+        futures_executor::block_on(service.clone().oneshot(Request::new(http_body::Full::from(body)))).unwrap();
+    }
+    assert_eq!(measurements.count("http.server.request.body.read_duration", &[]), read_durations + 1);
+}
+
+#[test]
+fn layers_use_their_own_propagator() {
+    let (provider, recorder) = recording_provider();
+    let trace_context = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_propagator(TraceContextPropagator::new());
+    let none = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_propagator(TextMapCompositePropagator::new(Vec::new()));
+    let traceparent = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+    let res = call(&trace_context, Request::builder().header("traceparent", traceparent).body(()).unwrap());
+    assert!(res.headers().contains_key("traceparent"));
+    let res = call(&none, Request::builder().header("traceparent", traceparent).body(()).unwrap());
+    assert!(!res.headers().contains_key("traceparent"));
+    let trace_ids: Vec<_> = recorder.spans().iter().map(|span| span.span_context.trace_id()).collect();
+    assert_eq!(trace_ids[0], TraceId::from_hex("4bf92f3577b34da6a3ce929d0e0e4736").unwrap());
+    assert_ne!(trace_ids[1], trace_ids[0]);
+}
+
+/// A body that yields nothing, with an exact size hint if it has a size.
+#[cfg(any(feature = "request-body", feature = "response-body"))]
+struct HintedBody(Option<u64>);
+
+#[cfg(any(feature = "request-body", feature = "response-body"))]
+impl http_body::Body for HintedBody {
+    type Data = bytes::Bytes;
+    type Error = Infallible;
+
+    fn poll_data(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> Poll<Option<Result<Self::Data, Self::Error>>> {
+        Poll::Ready(None)
+    }
+
+    fn poll_trailers(
+        self: Pin<&mut Self>,
+        _cx: &mut std::task::Context<'_>,
+    ) -> Poll<Result<Option<http::HeaderMap>, Self::Error>> {
+        Poll::Ready(Ok(None))
+    }
+
+    fn size_hint(&self) -> http_body::SizeHint {
+        self.0.map_or_else(http_body::SizeHint::default, http_body::SizeHint::with_exact)
+    }
+}
+
+#[cfg(feature = "request-body")]
+#[test]
+fn request_sizes_prefer_the_exact_hint_over_the_header() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider);
+    let service = tower_layer::Layer::layer(
+        &request_body::Layer::new(0).with_content_length(true),
+        service_fn(|_req: Request<request_body::Body<HintedBody>>| async {
+            Ok::<_, Infallible>(Response::new(()))
+        }),
+    );
+    let service = tower_layer::Layer::layer(&layer, service);
+    let requests = [
+        Request::builder()
+            .header(header::CONTENT_LENGTH, "99")
+            .body(HintedBody(Some(5)))
+            .unwrap(),
+        Request::builder()
+            .header(header::CONTENT_LENGTH, "42")
+            .body(HintedBody(None))
+            .unwrap(),
+        Request::new(HintedBody(None)),
+    ];
+    for req in requests {
+        futures_executor::block_on(service.clone().oneshot(req)).unwrap();
+    }
+    let sizes: Vec<_> = recorder
+        .spans()
+        .iter()
+        .map(|span| attribute(span, &opentelemetry_semantic_conventions::trace::HTTP_REQUEST_CONTENT_LENGTH))
+        .collect();
+    assert_eq!(sizes, vec![Some(Value::I64(5)), Some(Value::I64(42)), None]);
+}
+
+#[cfg(feature = "response-body")]
+#[test]
+fn response_sizes_are_recorded_from_the_hint_or_header() {
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new()
+        .with_tracer_provider(&provider)
+        .with_deferred_span_end(true);
+    let service = tower_layer::Layer::layer(
+        &layer,
+        service_fn(|req: Request<()>| async move {
+            let res = match req.uri().path() {
+                "/exact" => Response::new(HintedBody(Some(5))),
+                "/header" => Response::builder()
+                    .header(header::CONTENT_LENGTH, "42")
+                    .body(HintedBody(None))
+                    .unwrap(),
+                _ => Response::new(HintedBody(None)),
+            };
+            Ok::<_, Infallible>(res)
+        }),
+    );
+    let service = tower_layer::Layer::layer(&response_body::Layer::new().with_content_length(true), service);
+    for uri in ["/exact", "/header", "/unknown"] {
+        let req = Request::builder().uri(uri).body(()).unwrap();
+        drop(futures_executor::block_on(service.clone().oneshot(req)).unwrap());
+    }
+    let sizes: Vec<_> = recorder
+        .spans()
+        .iter()
+        .map(|span| attribute(span, &opentelemetry_semantic_conventions::trace::HTTP_RESPONSE_CONTENT_LENGTH))
+        .collect();
+    assert_eq!(sizes, vec![Some(Value::I64(5)), Some(Value::I64(42)), None]);
+}
+
+#[cfg(feature = "dry-run")]
+#[test]
+fn dry_run_logs_spans_instead_of_exporting_them() {
+    #[derive(Clone, Default)]
+    struct Events(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl tracing::field::Visit for Events {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+            self.0.lock().unwrap().push((field.name().to_string(), format!("{value:?}")));
+        }
+    }
+
+    impl tracing::Subscriber for Events {
+        fn enabled(&self, metadata: &tracing::Metadata<'_>) -> bool {
+            metadata.target() == "tower_opentelemetry::dry_run"
+        }
+
+        fn new_span(&self, _span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            tracing::span::Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, _values: &tracing::span::Record<'_>) {}
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, event: &tracing::Event<'_>) {
+            event.record(&mut self.clone());
+        }
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    let (provider, recorder) = recording_provider();
+    let layer = Layer::new().with_tracer_provider(&provider).with_dry_run(true);
+    let events = Events::default();
+    tracing::subscriber::with_default(events.clone(), || {
+        call(&layer, Request::builder().uri("/users").body(()).unwrap());
+    });
+    assert!(recorder.spans().is_empty());
+    let fields = events.0.lock().unwrap();
+    let field = |name: &str| fields.iter().find(|(field, _)| field == name).map(|(_, value)| value.clone());
+    assert_eq!(field("name").as_deref(), Some("/users"));
+    let attributes = field("attributes").unwrap();
+    assert!(attributes.contains("http.method=GET"), "{}", attributes);
+    assert!(attributes.contains("http.status_code=200"), "{}", attributes);
+}
+
+#[cfg(feature = "tracing")]
+#[test]
+fn tracing_spans_carry_the_key_http_fields() {
+    #[derive(Clone, Default)]
+    struct Fields(Arc<Mutex<Vec<(String, String)>>>);
+
+    impl tracing::field::Visit for Fields {
+        fn record_debug(&mut self, field: &tracing::field::Field, value: &dyn fmt::Debug) {
+            self.0.lock().unwrap().push((field.name().to_string(), format!("{value:?}")));
+        }
+    }
+
+    impl tracing::Subscriber for Fields {
+        fn enabled(&self, _metadata: &tracing::Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &tracing::span::Attributes<'_>) -> tracing::span::Id {
+            span.record(&mut self.clone());
+            tracing::span::Id::from_u64(1)
+        }
+
+        fn record(&self, _span: &tracing::span::Id, values: &tracing::span::Record<'_>) {
+            values.record(&mut self.clone());
+        }
+
+        fn record_follows_from(&self, _span: &tracing::span::Id, _follows: &tracing::span::Id) {}
+
+        fn event(&self, _event: &tracing::Event<'_>) {}
+
+        fn enter(&self, _span: &tracing::span::Id) {}
+
+        fn exit(&self, _span: &tracing::span::Id) {}
+    }
+
+    let provider = provider(Sampler::AlwaysOn);
+    let layer = Layer::new().with_tracer_provider(&provider).with_tracing_span(true);
+    let fields = Fields::default();
+    let mut req = Request::new(());
+    req.extensions_mut().insert(MatchedRoute::new("/users/:id"));
+    tracing::subscriber::with_default(fields.clone(), || call(&layer, req));
+    let fields = fields.0.lock().unwrap();
+    let names: Vec<_> = fields.iter().map(|(name, _)| name.as_str()).collect();
+    assert_eq!(names, vec!["http.method", "trace_id", "http.route", "http.status_code"]);
+    assert_eq!(fields[0].1, "GET");
+    assert_eq!(fields[1].1.len(), 32);
+    assert_eq!(fields[2].1, "\"/users/:id\"");
+    assert_eq!(fields[3].1, "200");
+}
+
+#[test]
+fn reloads_keep_the_inner_service_that_was_polled_ready() {
+    /// A service that must be polled ready before each call, like a `Buffer`, and whose clones
+    /// start out unready.
+    struct MustBeReady(bool);
+
+    impl Clone for MustBeReady {
+        fn clone(&self) -> Self {
+            Self(false)
+        }
+    }
+
+    impl tower_service::Service<Request<()>> for MustBeReady {
+        type Error = Infallible;
+        type Future = futures_util::future::Ready<Result<Response<()>, Infallible>>;
+        type Response = Response<()>;
+
+        fn poll_ready(&mut self, _cx: &mut std::task::Context<'_>) -> Poll<Result<(), Infallible>> {
+            self.0 = true;
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _req: Request<()>) -> Self::Future {
+            assert!(std::mem::replace(&mut self.0, false), "called without being polled ready");
+            futures_util::future::ready(Ok(Response::new(())))
+        }
+    }
+
+    init_propagator();
+    let (provider, recorder) = recording_provider();
+    let handle = ReloadHandle::new();
+    let mut service = tower_layer::Layer::layer(
+        &Layer::new().with_tracer_provider(&provider).with_reload_handle(handle.clone()),
+        MustBeReady(false),
+    );
+    futures_executor::block_on(service.ready()).unwrap();
+    handle.reload(Layer::new().with_tracer_provider(&provider).with_status_class(true));
+    futures_executor::block_on(tower_service::Service::call(&mut service, Request::new(()))).unwrap();
+    assert_eq!(attribute(&recorder.single(), &HTTP_STATUS_CLASS), Some(Value::from("2xx")));
+}
+
+#[cfg(feature = "otlp")]
+#[test]
+fn otlp_init_installs_the_global_provider_and_propagators() {
+    // Keep the metrics tests from recording while the OTLP meter provider is installed.
+    #[cfg(feature = "metrics")]
+    let measurements = Measurements::lock();
+    let runtime = tokio::runtime::Runtime::new().unwrap();
+    let _layer = runtime.block_on(async { otlp::init() }).unwrap();
+    let span = global::tracer("otlp-test").start("probe");
+    assert!(span.span_context().is_sampled());
+    let mut headers = http::HeaderMap::new();
+    let cx = Context::current_with_span(span).with_baggage(vec![KeyValue::new("tenant", "acme")]);
+    global::get_text_map_propagator(|propagator| {
+        propagator.inject_context(&cx, &mut HeaderCarrier::new(&mut headers));
+    });
+    assert!(headers.contains_key("traceparent"));
+    assert_eq!(headers["baggage"], "tenant=acme");
+    drop(cx);
+    #[cfg(feature = "metrics")]
+    {
+        assert!(otlp::METRICS.lock().unwrap().is_some());
+        global::set_meter_provider(measurements.0.provider());
+    }
+    runtime.block_on(async { otlp::shutdown() });
+    #[cfg(feature = "metrics")]
+    assert!(otlp::METRICS.lock().unwrap().is_none());
+}
@@ -0,0 +1,185 @@
+//! Type erasure for the tracer a [`Service`] builds its spans with.
+//!
+//! [`Service`]: crate::Service
+use std::{any::Any, borrow::Cow, fmt, sync::Arc};
+
+use opentelemetry::{
+    global,
+    sdk::{self, InstrumentationLibrary},
+    trace::{IdGenerator, SpanBuilder, SpanId, TraceContextExt, TraceId, Tracer, TracerProvider},
+    Context,
+};
+
+/// The instrumentation scope name reported for every span built by this crate.
+const SCOPE_NAME: &str = "tower-opentelemetry";
+
+/// The instrumentation scope of this crate, as reported to the samplers of [`Layer::with_sampler`].
+///
+/// [`Layer::with_sampler`]: crate::Layer::with_sampler
+pub(crate) fn instrumentation_library() -> InstrumentationLibrary {
+    InstrumentationLibrary::new(SCOPE_NAME, Some(env!("CARGO_PKG_VERSION")))
+}
+
+/// Object-safe subset of [`Tracer`], returning the built span inside a [`Context`].
+trait ObjectSafeTracer: Send + Sync {
+    fn build_with_context(&self, builder: SpanBuilder, parent_cx: &Context) -> Context;
+
+    /// Call `f` with the id generator of the tracer's provider, or `None` if it isn't known.
+    fn with_id_generator(&self, f: &mut dyn FnMut(Option<&dyn IdGenerator>));
+}
+
+impl<T> ObjectSafeTracer for T
+where
+    T: Tracer + Send + Sync + 'static,
+    T::Span: Send + Sync + 'static,
+{
+    fn build_with_context(&self, builder: SpanBuilder, parent_cx: &Context) -> Context {
+        parent_cx.with_span(Tracer::build_with_context(self, builder, parent_cx))
+    }
+
+    /// Only SDK tracers expose their provider's generator. Others, including the
+    /// [`global::BoxedTracer`] of the global provider, may wrap a custom one out of reach here.
+    fn with_id_generator(&self, f: &mut dyn FnMut(Option<&dyn IdGenerator>)) {
+        let tracer = (self as &dyn Any).downcast_ref::<sdk::trace::Tracer>();
+        match tracer.and_then(sdk::trace::Tracer::provider) {
+            Some(provider) => f(Some(provider.config().id_generator.as_ref())),
+            None => f(None),
+        }
+    }
+}
+
+/// A cheaply cloneable tracer of any concrete type.
+#[derive(Clone)]
+pub(crate) struct BoxedTracer(Arc<dyn ObjectSafeTracer>);
+
+impl BoxedTracer {
+    /// Create a tracer for this crate's instrumentation scope from `provider`, reporting
+    /// `schema_url` as the scope's schema.
+    pub(crate) fn from_provider<P>(provider: &P, schema_url: Option<&'static str>) -> Self
+    where
+        P: TracerProvider,
+        P::Tracer: Send + Sync + 'static,
+        <P::Tracer as Tracer>::Span: Send + Sync + 'static,
+    {
+        Self(Arc::new(provider.versioned_tracer(
+            SCOPE_NAME,
+            Some(env!("CARGO_PKG_VERSION")),
+            schema_url,
+        )))
+    }
+
+    /// Create a tracer for this crate's instrumentation scope from the global provider.
+    pub(crate) fn global(schema_url: Option<&'static str>) -> Self {
+        Self::from_provider(&global::tracer_provider(), schema_url)
+    }
+
+    #[allow(clippy::unused_self)]
+    pub(crate) fn span_builder<T>(&self, name: T) -> SpanBuilder
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        SpanBuilder::from_name(name)
+    }
+
+    /// Build a span from `builder` and return `parent_cx` with the span set as active.
+    pub(crate) fn build_with_context(&self, builder: SpanBuilder, parent_cx: &Context) -> Context {
+        self.0.build_with_context(builder, parent_cx)
+    }
+
+    /// A new trace id from the id generator of the tracer's provider, or `None` if the tracer
+    /// isn't an SDK tracer, whose generator can't be reached.
+    pub(crate) fn provider_trace_id(&self) -> Option<TraceId> {
+        let mut id = None;
+        self.0.with_id_generator(&mut |ids| id = ids.map(IdGenerator::new_trace_id));
+        id
+    }
+
+    /// A new trace id from the id generator of the tracer's provider, or the SDK's default
+    /// generator if the tracer isn't an SDK tracer.
+    pub(crate) fn new_trace_id(&self) -> TraceId {
+        self.provider_trace_id().unwrap_or_else(|| sdk::trace::IdGenerator::default().new_trace_id())
+    }
+
+    /// A new span id from the id generator of the tracer's provider, or the SDK's default
+    /// generator if the tracer isn't an SDK tracer.
+    pub(crate) fn new_span_id(&self) -> SpanId {
+        let mut id = None;
+        self.0.with_id_generator(&mut |ids| id = ids.map(IdGenerator::new_span_id));
+        id.unwrap_or_else(|| sdk::trace::IdGenerator::default().new_span_id())
+    }
+}
+
+impl fmt::Debug for BoxedTracer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("BoxedTracer")
+    }
+}
+
+/// A handle to a tracer for this crate's instrumentation scope.
+///
+/// [`Service::tracer`] returns the handle a [`Service`] builds its spans with, for creating
+/// related spans with the same instrumentation scope. Inserted into the request extensions by an
+/// earlier layer, a handle replaces the tracer configured on the [`Layer`] for that request's
+/// span, for example to route each tenant's spans to its own tracer provider.
+///
+/// ```
+/// use opentelemetry::trace::noop::NoopTracerProvider;
+/// use tower_opentelemetry::TracerHandle;
+///
+/// let provider = NoopTracerProvider::new();
+/// let mut req = http::Request::new(());
+/// req.extensions_mut().insert(TracerHandle::from_provider(&provider));
+/// ```
+///
+/// [`Service`]: crate::Service
+/// [`Service::tracer`]: crate::Service::tracer
+/// [`Layer`]: crate::Layer
+#[derive(Debug, Clone)]
+pub struct TracerHandle(pub(crate) BoxedTracer);
+
+impl TracerHandle {
+    /// Create a tracer for this crate's instrumentation scope from `provider`.
+    ///
+    /// SDK tracers only hold a weak reference to their provider, so `provider` must be kept alive
+    /// for as long as spans should be recorded. The scope reports no schema URL, not even the one
+    /// of [`Layer::with_schema_url`], see [`TracerHandle::from_provider_with_schema_url`].
+    ///
+    /// [`Layer::with_schema_url`]: crate::Layer::with_schema_url
+    #[must_use]
+    pub fn from_provider<P>(provider: &P) -> Self
+    where
+        P: TracerProvider,
+        P::Tracer: Send + Sync + 'static,
+        <P::Tracer as Tracer>::Span: Send + Sync + 'static,
+    {
+        Self(BoxedTracer::from_provider(provider, None))
+    }
+
+    /// Create a tracer like [`TracerHandle::from_provider`], reporting `schema_url` as the schema
+    /// of its instrumentation scope.
+    #[must_use]
+    pub fn from_provider_with_schema_url<P>(provider: &P, schema_url: &'static str) -> Self
+    where
+        P: TracerProvider,
+        P::Tracer: Send + Sync + 'static,
+        <P::Tracer as Tracer>::Span: Send + Sync + 'static,
+    {
+        Self(BoxedTracer::from_provider(provider, Some(schema_url)))
+    }
+
+    /// Start building a span named `name`.
+    #[must_use]
+    pub fn span_builder<T>(&self, name: T) -> SpanBuilder
+    where
+        T: Into<Cow<'static, str>>,
+    {
+        self.0.span_builder(name)
+    }
+
+    /// Build a span from `builder` as a child of `parent_cx`, returning `parent_cx` with the span
+    /// set as active.
+    #[must_use]
+    pub fn build_with_context(&self, builder: SpanBuilder, parent_cx: &Context) -> Context {
+        self.0.build_with_context(builder, parent_cx)
+    }
+}
@@ -0,0 +1,32 @@
+//! Fields for the [`tracing`] span entered around each request.
+//!
+//! [`tracing`]: https://docs.rs/tracing
+use http::{Request, Response};
+use opentelemetry::{trace::TraceContextExt, Context};
+use tracing::field::Empty;
+
+use crate::{http_method_str, MatchedRoute};
+
+/// Create the `tracing` span for `req`, whose OpenTelemetry span is in `cx`.
+pub(crate) fn request_span<B>(req: &Request<B>, cx: &Context) -> tracing::Span {
+    let trace_id = format!("{:032x}", cx.span().span_context().trace_id());
+    let span = tracing::info_span!(
+        "http.request",
+        http.method = %http_method_str(req.method()),
+        http.route = Empty,
+        http.status_code = Empty,
+        trace_id = %trace_id,
+    );
+    if let Some(MatchedRoute(route)) = MatchedRoute::from_extensions(req.extensions()) {
+        span.record("http.route", route.as_ref());
+    }
+    span
+}
+
+/// Record the fields known once the response is produced.
+pub(crate) fn record_response<B>(span: &tracing::Span, res: &Response<B>) {
+    span.record("http.status_code", res.status().as_u16());
+    if let Some(MatchedRoute(route)) = MatchedRoute::from_extensions(res.extensions()) {
+        span.record("http.route", route.as_ref());
+    }
+}
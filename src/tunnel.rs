@@ -0,0 +1,31 @@
+//! Spans for `CONNECT` tunnels that outlive the response.
+use opentelemetry::Context;
+
+use crate::guard::SpanGuard;
+
+/// Keeps the span of an established `CONNECT` tunnel open until it is dropped.
+///
+/// When [`Layer::with_tunnel_spans`] is enabled, a successful response to a `CONNECT` request
+/// carries a [`Tunnel`] in its extensions instead of ending the span. Move it into the task that
+/// drives the upgraded connection so the span covers the tunnel's lifetime.
+///
+/// [`Layer::with_tunnel_spans`]: crate::Layer::with_tunnel_spans
+#[derive(Debug)]
+pub struct Tunnel {
+    guard: SpanGuard,
+}
+
+impl Tunnel {
+    pub(crate) fn new(guard: SpanGuard) -> Self {
+        Self { guard }
+    }
+
+    /// The [`Context`] holding the tunnel's span, for parenting spans created inside the tunnel.
+    #[must_use]
+    pub fn context(&self) -> &Context {
+        self.guard.context()
+    }
+
+    /// End the tunnel's span now rather than when the [`Tunnel`] is dropped.
+    pub fn end(self) {}
+}